@@ -1 +1,4 @@
+pub mod error;
+pub mod event;
+#[cfg(target_os = "android")]
 pub mod sensor;
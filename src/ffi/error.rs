@@ -0,0 +1,50 @@
+#![cfg_attr(not(target_os = "android"), allow(dead_code))]
+
+use std::fmt;
+
+/// A failed call into the NDK sensor API. Statuses are the negative values returned by the NDK.
+#[derive(Clone, Debug)]
+pub enum SensorError {
+    ManagerUnavailable,
+    LooperUnavailable,
+    QueueCreationFailed,
+    QueueDestructionFailed(i32),
+    EnableFailed(i32),
+    DisableFailed(i32),
+    SetEventRateFailed(i32),
+    PollFailed(i32),
+    ReadFailed(i32),
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::ManagerUnavailable => write!(f, "sensor manager is unavailable"),
+            SensorError::LooperUnavailable => write!(f, "could not prepare a looper"),
+            SensorError::QueueCreationFailed => write!(f, "could not create a sensor event queue"),
+            SensorError::QueueDestructionFailed(status) => {
+                write!(
+                    f,
+                    "could not destroy the sensor event queue (status {status})"
+                )
+            }
+            SensorError::EnableFailed(status) => {
+                write!(f, "could not enable sensor (status {status})")
+            }
+            SensorError::DisableFailed(status) => {
+                write!(f, "could not disable sensor (status {status})")
+            }
+            SensorError::SetEventRateFailed(status) => {
+                write!(f, "could not set sensor event rate (status {status})")
+            }
+            SensorError::PollFailed(status) => {
+                write!(f, "polling the looper failed (status {status})")
+            }
+            SensorError::ReadFailed(status) => {
+                write!(f, "reading sensor events failed (status {status})")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SensorError {}
@@ -0,0 +1,426 @@
+#![allow(dead_code)]
+
+use bevy::math::{Quat, Vec3};
+use num_derive::FromPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::geodetic::Geodetic;
+
+// Values from the NDK's <android/sensor.h>. They are part of the stable NDK ABI and are
+// duplicated here so the event model, unlike the NDK bindings, also builds off-device.
+const ASENSOR_STATUS_NO_CONTACT: i32 = -1;
+const ASENSOR_STATUS_UNRELIABLE: i32 = 0;
+const ASENSOR_STATUS_ACCURACY_LOW: i32 = 1;
+const ASENSOR_STATUS_ACCURACY_MEDIUM: i32 = 2;
+const ASENSOR_STATUS_ACCURACY_HIGH: i32 = 3;
+
+const ASENSOR_TYPE_ACCELEROMETER: i32 = 1;
+const ASENSOR_TYPE_MAGNETIC_FIELD: i32 = 2;
+const ASENSOR_TYPE_GYROSCOPE: i32 = 4;
+const ASENSOR_TYPE_PRESSURE: i32 = 6;
+const ASENSOR_TYPE_GRAVITY: i32 = 9;
+const ASENSOR_TYPE_LINEAR_ACCELERATION: i32 = 10;
+const ASENSOR_TYPE_ROTATION_VECTOR: i32 = 11;
+const ASENSOR_TYPE_MAGNETIC_FIELD_UNCALIBRATED: i32 = 14;
+const ASENSOR_TYPE_GAME_ROTATION_VECTOR: i32 = 15;
+const ASENSOR_TYPE_GYROSCOPE_UNCALIBRATED: i32 = 16;
+const ASENSOR_TYPE_GEOMAGNETIC_ROTATION_VECTOR: i32 = 20;
+const ASENSOR_TYPE_ADDITIONAL_INFO: i32 = 33;
+const ASENSOR_TYPE_ACCELEROMETER_UNCALIBRATED: i32 = 35;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum SensorAccuracy {
+    High = ASENSOR_STATUS_ACCURACY_HIGH as isize,
+    Low = ASENSOR_STATUS_ACCURACY_LOW as isize,
+    Medium = ASENSOR_STATUS_ACCURACY_MEDIUM as isize,
+    NoContact = ASENSOR_STATUS_NO_CONTACT as isize,
+    Unreliable = ASENSOR_STATUS_UNRELIABLE as isize,
+    /// The sensor's events carry no status, e.g. rotation vectors use all four data slots
+    /// and uncalibrated sensors store their bias where the status would be
+    Unknown = i8::MIN as isize,
+}
+
+impl SensorAccuracy {
+    /// Buckets the estimated heading error of a rotation vector, in radians, into an
+    /// accuracy level. Negative errors mean the sensor does not estimate it.
+    pub fn from_heading_error(error: f32) -> Self {
+        match error {
+            error if error < 0.0 || error.is_nan() => SensorAccuracy::Unknown,
+            error if error <= 0.1 => SensorAccuracy::High,
+            error if error <= 0.35 => SensorAccuracy::Medium,
+            error if error <= 1.0 => SensorAccuracy::Low,
+            _ => SensorAccuracy::Unreliable,
+        }
+    }
+
+    /// How much a sample at this accuracy should count, from `0` (ignore) to `1`.
+    /// Samples of unknown accuracy are fully trusted.
+    pub fn weight(&self) -> f32 {
+        match self {
+            SensorAccuracy::High | SensorAccuracy::Unknown => 1.0,
+            SensorAccuracy::Medium => 0.75,
+            SensorAccuracy::Low => 0.25,
+            SensorAccuracy::Unreliable | SensorAccuracy::NoContact => 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, FromPrimitive, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorType {
+    Accelerometer = ASENSOR_TYPE_LINEAR_ACCELERATION as isize,
+    RawAccelerometer = ASENSOR_TYPE_ACCELEROMETER as isize,
+    UncalibratedAccelerometer = ASENSOR_TYPE_ACCELEROMETER_UNCALIBRATED as isize,
+    Gyroscope = ASENSOR_TYPE_GYROSCOPE as isize,
+    UncalibratedGyroscope = ASENSOR_TYPE_GYROSCOPE_UNCALIBRATED as isize,
+    MagneticField = ASENSOR_TYPE_MAGNETIC_FIELD as isize,
+    UncalibratedMagneticField = ASENSOR_TYPE_MAGNETIC_FIELD_UNCALIBRATED as isize,
+    Rotation = ASENSOR_TYPE_ROTATION_VECTOR as isize,
+    /// Rotation vector without the magnetometer, so its yaw drifts but is not disturbed by metal
+    GameRotation = ASENSOR_TYPE_GAME_ROTATION_VECTOR as isize,
+    Compass = ASENSOR_TYPE_GEOMAGNETIC_ROTATION_VECTOR as isize,
+    Gravity = ASENSOR_TYPE_GRAVITY as isize,
+    /// Static air pressure in hPa
+    Pressure = ASENSOR_TYPE_PRESSURE as isize,
+    AdditionalInfo = ASENSOR_TYPE_ADDITIONAL_INFO as isize,
+    /// Not an NDK sensor: fixes come from the platform's location service or a replay file
+    Location = -1,
+    Unavailable = 0,
+}
+
+/// A position fix from a satellite receiver or the platform's fused location provider
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocationFix {
+    /// Degrees, positive north
+    pub latitude: f64,
+    /// Degrees, positive east
+    pub longitude: f64,
+    /// Metres above the WGS84 ellipsoid
+    pub altitude: f64,
+    /// Horizontal radius of 68% confidence in metres, like Android's `Location.getAccuracy()`
+    pub accuracy: f32,
+    /// Vertical 68% confidence in metres, if the source reports it
+    pub vertical_accuracy: Option<f32>,
+    /// Ground speed in m/s
+    pub speed: Option<f32>,
+    /// Direction of travel in degrees clockwise from true north
+    pub bearing: Option<f32>,
+}
+
+impl LocationFix {
+    pub fn geodetic(&self) -> Geodetic {
+        Geodetic::new(self.latitude, self.longitude, self.altitude)
+    }
+
+    /// Horizontal velocity as east, north, up in m/s, if both speed and bearing are known
+    pub fn velocity(&self) -> Option<Vec3> {
+        let (sin, cos) = self.bearing?.to_radians().sin_cos();
+        let speed = self.speed?;
+        Some(Vec3::new(speed * sin, speed * cos, 0.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SensorValues {
+    Scalar(f32),
+    Vec3(Vec3),
+    Quat(Quat),
+    /// Reading of an uncalibrated sensor together with the bias the system estimated for it.
+    /// The calibrated value is `values - bias`.
+    Uncalibrated {
+        values: Vec3,
+        bias: Vec3,
+    },
+    Location(LocationFix),
+}
+
+impl SensorValues {
+    pub fn scalar(&self) -> Option<f32> {
+        match self {
+            SensorValues::Scalar(data) => Some(*data),
+            _ => None,
+        }
+    }
+
+    /// The vector reading, uncorrected for uncalibrated sensors
+    pub fn vec3(&self) -> Option<&Vec3> {
+        match self {
+            SensorValues::Vec3(data) => Some(data),
+            SensorValues::Uncalibrated { values, .. } => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn bias(&self) -> Option<&Vec3> {
+        match self {
+            SensorValues::Uncalibrated { bias, .. } => Some(bias),
+            _ => None,
+        }
+    }
+
+    pub fn quat(&self) -> Option<&Quat> {
+        match self {
+            SensorValues::Quat(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn location(&self) -> Option<&LocationFix> {
+        match self {
+            SensorValues::Location(fix) => Some(fix),
+            _ => None,
+        }
+    }
+
+    /// Blends towards `other` by `t` (0..=1): linear for vectors, spherical for quaternions.
+    /// Returns `None` if the two values are of different kinds, or are location fixes.
+    pub fn interpolate(&self, other: &SensorValues, t: f32) -> Option<SensorValues> {
+        match (self, other) {
+            (SensorValues::Scalar(from), SensorValues::Scalar(to)) => {
+                Some(SensorValues::Scalar(from + (to - from) * t))
+            }
+            (SensorValues::Vec3(from), SensorValues::Vec3(to)) => {
+                Some(SensorValues::Vec3(from.lerp(*to, t)))
+            }
+            (SensorValues::Quat(from), SensorValues::Quat(to)) => Some(SensorValues::Quat(
+                from.normalize().slerp(to.normalize(), t),
+            )),
+            (
+                SensorValues::Uncalibrated { values, bias },
+                SensorValues::Uncalibrated {
+                    values: values_to,
+                    bias: bias_to,
+                },
+            ) => Some(SensorValues::Uncalibrated {
+                values: values.lerp(*values_to, t),
+                bias: bias.lerp(*bias_to, t),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SensorEvent {
+    pub accuracy: SensorAccuracy,
+    pub sensor_type: SensorType,
+    pub timestamp: i64,
+    pub values: SensorValues,
+}
+
+impl Default for SensorEvent {
+    fn default() -> Self {
+        Self {
+            accuracy: SensorAccuracy::NoContact,
+            sensor_type: SensorType::Unavailable,
+            timestamp: 0,
+            values: SensorValues::Vec3(Vec3::ZERO),
+        }
+    }
+}
+
+/// The parts of an `ASensorEvent` needed to decode it, as plain data
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RawSensorEvent {
+    pub sensor_type: i32,
+    pub timestamp: i64,
+    pub data: [f32; 16],
+    /// `status` of the `ASensorVector` union member. It shares its bytes with `data[3]`,
+    /// so it is only meaningful for sensors reporting a 3-axis vector.
+    pub status: i8,
+}
+
+/// Shape of a sensor's payload in `RawSensorEvent::data`
+#[derive(Clone, Copy, Debug)]
+enum Layout {
+    /// `data[0]` alone, without a status
+    Scalar,
+    /// `data[0..3]` plus a status byte
+    Vec3,
+    /// `data[0..4]` as x, y, z, w, without a status
+    Quat,
+    /// Like [`Layout::Quat`], with the estimated heading error in radians in `data[4]`
+    QuatWithHeadingError,
+    /// `data[0..3]` uncalibrated values followed by their bias in `data[3..6]`, without a status
+    Uncalibrated,
+}
+
+/// How each supported sensor type is decoded. Types not listed here are dropped.
+const DECODERS: &[(SensorType, Layout)] = &[
+    (SensorType::Accelerometer, Layout::Vec3),
+    (SensorType::RawAccelerometer, Layout::Vec3),
+    (SensorType::UncalibratedAccelerometer, Layout::Uncalibrated),
+    (SensorType::Gyroscope, Layout::Vec3),
+    (SensorType::UncalibratedGyroscope, Layout::Uncalibrated),
+    (SensorType::MagneticField, Layout::Vec3),
+    (SensorType::UncalibratedMagneticField, Layout::Uncalibrated),
+    (SensorType::Rotation, Layout::QuatWithHeadingError),
+    (SensorType::GameRotation, Layout::Quat),
+    (SensorType::Compass, Layout::QuatWithHeadingError),
+    (SensorType::Gravity, Layout::Vec3),
+    (SensorType::Pressure, Layout::Scalar),
+];
+
+impl SensorType {
+    pub fn from_raw(sensor_type: i32) -> Option<Self> {
+        num::FromPrimitive::from_i32(sensor_type)
+    }
+}
+
+/// Turns a raw NDK event into a [`SensorEvent`], or `None` if its type is not handled
+pub fn decode(raw: &RawSensorEvent) -> Option<SensorEvent> {
+    let sensor_type = SensorType::from_raw(raw.sensor_type)?;
+    let &(_, layout) = DECODERS
+        .iter()
+        .find(|(decoded_type, _)| *decoded_type == sensor_type)?;
+
+    let (accuracy, values) = match layout {
+        Layout::Scalar => (SensorAccuracy::Unknown, SensorValues::Scalar(raw.data[0])),
+        Layout::Vec3 => (
+            num::FromPrimitive::from_i8(raw.status).unwrap_or(SensorAccuracy::Unreliable),
+            SensorValues::Vec3(Vec3::from_slice(&raw.data[..3])),
+        ),
+        Layout::Quat => (
+            SensorAccuracy::Unknown,
+            SensorValues::Quat(Quat::from_slice(&raw.data[..4])),
+        ),
+        Layout::QuatWithHeadingError => (
+            SensorAccuracy::from_heading_error(raw.data[4]),
+            SensorValues::Quat(Quat::from_slice(&raw.data[..4])),
+        ),
+        Layout::Uncalibrated => (
+            SensorAccuracy::Unknown,
+            SensorValues::Uncalibrated {
+                values: Vec3::from_slice(&raw.data[..3]),
+                bias: Vec3::from_slice(&raw.data[3..6]),
+            },
+        ),
+    };
+
+    Some(SensorEvent {
+        accuracy,
+        sensor_type,
+        timestamp: raw.timestamp,
+        values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A raw event as the NDK lays it out: the status of `ASensorVector`
+    /// is the first byte of `data[3]`
+    fn raw(sensor_type: SensorType, values: &[f32]) -> RawSensorEvent {
+        let mut data = [0.0; 16];
+        data[..values.len()].copy_from_slice(values);
+        RawSensorEvent {
+            sensor_type: sensor_type as i32,
+            timestamp: 42,
+            data,
+            status: data[3].to_bits() as u8 as i8,
+        }
+    }
+
+    #[test]
+    fn every_decoder_is_listed_once() {
+        for (index, (sensor_type, _)) in DECODERS.iter().enumerate() {
+            assert!(
+                !DECODERS[index + 1..]
+                    .iter()
+                    .any(|(other, _)| other == sensor_type),
+                "{sensor_type:?} listed twice"
+            );
+        }
+    }
+
+    #[test]
+    fn vec3_reads_its_status_from_the_byte_after_the_values() {
+        let status = f32::from_bits(ASENSOR_STATUS_ACCURACY_MEDIUM as u32);
+        let event = decode(&raw(SensorType::Gyroscope, &[0.1, -0.2, 0.3, status])).unwrap();
+
+        assert_eq!(event.sensor_type, SensorType::Gyroscope);
+        assert_eq!(event.timestamp, 42);
+        assert_eq!(event.accuracy, SensorAccuracy::Medium);
+        assert_eq!(event.values.vec3(), Some(&Vec3::new(0.1, -0.2, 0.3)));
+    }
+
+    #[test]
+    fn vec3_with_an_unknown_status_is_unreliable() {
+        let status = f32::from_bits(0x7f);
+        let event = decode(&raw(SensorType::MagneticField, &[1.0, 2.0, 3.0, status])).unwrap();
+
+        assert_eq!(event.accuracy, SensorAccuracy::Unreliable);
+    }
+
+    #[test]
+    fn scalar_reads_the_first_value() {
+        let event = decode(&raw(SensorType::Pressure, &[1013.25, 7.0])).unwrap();
+
+        assert_eq!(event.accuracy, SensorAccuracy::Unknown);
+        assert_eq!(event.values.scalar(), Some(1013.25));
+    }
+
+    #[test]
+    fn quat_is_x_y_z_w() {
+        let event = decode(&raw(SensorType::GameRotation, &[0.0, 0.0, 0.6, 0.8])).unwrap();
+
+        // the w component overlaps the status byte, which must not turn into an accuracy
+        assert_eq!(event.accuracy, SensorAccuracy::Unknown);
+        assert_eq!(
+            event.values.quat(),
+            Some(&Quat::from_xyzw(0.0, 0.0, 0.6, 0.8))
+        );
+    }
+
+    #[test]
+    fn rotation_vector_accuracy_comes_from_its_heading_error() {
+        let accuracy = |heading_error| {
+            decode(&raw(
+                SensorType::Rotation,
+                &[0.0, 0.0, 0.0, 1.0, heading_error],
+            ))
+            .unwrap()
+            .accuracy
+        };
+
+        assert_eq!(accuracy(0.05), SensorAccuracy::High);
+        assert_eq!(accuracy(0.2), SensorAccuracy::Medium);
+        assert_eq!(accuracy(0.5), SensorAccuracy::Low);
+        assert_eq!(accuracy(1.5), SensorAccuracy::Unreliable);
+        // not estimated
+        assert_eq!(accuracy(-1.0), SensorAccuracy::Unknown);
+        assert_eq!(accuracy(f32::NAN), SensorAccuracy::Unknown);
+        assert_eq!(
+            decode(&raw(SensorType::Compass, &[0.0, 0.0, 0.0, 1.0, 0.05]))
+                .unwrap()
+                .accuracy,
+            SensorAccuracy::High
+        );
+    }
+
+    #[test]
+    fn uncalibrated_keeps_the_bias_where_the_status_would_be() {
+        // a bias of 3.0 has a first byte of 0, which would read as unreliable
+        let event = decode(&raw(
+            SensorType::UncalibratedGyroscope,
+            &[0.1, 0.2, 0.3, 3.0, -0.5, 0.25],
+        ))
+        .unwrap();
+
+        assert_eq!(event.accuracy, SensorAccuracy::Unknown);
+        assert_eq!(event.values.vec3(), Some(&Vec3::new(0.1, 0.2, 0.3)));
+        assert_eq!(event.values.bias(), Some(&Vec3::new(3.0, -0.5, 0.25)));
+    }
+
+    #[test]
+    fn unknown_sensor_types_are_dropped() {
+        let mut unknown = raw(SensorType::Gyroscope, &[0.0; 4]);
+        unknown.sensor_type = 9_999;
+        assert!(decode(&unknown).is_none());
+
+        // known to the NDK, but not decoded
+        assert!(decode(&raw(SensorType::AdditionalInfo, &[0.0; 4])).is_none());
+        assert!(decode(&raw(SensorType::Unavailable, &[0.0; 4])).is_none());
+    }
+}
@@ -1,131 +1,200 @@
 #![allow(dead_code)]
 
-use bevy::{
-    log::warn,
-    math::{Quat, Vec3},
-};
+use bevy::log::warn;
 use ndk_sys::{
-    ALooper_pollAll, ALooper_prepare, ASensor, ASensorEvent, ASensorEventQueue,
-    ASensorEventQueue_disableSensor, ASensorEventQueue_enableSensor, ASensorEventQueue_getEvents,
-    ASensorEventQueue_setEventRate, ASensorManager, ASensorManager_createEventQueue,
-    ASensorManager_destroyEventQueue, ASensorManager_getDefaultSensor, ASensorManager_getInstance,
-    ALOOPER_PREPARE_ALLOW_NON_CALLBACKS, ASENSOR_STATUS_ACCURACY_HIGH, ASENSOR_STATUS_ACCURACY_LOW,
-    ASENSOR_STATUS_ACCURACY_MEDIUM, ASENSOR_STATUS_NO_CONTACT, ASENSOR_STATUS_UNRELIABLE,
-    ASENSOR_TYPE_ACCELEROMETER, ASENSOR_TYPE_ADDITIONAL_INFO,
-    ASENSOR_TYPE_GEOMAGNETIC_ROTATION_VECTOR, ASENSOR_TYPE_GRAVITY, ASENSOR_TYPE_GYROSCOPE,
-    ASENSOR_TYPE_LINEAR_ACCELERATION, ASENSOR_TYPE_ROTATION_VECTOR,
+    ALOOPER_POLL_ERROR, ALOOPER_PREPARE_ALLOW_NON_CALLBACKS, ALooper_pollAll, ALooper_prepare,
+    ASensor, ASensor_getFifoMaxEventCount, ASensor_getFifoReservedEventCount, ASensor_getMinDelay,
+    ASensor_getName, ASensor_getResolution, ASensor_getStringType, ASensor_getType,
+    ASensor_getVendor, ASensorEvent, ASensorEventQueue, ASensorEventQueue_disableSensor,
+    ASensorEventQueue_getEvents, ASensorEventQueue_registerSensor, ASensorEventQueue_setEventRate,
+    ASensorList, ASensorManager, ASensorManager_createEventQueue, ASensorManager_destroyEventQueue,
+    ASensorManager_getDefaultSensor, ASensorManager_getInstance, ASensorManager_getSensorList,
 };
-use num_derive::FromPrimitive;
-
-#[derive(Clone, Debug, FromPrimitive)]
-pub enum SensorAccuracy {
-    High = ASENSOR_STATUS_ACCURACY_HIGH as isize,
-    Low = ASENSOR_STATUS_ACCURACY_LOW as isize,
-    Medium = ASENSOR_STATUS_ACCURACY_MEDIUM as isize,
-    NoContact = ASENSOR_STATUS_NO_CONTACT as isize,
-    Unreliable = ASENSOR_STATUS_UNRELIABLE as isize,
-}
+use std::ffi::{CStr, c_char};
 
-#[derive(Clone, Copy, Debug, FromPrimitive)]
-pub enum SensorType {
-    Accelerometer = ASENSOR_TYPE_LINEAR_ACCELERATION as isize,
-    Gyroscope = ASENSOR_TYPE_GYROSCOPE as isize,
-    Rotation = ASENSOR_TYPE_ROTATION_VECTOR as isize,
-    Compass = ASENSOR_TYPE_GEOMAGNETIC_ROTATION_VECTOR as isize,
-    Gravity = ASENSOR_TYPE_GRAVITY as isize,
-    AdditionalInfo = ASENSOR_TYPE_ADDITIONAL_INFO as isize,
-    Unavailable = 0,
+use super::error::SensorError;
+use super::event::{RawSensorEvent, SensorEvent, SensorType, decode};
+
+/// Maps a negative NDK status to `error`
+fn check(status: i32, error: fn(i32) -> SensorError) -> Result<(), SensorError> {
+    if status < 0 {
+        Err(error(status))
+    } else {
+        Ok(())
+    }
 }
 
 pub struct Sensor {
     sensor: *const ASensor,
 }
 
-pub struct SensorManager {
-    manager: *mut ASensorManager,
+/// Static description of a sensor as reported by the device.
+/// The NDK does not expose a sensor's maximum range, so it is not part of this.
+#[derive(Clone, Debug)]
+pub struct SensorInfo {
+    pub name: String,
+    pub vendor: String,
+    /// `None` for sensor types this crate does not handle
+    pub sensor_type: Option<SensorType>,
+    /// Android's string type, e.g. `android.sensor.accelerometer`
+    pub type_name: String,
+    pub resolution: f32,
+    /// Shortest sampling period in microseconds, `0` for sensors that only report on change
+    pub min_delay_us: i32,
+    pub fifo_max_event_count: i32,
+    pub fifo_reserved_event_count: i32,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum SensorValues {
-    Vec3(Vec3),
-    Quat(Quat),
-}
+impl Sensor {
+    pub fn info(&self) -> SensorInfo {
+        let string = |ptr: *const c_char| {
+            if ptr.is_null() {
+                String::new()
+            } else {
+                unsafe { CStr::from_ptr(ptr) }
+                    .to_string_lossy()
+                    .into_owned()
+            }
+        };
 
-impl SensorValues {
-    pub fn vec3(&self) -> Option<&Vec3> {
-        match self {
-            SensorValues::Vec3(data) => Some(data),
-            _ => None,
+        unsafe {
+            SensorInfo {
+                name: string(ASensor_getName(self.sensor)),
+                vendor: string(ASensor_getVendor(self.sensor)),
+                sensor_type: num::FromPrimitive::from_i32(ASensor_getType(self.sensor)),
+                type_name: string(ASensor_getStringType(self.sensor)),
+                resolution: ASensor_getResolution(self.sensor),
+                min_delay_us: ASensor_getMinDelay(self.sensor),
+                fifo_max_event_count: ASensor_getFifoMaxEventCount(self.sensor),
+                fifo_reserved_event_count: ASensor_getFifoReservedEventCount(self.sensor),
+            }
         }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct SensorEvent {
-    pub accuracy: SensorAccuracy,
-    pub sensor_type: SensorType,
-    pub timestamp: i64,
-    pub values: SensorValues,
-}
-
-impl Default for SensorEvent {
-    fn default() -> Self {
-        Self {
-            accuracy: SensorAccuracy::NoContact,
-            sensor_type: SensorType::Unavailable,
-            timestamp: 0,
-            values: SensorValues::Vec3(Vec3::ZERO),
-        }
-    }
+/// The process wide sensor manager. It is owned by the system and never freed.
+pub struct SensorManager {
+    manager: *mut ASensorManager,
 }
 
-#[derive(Debug)]
+/// An event queue attached to the calling thread's looper.
+/// It is destroyed when dropped, together with every sensor enabled on it.
 pub struct SensorEventQueue {
+    manager: *mut ASensorManager,
     queue: *mut ASensorEventQueue,
+    buffer: Box<[ASensorEvent]>,
+}
+
+impl From<&ASensorEvent> for RawSensorEvent {
+    fn from(event: &ASensorEvent) -> Self {
+        unsafe {
+            RawSensorEvent {
+                sensor_type: event.type_,
+                timestamp: event.timestamp,
+                data: event.__bindgen_anon_1.__bindgen_anon_1.data,
+                status: event.__bindgen_anon_1.__bindgen_anon_1.vector.status,
+            }
+        }
+    }
 }
 
 impl SensorManager {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, SensorError> {
         let manager = unsafe { ASensorManager_getInstance() };
-        assert!(!manager.is_null(), "*mut ASensorManger is null");
-        Self { manager }
+        if manager.is_null() {
+            return Err(SensorError::ManagerUnavailable);
+        }
+        Ok(Self { manager })
     }
 
-    pub fn get_default_sensor(&self, sensor_type: SensorType) -> Sensor {
+    /// `None` if the device has no sensor of this type
+    pub fn get_default_sensor(&self, sensor_type: SensorType) -> Option<Sensor> {
         let sensor = unsafe { ASensorManager_getDefaultSensor(self.manager, sensor_type as i32) };
-        assert!(!sensor.is_null(), "*const ASensor is null");
-        Sensor { sensor }
+        (!sensor.is_null()).then_some(Sensor { sensor })
     }
 
-    pub fn create_event_queue(&self) -> SensorEventQueue {
+    /// Every sensor on the device, including types this crate does not handle
+    pub fn get_sensor_list(&self) -> Vec<Sensor> {
+        let mut list: ASensorList = std::ptr::null();
+        let count = unsafe { ASensorManager_getSensorList(self.manager, &mut list) };
+        if count <= 0 || list.is_null() {
+            return Vec::new();
+        }
+
+        unsafe { std::slice::from_raw_parts(list, count as usize) }
+            .iter()
+            .filter(|sensor| !sensor.is_null())
+            .map(|&sensor| Sensor { sensor })
+            .collect()
+    }
+
+    pub fn create_event_queue(&self) -> Result<SensorEventQueue, SensorError> {
         let looper_ptr = unsafe { ALooper_prepare(ALOOPER_PREPARE_ALLOW_NON_CALLBACKS as _) };
-        assert!(!looper_ptr.is_null(), "*mut ALooper is null");
+        if looper_ptr.is_null() {
+            return Err(SensorError::LooperUnavailable);
+        }
         let queue = unsafe {
             // ident field has to be 2
             // (https://github.com/rust-mobile/android-activity/blob/9fce89021959a6f6ea8853221367bfa305803369/android-activity/src/native_activity/mod.rs#L290)
             ASensorManager_createEventQueue(self.manager, looper_ptr, 2, None, std::ptr::null_mut())
         };
-        assert!(!queue.is_null(), "*mut ASensorEventQueue is null");
-        SensorEventQueue { queue }
-    }
-
-    pub fn destroy_event_queue(&self, queue: SensorEventQueue) {
-        let status = unsafe { ASensorManager_destroyEventQueue(self.manager, queue.queue) };
-        assert!(status >= 0);
+        if queue.is_null() {
+            return Err(SensorError::QueueCreationFailed);
+        }
+        Ok(SensorEventQueue {
+            manager: self.manager,
+            queue,
+            buffer: vec![unsafe { std::mem::zeroed() }; SensorEventQueue::BATCH_SIZE]
+                .into_boxed_slice(),
+        })
     }
 }
 
 impl SensorEventQueue {
-    pub fn enable_sensor(&self, sensor: &Sensor, sampling_period_us: i32) {
-        let status = unsafe { ASensorEventQueue_enableSensor(self.queue, sensor.sensor) };
-        assert!(status >= 0);
+    const BATCH_SIZE: usize = 64;
+
+    /// Destroys the queue right away. Unlike dropping it, this reports a failure.
+    pub fn destroy(mut self) -> Result<(), SensorError> {
+        let status = unsafe { ASensorManager_destroyEventQueue(self.manager, self.queue) };
+        self.queue = std::ptr::null_mut();
+        check(status, SensorError::QueueDestructionFailed)
+    }
+
+    /// Enables `sensor` at the given rate. With a non-zero `max_report_latency_us` the
+    /// hardware may hold samples in its FIFO for that long and deliver them in one burst.
+    /// `ASensorEventQueue_registerSensor` needs API level 26, the `min_sdk_version`.
+    pub fn enable_sensor(
+        &self,
+        sensor: &Sensor,
+        sampling_period_us: i32,
+        max_report_latency_us: i64,
+    ) -> Result<(), SensorError> {
+        let status = unsafe {
+            ASensorEventQueue_registerSensor(
+                self.queue,
+                sensor.sensor,
+                sampling_period_us,
+                max_report_latency_us,
+            )
+        };
+        check(status, SensorError::EnableFailed)
+    }
+
+    pub fn set_event_rate(
+        &self,
+        sensor: &Sensor,
+        sampling_period_us: i32,
+    ) -> Result<(), SensorError> {
         let status = unsafe {
             ASensorEventQueue_setEventRate(self.queue, sensor.sensor, sampling_period_us)
         };
-        assert!(status >= 0);
+        check(status, SensorError::SetEventRateFailed)
     }
 
-    pub fn get_events(&self) -> Vec<SensorEvent> {
+    /// Drains every pending event, reading them from the NDK in batches of
+    /// [`Self::BATCH_SIZE`] into the queue's reusable buffer. A FIFO flush can
+    /// deliver many batches at once, events are returned in the order they were read.
+    pub fn get_events(&mut self) -> Result<Vec<SensorEvent>, SensorError> {
         let mut fd = -1;
         let mut events = -1;
         let mut data = std::ptr::null_mut();
@@ -133,113 +202,55 @@ impl SensorEventQueue {
             // non-blocking
             ALooper_pollAll(0, &mut fd, &mut events, &mut data)
         };
-        assert_ne!(status, 0);
-
-        let mut event: ASensorEvent = unsafe { std::mem::zeroed() };
-        let mut event_count =
-            unsafe { ASensorEventQueue_getEvents(self.queue, &mut event as *mut _, 1) };
-        assert!(event_count >= 0 && event_count <= 1);
+        if status == ALOOPER_POLL_ERROR {
+            return Err(SensorError::PollFailed(status));
+        }
 
         let mut events: Vec<SensorEvent> = Vec::new();
         loop {
-            if let Some(sensor_type) = num::FromPrimitive::from_i32(event.type_) {
-                match sensor_type {
-                    SensorType::Accelerometer => {
-                        events.push(SensorEvent {
-                            accuracy: num::FromPrimitive::from_i8(unsafe {
-                                event.__bindgen_anon_1.__bindgen_anon_1.acceleration.status
-                            })
-                            .unwrap_or(SensorAccuracy::Unreliable),
-                            sensor_type: SensorType::Accelerometer,
-                            timestamp: event.timestamp,
-                            values: unsafe {
-                                SensorValues::Vec3(Vec3::new(
-                                    event.__bindgen_anon_1.__bindgen_anon_1.data[0],
-                                    event.__bindgen_anon_1.__bindgen_anon_1.data[1],
-                                    event.__bindgen_anon_1.__bindgen_anon_1.data[2],
-                                ))
-                            },
-                        });
+            let event_count = unsafe {
+                ASensorEventQueue_getEvents(
+                    self.queue,
+                    self.buffer.as_mut_ptr(),
+                    self.buffer.len() as _,
+                )
+            };
+            if event_count < 0 {
+                return Err(SensorError::ReadFailed(event_count as i32));
+            }
+
+            for event in &self.buffer[..event_count as usize] {
+                let raw = RawSensorEvent::from(event);
+                match decode(&raw) {
+                    Some(event) => events.push(event),
+                    None if SensorType::from_raw(raw.sensor_type).is_none() => {
+                        warn!("Sensor (type: {}) not recognized!", raw.sensor_type)
                     }
-                    SensorType::Gyroscope => events.push(SensorEvent {
-                        accuracy: num::FromPrimitive::from_i8(unsafe {
-                            event.__bindgen_anon_1.__bindgen_anon_1.gyro.status
-                        })
-                        .unwrap_or(SensorAccuracy::Unreliable),
-                        sensor_type: SensorType::Gyroscope,
-                        timestamp: event.timestamp,
-                        values: unsafe {
-                            SensorValues::Vec3(Vec3::new(
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[0],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[1],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[2],
-                            ))
-                        },
-                    }),
-                    SensorType::Rotation => events.push(SensorEvent {
-                        accuracy: num::FromPrimitive::from_i8(unsafe {
-                            event.__bindgen_anon_1.__bindgen_anon_1.vector.status
-                        })
-                        .unwrap_or(SensorAccuracy::Unreliable),
-                        sensor_type: SensorType::Rotation,
-                        timestamp: event.timestamp,
-                        values: unsafe {
-                            SensorValues::Quat(Quat::from_xyzw(
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[0],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[1],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[2],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[3],
-                            ))
-                        },
-                    }),
-                    SensorType::Compass => events.push(SensorEvent {
-                        accuracy: num::FromPrimitive::from_i8(unsafe {
-                            event.__bindgen_anon_1.__bindgen_anon_1.vector.status
-                        })
-                        .unwrap_or(SensorAccuracy::Unreliable),
-                        sensor_type: SensorType::Compass,
-                        timestamp: event.timestamp,
-                        values: unsafe {
-                            SensorValues::Quat(Quat::from_xyzw(
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[0],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[1],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[2],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[3],
-                            ))
-                        },
-                    }),
-                    SensorType::Gravity => events.push(SensorEvent {
-                        accuracy: num::FromPrimitive::from_i8(unsafe {
-                            event.__bindgen_anon_1.__bindgen_anon_1.vector.status
-                        })
-                        .unwrap_or(SensorAccuracy::Unreliable),
-                        sensor_type: SensorType::Gravity,
-                        timestamp: event.timestamp,
-                        values: unsafe {
-                            SensorValues::Vec3(Vec3::new(
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[0],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[1],
-                                event.__bindgen_anon_1.__bindgen_anon_1.data[2],
-                            ))
-                        },
-                    }),
-                    _ => (),
+                    None => (),
                 }
-            } else {
-                warn!("Sensor (type: {}) not recognized!", event.type_);
             }
 
-            event_count =
-                unsafe { ASensorEventQueue_getEvents(self.queue, &mut event as *mut _, 1) };
-
-            if event_count < 1 {
+            if (event_count as usize) < self.buffer.len() {
                 break;
             }
         }
-        events
+        Ok(events)
+    }
+
+    pub fn disable_sensor(&self, sensor: &Sensor) -> Result<(), SensorError> {
+        let status = unsafe { ASensorEventQueue_disableSensor(self.queue, sensor.sensor) };
+        check(status, SensorError::DisableFailed)
     }
+}
 
-    pub fn disable_sensor(&self, sensor: &Sensor) {
-        unsafe { ASensorEventQueue_disableSensor(self.queue, sensor.sensor) };
+impl Drop for SensorEventQueue {
+    fn drop(&mut self) {
+        if self.queue.is_null() {
+            return;
+        }
+        let status = unsafe { ASensorManager_destroyEventQueue(self.manager, self.queue) };
+        if let Err(error) = check(status, SensorError::QueueDestructionFailed) {
+            warn!("{}", error);
+        }
     }
 }
@@ -0,0 +1,303 @@
+//! WGS84 geodetic coordinates, Earth-centred Earth-fixed (ECEF) positions and
+//! local east-north-up (ENU) / north-east-down (NED) frames. Everything is in f64,
+//! an f32 ECEF coordinate would only resolve about half a metre.
+
+#![allow(dead_code)]
+
+use bevy::math::DVec3;
+use std::fmt;
+
+/// WGS84 semi-major axis in metres
+pub const WGS84_A: f64 = 6_378_137.0;
+/// WGS84 flattening
+pub const WGS84_F: f64 = 1.0 / 298.257_223_563;
+/// WGS84 semi-minor axis in metres
+pub const WGS84_B: f64 = WGS84_A * (1.0 - WGS84_F);
+/// First eccentricity squared
+const WGS84_E2: f64 = WGS84_F * (2.0 - WGS84_F);
+/// Second eccentricity squared
+const WGS84_EP2: f64 = (WGS84_A * WGS84_A - WGS84_B * WGS84_B) / (WGS84_B * WGS84_B);
+
+/// A point on or above the WGS84 ellipsoid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Geodetic {
+    /// Degrees, positive north
+    pub latitude: f64,
+    /// Degrees, positive east
+    pub longitude: f64,
+    /// Metres above the ellipsoid
+    pub altitude: f64,
+}
+
+impl Geodetic {
+    pub fn new(latitude: f64, longitude: f64, altitude: f64) -> Self {
+        Self {
+            latitude,
+            longitude,
+            altitude,
+        }
+    }
+
+    pub fn to_ecef(&self) -> DVec3 {
+        let (sin_lat, cos_lat) = self.latitude.to_radians().sin_cos();
+        let (sin_lon, cos_lon) = self.longitude.to_radians().sin_cos();
+        let prime_vertical = WGS84_A / (1.0 - WGS84_E2 * sin_lat * sin_lat).sqrt();
+
+        DVec3::new(
+            (prime_vertical + self.altitude) * cos_lat * cos_lon,
+            (prime_vertical + self.altitude) * cos_lat * sin_lon,
+            (prime_vertical * (1.0 - WGS84_E2) + self.altitude) * sin_lat,
+        )
+    }
+
+    /// Closed form inverse after Heikkinen (1982), exact to well below a millimetre
+    pub fn from_ecef(ecef: DVec3) -> Self {
+        let p = ecef.x.hypot(ecef.y);
+        let z = ecef.z;
+        if p < 1e-9 {
+            // on the polar axis the longitude is arbitrary
+            return Self::new(90.0_f64.copysign(z), 0.0, z.abs() - WGS84_B);
+        }
+
+        let a2 = WGS84_A * WGS84_A;
+        let b2 = WGS84_B * WGS84_B;
+        let f = 54.0 * b2 * z * z;
+        let g = p * p + (1.0 - WGS84_E2) * z * z - WGS84_E2 * (a2 - b2);
+        let c = WGS84_E2 * WGS84_E2 * f * p * p / (g * g * g);
+        let s = (1.0 + c + (c * c + 2.0 * c).sqrt()).cbrt();
+        let k = s + 1.0 + 1.0 / s;
+        let big_p = f / (3.0 * k * k * g * g);
+        let q = (1.0 + 2.0 * WGS84_E2 * WGS84_E2 * big_p).sqrt();
+        let r0 = -big_p * WGS84_E2 * p / (1.0 + q)
+            + (a2 / 2.0 * (1.0 + 1.0 / q)
+                - big_p * (1.0 - WGS84_E2) * z * z / (q * (1.0 + q))
+                - big_p * p * p / 2.0)
+                .max(0.0)
+                .sqrt();
+        let u = ((p - WGS84_E2 * r0).powi(2) + z * z).sqrt();
+        let v = ((p - WGS84_E2 * r0).powi(2) + (1.0 - WGS84_E2) * z * z).sqrt();
+        let z0 = b2 * z / (WGS84_A * v);
+
+        Self::new(
+            (z + WGS84_EP2 * z0).atan2(p).to_degrees(),
+            ecef.y.atan2(ecef.x).to_degrees(),
+            u * (1.0 - b2 / (WGS84_A * v)),
+        )
+    }
+}
+
+impl fmt::Display for Geodetic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.6}° {}, {:.6}° {}, {:.1} m",
+            self.latitude.abs(),
+            if self.latitude < 0.0 { 'S' } else { 'N' },
+            self.longitude.abs(),
+            if self.longitude < 0.0 { 'W' } else { 'E' },
+            self.altitude
+        )
+    }
+}
+
+pub fn enu_to_ned(enu: DVec3) -> DVec3 {
+    DVec3::new(enu.y, enu.x, -enu.z)
+}
+
+pub fn ned_to_enu(ned: DVec3) -> DVec3 {
+    DVec3::new(ned.y, ned.x, -ned.z)
+}
+
+/// A local tangent plane anchored at a geodetic origin
+#[derive(Clone, Copy, Debug)]
+pub struct LocalFrame {
+    origin: Geodetic,
+    origin_ecef: DVec3,
+    /// ENU axes expressed in ECEF
+    east: DVec3,
+    north: DVec3,
+    up: DVec3,
+}
+
+impl LocalFrame {
+    pub fn new(origin: Geodetic) -> Self {
+        let (sin_lat, cos_lat) = origin.latitude.to_radians().sin_cos();
+        let (sin_lon, cos_lon) = origin.longitude.to_radians().sin_cos();
+
+        Self {
+            origin,
+            origin_ecef: origin.to_ecef(),
+            east: DVec3::new(-sin_lon, cos_lon, 0.0),
+            north: DVec3::new(-sin_lat * cos_lon, -sin_lat * sin_lon, cos_lat),
+            up: DVec3::new(cos_lat * cos_lon, cos_lat * sin_lon, sin_lat),
+        }
+    }
+
+    pub fn origin(&self) -> Geodetic {
+        self.origin
+    }
+
+    pub fn ecef_to_enu(&self, ecef: DVec3) -> DVec3 {
+        let offset = ecef - self.origin_ecef;
+        DVec3::new(
+            offset.dot(self.east),
+            offset.dot(self.north),
+            offset.dot(self.up),
+        )
+    }
+
+    pub fn enu_to_ecef(&self, enu: DVec3) -> DVec3 {
+        self.origin_ecef + self.east * enu.x + self.north * enu.y + self.up * enu.z
+    }
+
+    pub fn enu_to_geodetic(&self, enu: DVec3) -> Geodetic {
+        Geodetic::from_ecef(self.enu_to_ecef(enu))
+    }
+
+    pub fn geodetic_to_enu(&self, geodetic: &Geodetic) -> DVec3 {
+        self.ecef_to_enu(geodetic.to_ecef())
+    }
+
+    pub fn ned_to_geodetic(&self, ned: DVec3) -> Geodetic {
+        self.enu_to_geodetic(ned_to_enu(ned))
+    }
+
+    pub fn geodetic_to_ned(&self, geodetic: &Geodetic) -> DVec3 {
+        enu_to_ned(self.geodetic_to_enu(geodetic))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: DVec3, expected: DVec3, tolerance: f64) {
+        assert!(
+            (actual - expected).abs().max_element() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    fn assert_same_place(actual: Geodetic, expected: Geodetic) {
+        assert!(
+            (actual.latitude - expected.latitude).abs() < 1e-9,
+            "{actual}"
+        );
+        assert!(
+            (actual.longitude - expected.longitude).abs() < 1e-9,
+            "{actual}"
+        );
+        assert!(
+            (actual.altitude - expected.altitude).abs() < 1e-3,
+            "{actual}"
+        );
+    }
+
+    #[test]
+    fn equator_at_the_prime_meridian() {
+        let origin = Geodetic::new(0.0, 0.0, 0.0);
+        assert_close(origin.to_ecef(), DVec3::new(WGS84_A, 0.0, 0.0), 1e-6);
+        assert_same_place(Geodetic::from_ecef(DVec3::new(WGS84_A, 0.0, 0.0)), origin);
+
+        let above = Geodetic::new(0.0, 90.0, 1_000.0);
+        assert_close(
+            above.to_ecef(),
+            DVec3::new(0.0, WGS84_A + 1_000.0, 0.0),
+            1e-6,
+        );
+    }
+
+    #[test]
+    fn poles() {
+        let north = Geodetic::new(90.0, 0.0, 0.0);
+        assert_close(north.to_ecef(), DVec3::new(0.0, 0.0, WGS84_B), 1e-6);
+        assert_same_place(Geodetic::from_ecef(DVec3::new(0.0, 0.0, WGS84_B)), north);
+
+        let south = Geodetic::new(-90.0, 0.0, 250.0);
+        assert_close(
+            south.to_ecef(),
+            DVec3::new(0.0, 0.0, -WGS84_B - 250.0),
+            1e-6,
+        );
+        assert_same_place(
+            Geodetic::from_ecef(DVec3::new(0.0, 0.0, -WGS84_B - 250.0)),
+            south,
+        );
+    }
+
+    #[test]
+    fn los_angeles() {
+        // 34.0522° N, 118.2437° W on the ellipsoid, as published by common ECEF converters
+        let los_angeles = Geodetic::new(34.0522, -118.2437, 0.0);
+        let ecef = DVec3::new(-2_503_357.0, -4_660_203.0, 3_551_245.0);
+
+        assert_close(los_angeles.to_ecef(), ecef, 1.0);
+        assert_same_place(Geodetic::from_ecef(los_angeles.to_ecef()), los_angeles);
+    }
+
+    #[test]
+    fn ecef_round_trips() {
+        for (latitude, longitude, altitude) in [
+            (47.3769, 8.5417, 408.0),
+            (-33.8688, 151.2093, 58.0),
+            (89.9999, -45.0, 3_000.0),
+            (-0.0001, 179.9999, -30.0),
+            (27.9881, 86.925, 8_848.86),
+        ] {
+            let geodetic = Geodetic::new(latitude, longitude, altitude);
+            assert_same_place(Geodetic::from_ecef(geodetic.to_ecef()), geodetic);
+        }
+    }
+
+    #[test]
+    fn enu_and_ned_swap_axes() {
+        let enu = DVec3::new(1.0, 2.0, 3.0);
+        assert_eq!(enu_to_ned(enu), DVec3::new(2.0, 1.0, -3.0));
+        assert_eq!(ned_to_enu(enu_to_ned(enu)), enu);
+    }
+
+    #[test]
+    fn local_frame_round_trips() {
+        let frame = LocalFrame::new(Geodetic::new(47.3769, 8.5417, 408.0));
+        assert_close(frame.geodetic_to_enu(&frame.origin()), DVec3::ZERO, 1e-6);
+
+        for enu in [
+            DVec3::new(100.0, 0.0, 0.0),
+            DVec3::new(0.0, 100.0, 0.0),
+            DVec3::new(-2_500.0, 1_200.0, 35.0),
+            DVec3::new(15_000.0, -8_000.0, -120.0),
+        ] {
+            assert_close(
+                frame.geodetic_to_enu(&frame.enu_to_geodetic(enu)),
+                enu,
+                1e-6,
+            );
+            let ned = enu_to_ned(enu);
+            assert_close(
+                frame.geodetic_to_ned(&frame.ned_to_geodetic(ned)),
+                ned,
+                1e-6,
+            );
+        }
+    }
+
+    #[test]
+    fn local_frame_axes_point_east_north_up() {
+        let origin = Geodetic::new(47.3769, 8.5417, 408.0);
+        let frame = LocalFrame::new(origin);
+
+        let east = frame.enu_to_geodetic(DVec3::new(100.0, 0.0, 0.0));
+        assert!(east.longitude > origin.longitude);
+        assert!((east.latitude - origin.latitude).abs() < 1e-5);
+
+        let north = frame.enu_to_geodetic(DVec3::new(0.0, 100.0, 0.0));
+        assert!(north.latitude > origin.latitude);
+        assert!((north.longitude - origin.longitude).abs() < 1e-9);
+
+        let up = frame.enu_to_geodetic(DVec3::new(0.0, 0.0, 100.0));
+        assert!((up.altitude - origin.altitude - 100.0).abs() < 1e-6);
+
+        let below = frame.ned_to_geodetic(DVec3::new(0.0, 0.0, 10.0));
+        assert!((below.altitude - origin.altitude + 10.0).abs() < 1e-6);
+    }
+}
@@ -1,7 +1,7 @@
 #![allow(clippy::type_complexity)]
 
-#[cfg(target_os = "android")]
 mod ffi;
+mod geodetic;
 mod plugins;
 
 #[cfg(target_os = "android")]
@@ -16,9 +16,13 @@ use bevy::{
 use bevy_debug_text_overlay::OverlayPlugin;
 use bevy_infinite_grid::{InfiniteGridBundle, InfiniteGridPlugin};
 use bevy_screen_diagnostics::{ScreenDiagnosticsPlugin, ScreenFrameDiagnosticsPlugin};
+use plugins::{
+    camera::AppCameraPlugin, config::ConfigPlugin, location::LocationPlugin,
+    mavlink::MavlinkPlugin, mcap::McapPlugin, nmea::NmeaPlugin, osc::OscPlugin,
+    settings::SettingsPlugin, state::StatePlugin, trajectory::TrajectoryPlugin,
+};
 #[cfg(target_os = "android")]
-use plugins::sensor::SensorPlugin;
-use plugins::{camera::AppCameraPlugin, state::StatePlugin};
+use plugins::{diagnostics::SensorDiagnosticsPlugin, sensor::SensorPlugin};
 
 #[bevy_main]
 pub fn main() {
@@ -48,12 +52,23 @@ pub fn main() {
     .add_plugins(ScreenDiagnosticsPlugin::default())
     .add_plugins(ScreenFrameDiagnosticsPlugin)
     .add_plugins(OverlayPlugin::default())
-    .add_plugins((AppCameraPlugin, StatePlugin))
+    // before the plugins whose resources it configures
+    .add_plugins(ConfigPlugin::default())
+    .add_plugins((AppCameraPlugin, StatePlugin, TrajectoryPlugin))
+    .add_plugins((NmeaPlugin, MavlinkPlugin, OscPlugin))
+    .add_plugins(LocationPlugin {
+        // recorded fixes, to test the fusion on desktop
+        replay: std::env::var_os("LOCATION_REPLAY").map(Into::into),
+    })
+    .add_plugins(McapPlugin {
+        replay: std::env::var_os("MCAP_REPLAY").map(Into::into),
+    })
+    .add_plugins(SettingsPlugin)
     .add_systems(Startup, (setup_scene));
 
     #[cfg(target_os = "android")]
     {
-        app.add_plugins(SensorPlugin);
+        app.add_plugins((SensorPlugin, SensorDiagnosticsPlugin::default()));
         app.insert_resource(WinitSettings::mobile());
     }
 
@@ -1,4 +1,13 @@
 pub mod camera;
+pub mod config;
 #[cfg(target_os = "android")]
+pub mod diagnostics;
+pub mod location;
+pub mod mavlink;
+pub mod mcap;
+pub mod nmea;
+pub mod osc;
 pub mod sensor;
+pub mod settings;
 pub mod state;
+pub mod trajectory;
@@ -2,6 +2,7 @@
 
 use bevy::{input::touch, prelude::*, time::Time};
 use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
+use serde::{Deserialize, Serialize};
 
 pub struct AppCameraPlugin;
 
@@ -11,7 +12,7 @@ impl Plugin for AppCameraPlugin {
             .add_systems(PostStartup, setup)
             .add_systems(Update, touch_control)
             .insert_resource(TouchTracker::default())
-            .insert_resource(TouchConfig::default());
+            .init_resource::<TouchConfig>();
     }
 }
 
@@ -38,7 +39,8 @@ enum GestureType {
 
 /// Contains the configuration parameters for the plugin.
 /// A copy of this will be attached as a `Resource` to the `App`.
-#[derive(Resource, Clone)]
+#[derive(Resource, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct TouchConfig {
     /// How far the camera will move relative to the touch drag distance. Higher is faster
     pub drag_sensitivity: f32,
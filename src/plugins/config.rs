@@ -0,0 +1,556 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::camera::TouchConfig;
+use super::location::LocationFilter;
+use super::mavlink::MavlinkOutput;
+use super::mcap::McapRecording;
+use super::nmea::NmeaOutput;
+use super::osc::OscOutput;
+use super::sensor::SENSOR_TYPES;
+#[cfg(target_os = "android")]
+use super::sensor::{SensorConfig, SensorData, SeriesRetention};
+use super::state::{EstimatorConfig, GapPolicy};
+use super::trajectory::{TrajectoryExport, TrajectoryTrail};
+use crate::ffi::event::SensorType;
+
+/// Loads the [`Config`] from a TOML file, keeps the resources it covers in line with it
+/// and writes changes made at runtime back to the file
+pub struct ConfigPlugin {
+    /// `None` runs with the defaults and saves nothing
+    pub path: Option<PathBuf>,
+}
+
+impl Default for ConfigPlugin {
+    fn default() -> Self {
+        Self {
+            path: ConfigPlugin::default_path(),
+        }
+    }
+}
+
+impl ConfigPlugin {
+    const FILE_NAME: &'static str = "config.toml";
+
+    /// The app's internal storage on Android. Elsewhere the path after `--config` on the
+    /// command line, or the working directory.
+    pub fn default_path() -> Option<PathBuf> {
+        #[cfg(target_os = "android")]
+        return bevy::window::ANDROID_APP
+            .get()?
+            .internal_data_path()
+            .map(|directory| directory.join(Self::FILE_NAME));
+        #[cfg(not(target_os = "android"))]
+        Some(
+            std::env::args_os()
+                .skip_while(|arg| *arg != "--config")
+                .nth(1)
+                .map_or_else(|| PathBuf::from(Self::FILE_NAME), PathBuf::from),
+        )
+    }
+}
+
+impl Plugin for ConfigPlugin {
+    fn build(&self, app: &mut App) {
+        let (config, path) = match &self.path {
+            Some(path) => match Config::load(path) {
+                Ok(config) => (config, Some(path.clone())),
+                Err(error) => {
+                    error!(
+                        "Ignoring config file {}, changes will not be saved to it: {}",
+                        path.display(),
+                        error
+                    );
+                    (Config::default(), None)
+                }
+            },
+            None => (Config::default(), None),
+        };
+
+        // added before the plugins that initialize these, so their startup systems
+        // already see the configured values
+        app.insert_resource(config.touch.clone())
+            .insert_resource(config.estimator_config())
+            .insert_resource(config.trail())
+            .insert_resource(config.export.clone())
+            .insert_resource(config.outputs.nmea.clone())
+            .insert_resource(config.outputs.mavlink.clone())
+            .insert_resource(config.outputs.osc.clone())
+            .insert_resource(config.outputs.mcap.clone())
+            .insert_resource(config)
+            .insert_resource(ConfigFile { path })
+            .add_systems(
+                Update,
+                (apply_config, apply_outputs, save_config).run_if(resource_changed::<Config>),
+            );
+
+        #[cfg(target_os = "android")]
+        app.add_systems(
+            Update,
+            apply_sensor_config.run_if(resource_changed::<Config>),
+        );
+    }
+}
+
+/// Everything that can be tuned without recompiling. Every section and field is optional
+/// in the file, whatever is left out keeps its default.
+///
+/// ```toml
+/// [sensors]
+/// rate_hz = 100.0
+/// rates = [{ sensor = "pressure", rate_hz = 10.0 }]
+///
+/// [estimator]
+/// gap_policy = "coast"
+///
+/// [outputs.nmea]
+/// sinks = [{ tcp_server = "0.0.0.0:10110" }]
+/// sentences = ["GGA", "RMC"]
+///
+/// [outputs.osc]
+/// target = "192.168.1.20:9000"
+/// channels = { quaternion = 60.0, "sensor/gyroscope" = 100.0 }
+///
+/// [outputs.mcap]
+/// directory = "recordings"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Resource, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub touch: TouchConfig,
+    pub sensors: SensorsSection,
+    pub estimator: EstimatorSection,
+    pub outputs: OutputsSection,
+    pub visualization: VisualizationSection,
+    pub export: TrajectoryExport,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorsSection {
+    /// Requested rate of every sensor without an entry in `rates`
+    pub rate_hz: f32,
+    pub rates: Vec<SensorRate>,
+    /// Cutoff of the low-pass filter on every sensor series, at most half of the lowest rate
+    pub low_pass_cutoff_hz: f32,
+    /// How much history every sensor series keeps, in seconds
+    pub history_seconds: f32,
+}
+
+impl Default for SensorsSection {
+    fn default() -> Self {
+        Self {
+            rate_hz: 50.0,
+            rates: Vec::new(),
+            low_pass_cutoff_hz: 3.0,
+            history_seconds: 5.0,
+        }
+    }
+}
+
+impl SensorsSection {
+    /// The lowest rate any sensor is requested at, whose Nyquist frequency bounds the cutoff
+    pub fn lowest_rate_hz(&self) -> f32 {
+        self.rates
+            .iter()
+            .map(|rate| rate.rate_hz)
+            .fold(self.rate_hz, f32::min)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorRate {
+    pub sensor: SensorType,
+    pub rate_hz: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EstimatorSection {
+    pub gap_policy: GapPolicy,
+    /// See [`EstimatorConfig::max_gap`]
+    pub max_gap: f32,
+    /// See [`LocationFilter::acceleration_noise`]
+    pub acceleration_noise: f32,
+    /// See [`LocationFilter::speed_noise`]
+    pub speed_noise: f32,
+}
+
+impl Default for EstimatorSection {
+    fn default() -> Self {
+        let estimator = EstimatorConfig::default();
+        let filter = LocationFilter::default();
+        Self {
+            gap_policy: estimator.gap_policy,
+            max_gap: estimator.max_gap,
+            acceleration_noise: filter.acceleration_noise,
+            speed_noise: filter.speed_noise,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputsSection {
+    pub nmea: NmeaOutput,
+    pub mavlink: MavlinkOutput,
+    pub osc: OscOutput,
+    pub mcap: McapRecording,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VisualizationSection {
+    /// See [`TrajectoryTrail::length`]
+    pub trail_length: usize,
+}
+
+impl Default for VisualizationSection {
+    fn default() -> Self {
+        Self {
+            trail_length: TrajectoryTrail::default().length,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(io::Error),
+    Parse(toml::de::Error),
+    /// Every value out of range
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(error) => write!(f, "could not read it: {error}"),
+            ConfigError::Parse(error) => write!(f, "{error}"),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid values:")?;
+                for problem in problems {
+                    write!(f, "\n  {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// The defaults if there is no file at `path`
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(error) => return Err(ConfigError::Read(error)),
+        };
+        let config: Self = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) {
+        let result = toml::to_string_pretty(self)
+            .map_err(|error| error.to_string())
+            .and_then(|contents| fs::write(path, contents).map_err(|error| error.to_string()));
+        if let Err(error) = result {
+            warn!("Could not save config to {}: {}", path.display(), error);
+        }
+    }
+
+    /// Every value out of range, as `section.field must be ...`
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        // written so that NaN fails every check
+        let mut check = |valid: bool, field: &str, requirement: &str, value: f32| {
+            if !valid {
+                problems.push(format!("{field} must be {requirement}, not {value}"));
+            }
+        };
+
+        let touch = &self.touch;
+        check(
+            touch.drag_sensitivity > 0.0,
+            "touch.drag_sensitivity",
+            "positive",
+            touch.drag_sensitivity,
+        );
+        check(
+            touch.zoom_sensitivity > 0.0,
+            "touch.zoom_sensitivity",
+            "positive",
+            touch.zoom_sensitivity,
+        );
+        check(
+            touch.touch_time_min >= 0.0,
+            "touch.touch_time_min",
+            "zero or more",
+            touch.touch_time_min,
+        );
+        check(
+            (-1.0..=1.0).contains(&touch.opposites_tolerance),
+            "touch.opposites_tolerance",
+            "between -1 and 1",
+            touch.opposites_tolerance,
+        );
+
+        let sensors = &self.sensors;
+        check(
+            sensors.rate_hz > 0.0,
+            "sensors.rate_hz",
+            "positive",
+            sensors.rate_hz,
+        );
+        for rate in &sensors.rates {
+            check(
+                rate.rate_hz > 0.0,
+                &format!("sensors.rates ({:?})", rate.sensor),
+                "positive",
+                rate.rate_hz,
+            );
+        }
+        // above half the sample rate the filter passes everything anyway
+        check(
+            sensors.low_pass_cutoff_hz > 0.0
+                && sensors.low_pass_cutoff_hz <= sensors.lowest_rate_hz() / 2.0,
+            "sensors.low_pass_cutoff_hz",
+            "positive and at most half of the lowest sensor rate",
+            sensors.low_pass_cutoff_hz,
+        );
+        check(
+            sensors.history_seconds > 0.0,
+            "sensors.history_seconds",
+            "positive",
+            sensors.history_seconds,
+        );
+
+        let estimator = &self.estimator;
+        check(
+            estimator.max_gap > 0.0,
+            "estimator.max_gap",
+            "positive",
+            estimator.max_gap,
+        );
+        check(
+            estimator.acceleration_noise > 0.0,
+            "estimator.acceleration_noise",
+            "positive",
+            estimator.acceleration_noise,
+        );
+        check(
+            estimator.speed_noise > 0.0,
+            "estimator.speed_noise",
+            "positive",
+            estimator.speed_noise,
+        );
+
+        let outputs = &self.outputs;
+        check(
+            outputs.nmea.rate_hz > 0.0,
+            "outputs.nmea.rate_hz",
+            "positive",
+            outputs.nmea.rate_hz,
+        );
+        check(
+            outputs.mavlink.rate_hz > 0.0,
+            "outputs.mavlink.rate_hz",
+            "positive",
+            outputs.mavlink.rate_hz,
+        );
+        for (channel, &rate_hz) in &outputs.osc.channels {
+            check(
+                rate_hz > 0.0,
+                &format!("outputs.osc.channels.{channel}"),
+                "positive",
+                rate_hz,
+            );
+        }
+        if !outputs.osc.namespace.starts_with('/') {
+            problems.push(format!(
+                "outputs.osc.namespace must start with /, not {:?}",
+                outputs.osc.namespace
+            ));
+        }
+
+        for (index, rate) in sensors.rates.iter().enumerate() {
+            if !SENSOR_TYPES.contains(&rate.sensor) {
+                problems.push(format!(
+                    "sensors.rates sensor must be one of {:?}, not {:?}",
+                    SENSOR_TYPES, rate.sensor
+                ));
+            } else if sensors.rates[..index]
+                .iter()
+                .any(|other| other.sensor == rate.sensor)
+            {
+                problems.push(format!(
+                    "sensors.rates must list {:?} only once",
+                    rate.sensor
+                ));
+            }
+        }
+
+        problems
+    }
+
+    pub fn estimator_config(&self) -> EstimatorConfig {
+        EstimatorConfig {
+            gap_policy: self.estimator.gap_policy,
+            max_gap: self.estimator.max_gap,
+        }
+    }
+
+    pub fn trail(&self) -> TrajectoryTrail {
+        TrajectoryTrail {
+            length: self.visualization.trail_length,
+        }
+    }
+
+    /// Rate requested from `sensor_type`
+    #[cfg(target_os = "android")]
+    pub fn sensor_rate(&self, sensor_type: SensorType) -> f32 {
+        self.sensors
+            .rates
+            .iter()
+            .find(|rate| rate.sensor == sensor_type)
+            .map_or(self.sensors.rate_hz, |rate| rate.rate_hz)
+    }
+}
+
+/// Where the [`Config`] came from and goes back to, `None` if it must not be written
+#[derive(Debug, Resource)]
+pub struct ConfigFile {
+    pub path: Option<PathBuf>,
+}
+
+/// Pushes the config to the resources it covers. Those that did not change are left
+/// untouched, so their change detection only fires for actual changes.
+fn apply_config(
+    config: Res<Config>,
+    mut touch: ResMut<TouchConfig>,
+    mut estimator: ResMut<EstimatorConfig>,
+    mut location_filter: ResMut<LocationFilter>,
+    mut trail: ResMut<TrajectoryTrail>,
+    mut export: ResMut<TrajectoryExport>,
+) {
+    touch.set_if_neq(config.touch.clone());
+    estimator.set_if_neq(config.estimator_config());
+    location_filter.acceleration_noise = config.estimator.acceleration_noise;
+    location_filter.speed_noise = config.estimator.speed_noise;
+    trail.set_if_neq(config.trail());
+    export.set_if_neq(config.export.clone());
+}
+
+/// Changing an output reopens it, so the others must keep their change ticks
+fn apply_outputs(
+    config: Res<Config>,
+    mut nmea: ResMut<NmeaOutput>,
+    mut mavlink: ResMut<MavlinkOutput>,
+    mut osc: ResMut<OscOutput>,
+    mut mcap: ResMut<McapRecording>,
+) {
+    nmea.set_if_neq(config.outputs.nmea.clone());
+    mavlink.set_if_neq(config.outputs.mavlink.clone());
+    osc.set_if_neq(config.outputs.osc.clone());
+    mcap.set_if_neq(config.outputs.mcap.clone());
+}
+
+#[cfg(target_os = "android")]
+fn apply_sensor_config(
+    config: Res<Config>,
+    mut sensor_config: ResMut<SensorConfig>,
+    mut sensor_data: ResMut<SensorData>,
+) {
+    for sensor_type in SENSOR_TYPES {
+        let rate_hz = config.sensor_rate(sensor_type);
+        if sensor_config.get(sensor_type).rate_hz != rate_hz {
+            sensor_config.set_rate(sensor_type, rate_hz);
+        }
+    }
+    sensor_data.set_low_pass(config.sensors.low_pass_cutoff_hz, |sensor_type| {
+        config.sensor_rate(sensor_type)
+    });
+    sensor_data.set_retention(SeriesRetention::Window(
+        (config.sensors.history_seconds * 1e9) as i64,
+    ));
+    sensor_data.cover_report_latency(sensor_config.max_report_latency_us());
+}
+
+/// Writes changes made at runtime, but not what was just loaded
+fn save_config(config: Res<Config>, file: Res<ConfigFile>) {
+    if !config.is_added()
+        && let Some(path) = &file.path
+    {
+        config.save(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_must_name_configurable_sensors_once() {
+        let rate = |sensor| SensorRate {
+            sensor,
+            rate_hz: 10.0,
+        };
+        let mut config = Config::default();
+        config.sensors.rates = vec![rate(SensorType::Pressure), rate(SensorType::Gyroscope)];
+        assert_eq!(config.validate(), Vec::<String>::new());
+
+        for sensor in [
+            SensorType::Unavailable,
+            SensorType::Location,
+            SensorType::AdditionalInfo,
+            SensorType::Pressure,
+        ] {
+            config.sensors.rates.push(rate(sensor));
+        }
+        let problems = config.validate();
+        assert_eq!(problems.len(), 4, "{problems:?}");
+        assert!(problems[0].ends_with("not Unavailable"), "{problems:?}");
+        assert!(problems[1].ends_with("not Location"), "{problems:?}");
+        assert!(problems[2].ends_with("not AdditionalInfo"), "{problems:?}");
+        assert!(problems[3].contains("Pressure only once"), "{problems:?}");
+    }
+
+    #[test]
+    fn cutoff_must_stay_below_the_lowest_nyquist_frequency() {
+        let mut config = Config::default();
+        config.sensors.rate_hz = 50.0;
+        config.sensors.low_pass_cutoff_hz = 5.0;
+        config.sensors.rates = vec![SensorRate {
+            sensor: SensorType::Pressure,
+            rate_hz: 10.0,
+        }];
+        assert_eq!(config.sensors.lowest_rate_hz(), 10.0);
+        assert_eq!(config.validate(), Vec::<String>::new());
+
+        config.sensors.low_pass_cutoff_hz = 5.5;
+        assert_eq!(
+            config.validate(),
+            [
+                "sensors.low_pass_cutoff_hz must be positive and at most half of the lowest \
+                 sensor rate, not 5.5"
+            ]
+        );
+
+        // the default rate can be the lowest one too
+        config.sensors.rates[0].rate_hz = 100.0;
+        assert_eq!(config.validate(), Vec::<String>::new());
+        config.sensors.rate_hz = 10.0;
+        assert_eq!(config.validate().len(), 1);
+    }
+}
@@ -0,0 +1,191 @@
+use bevy::diagnostic::{
+    Diagnostic, DiagnosticPath, Diagnostics, LogDiagnosticsPlugin, RegisterDiagnostic,
+};
+use bevy::prelude::*;
+use bevy_screen_diagnostics::{Aggregate, ScreenDiagnostics};
+
+use super::sensor::{SENSOR_TYPES, SensorAccuracies, SensorClock, SensorData};
+use crate::ffi::event::{SensorAccuracy, SensorType};
+
+/// Number of sensor events read in a frame
+pub const QUEUE_LENGTH: DiagnosticPath = DiagnosticPath::const_new("sensor/queue_length");
+
+/// Registers health diagnostics for every sensor, shows some of them next to the
+/// frame stats of the `ScreenDiagnosticsPlugin` and optionally logs all of them
+pub struct SensorDiagnosticsPlugin {
+    /// Sensors whose diagnostics are shown on screen
+    pub on_screen: Vec<SensorType>,
+    /// Writes every sensor diagnostic to the log
+    pub log: bool,
+}
+
+impl Default for SensorDiagnosticsPlugin {
+    fn default() -> Self {
+        Self {
+            on_screen: vec![SensorType::Accelerometer, SensorType::Gyroscope],
+            log: false,
+        }
+    }
+}
+
+impl Plugin for SensorDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        let sensors = SENSOR_TYPES
+            .iter()
+            .map(|&sensor_type| SensorPaths::new(sensor_type))
+            .collect::<Vec<_>>();
+
+        app.register_diagnostic(Diagnostic::new(QUEUE_LENGTH).with_suffix(" events"));
+        for sensor in &sensors {
+            app.register_diagnostic(Diagnostic::new(sensor.rate.clone()).with_suffix(" Hz"))
+                .register_diagnostic(Diagnostic::new(sensor.jitter.clone()).with_suffix(" ms"))
+                .register_diagnostic(
+                    Diagnostic::new(sensor.rejected.clone()).with_suffix(" samples"),
+                )
+                .register_diagnostic(Diagnostic::new(sensor.latency.clone()).with_suffix(" ms"))
+                .register_diagnostic(Diagnostic::new(sensor.accuracy.clone()));
+        }
+
+        if self.log {
+            let mut filter = sensors
+                .iter()
+                .flat_map(|sensor| sensor.all().map(Clone::clone))
+                .collect::<Vec<_>>();
+            filter.push(QUEUE_LENGTH);
+            app.add_plugins(LogDiagnosticsPlugin::filtered(filter));
+        }
+
+        app.insert_resource(SensorDiagnosticPaths {
+            sensors,
+            on_screen: self.on_screen.clone(),
+        })
+        .add_systems(Startup, show_sensor_diagnostics)
+        .add_systems(Update, measure_sensor_health);
+    }
+}
+
+/// Diagnostics of a single sensor
+struct SensorPaths {
+    sensor_type: SensorType,
+    /// Measured rate of the accepted samples
+    rate: DiagnosticPath,
+    /// Standard deviation of the time between samples
+    jitter: DiagnosticPath,
+    /// Samples rejected by the series so far, decimated or out of order
+    rejected: DiagnosticPath,
+    /// Age of the newest sample in app time
+    latency: DiagnosticPath,
+    /// Reported accuracy level, from 0 (unreliable) to 3 (high)
+    accuracy: DiagnosticPath,
+}
+
+impl SensorPaths {
+    fn new(sensor_type: SensorType) -> Self {
+        let path =
+            |metric: &str| DiagnosticPath::new(format!("sensor/{:?}/{}", sensor_type, metric));
+        Self {
+            sensor_type,
+            rate: path("rate"),
+            jitter: path("jitter"),
+            rejected: path("rejected"),
+            latency: path("latency"),
+            accuracy: path("accuracy"),
+        }
+    }
+
+    fn all(&self) -> [&DiagnosticPath; 5] {
+        [
+            &self.rate,
+            &self.jitter,
+            &self.rejected,
+            &self.latency,
+            &self.accuracy,
+        ]
+    }
+}
+
+#[derive(Resource)]
+struct SensorDiagnosticPaths {
+    sensors: Vec<SensorPaths>,
+    on_screen: Vec<SensorType>,
+}
+
+fn show_sensor_diagnostics(
+    paths: Res<SensorDiagnosticPaths>,
+    mut screen_diagnostics: ResMut<ScreenDiagnostics>,
+) {
+    screen_diagnostics
+        .add("sensor events".to_string(), QUEUE_LENGTH)
+        .aggregate(Aggregate::Value)
+        .format(|value| format!("{value:.0}"));
+
+    for sensor in paths
+        .sensors
+        .iter()
+        .filter(|sensor| paths.on_screen.contains(&sensor.sensor_type))
+    {
+        let name = format!("{:?}", sensor.sensor_type);
+        screen_diagnostics
+            .add(format!("{name} Hz"), sensor.rate.clone())
+            .aggregate(Aggregate::Value)
+            .format(|value| format!("{value:.1}"));
+        screen_diagnostics
+            .add(format!("{name} jitter ms"), sensor.jitter.clone())
+            .aggregate(Aggregate::Value)
+            .format(|value| format!("{value:.2}"));
+        screen_diagnostics
+            .add(format!("{name} rejected"), sensor.rejected.clone())
+            .aggregate(Aggregate::Value)
+            .format(|value| format!("{value:.0}"));
+        screen_diagnostics
+            .add(format!("{name} latency ms"), sensor.latency.clone())
+            .aggregate(Aggregate::Value)
+            .format(|value| format!("{value:.1}"));
+        screen_diagnostics
+            .add(format!("{name} accuracy"), sensor.accuracy.clone())
+            .aggregate(Aggregate::Value)
+            .format(|value| format!("{value:.0}"));
+    }
+}
+
+fn measure_sensor_health(
+    paths: Res<SensorDiagnosticPaths>,
+    sensor_data: Res<SensorData>,
+    accuracies: Res<SensorAccuracies>,
+    clock: Res<SensorClock>,
+    mut diagnostics: Diagnostics,
+) {
+    let now = clock.app_time_now();
+
+    for sensor in &paths.sensors {
+        let Some(series) = sensor_data
+            .series(sensor.sensor_type)
+            .filter(|series| series.has_data())
+        else {
+            continue;
+        };
+
+        if let Some(rate) = series.measured_rate() {
+            diagnostics.add_measurement(&sensor.rate, || rate as f64);
+        }
+        if let Some(jitter) = series.interval_jitter() {
+            diagnostics.add_measurement(&sensor.jitter, || jitter as f64 * 1e3);
+        }
+        diagnostics.add_measurement(&sensor.rejected, || series.stats().rejected() as f64);
+        if let Some(delivered) = clock.to_app_time(series.latest().unwrap().timestamp) {
+            diagnostics.add_measurement(&sensor.latency, || (now - delivered) as f64 * 1e-6);
+        }
+        if let Some(accuracy) = accuracies.get(sensor.sensor_type) {
+            let level = match accuracy {
+                SensorAccuracy::High => Some(3.0),
+                SensorAccuracy::Medium => Some(2.0),
+                SensorAccuracy::Low => Some(1.0),
+                SensorAccuracy::Unreliable | SensorAccuracy::NoContact => Some(0.0),
+                SensorAccuracy::Unknown => None,
+            };
+            if let Some(level) = level {
+                diagnostics.add_measurement(&sensor.accuracy, || level);
+            }
+        }
+    }
+}
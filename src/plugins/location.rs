@@ -0,0 +1,493 @@
+use bevy::prelude::*;
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::state::{GeodeticOrigin, StateVector};
+use crate::ffi::event::{LocationFix, SensorAccuracy, SensorEvent, SensorType, SensorValues};
+
+/// Corrects the inertial estimate with location fixes. The first fix anchors the local
+/// frame unless a [`GeodeticOrigin`] is already set.
+#[derive(Default)]
+pub struct LocationPlugin {
+    /// CSV file of fixes to play back, see [`LocationReplay`]
+    pub replay: Option<PathBuf>,
+}
+
+impl Plugin for LocationPlugin {
+    fn build(&self, app: &mut App) {
+        let replay = match &self.replay {
+            Some(path) => LocationReplay::from_file(path).unwrap_or_else(|error| {
+                error!(
+                    "Could not load location replay {}: {}",
+                    path.display(),
+                    error
+                );
+                LocationReplay::default()
+            }),
+            None => LocationReplay::default(),
+        };
+
+        app.insert_resource(replay)
+            .init_resource::<LocationFilter>()
+            .add_event::<LocationMeasured>()
+            .add_systems(Update, (replay_locations, fuse_locations).chain());
+    }
+}
+
+/// A [`SensorEvent`] of type [`SensorType::Location`], from whatever provides the fixes
+#[derive(Clone, Debug, Event)]
+pub struct LocationMeasured(pub SensorEvent);
+
+/// Per-axis variances of the position and velocity estimate. They grow between fixes and
+/// shrink with each fix, like a Kalman filter whose axes are decoupled.
+#[derive(Clone, Debug, Resource)]
+pub struct LocationFilter {
+    /// Standard deviation of the acceleration the inertial estimate gets wrong, in m/s²
+    pub acceleration_noise: f32,
+    /// Standard deviation of the speed of a fix, in m/s
+    pub speed_noise: f32,
+    position_variance: Vec3,
+    velocity_variance: Vec3,
+    last_fix: Option<i64>,
+}
+
+impl Default for LocationFilter {
+    fn default() -> Self {
+        Self {
+            acceleration_noise: 0.5,
+            speed_noise: 0.5,
+            position_variance: Vec3::splat(Self::INITIAL_VARIANCE),
+            velocity_variance: Vec3::splat(Self::INITIAL_VARIANCE),
+            last_fix: None,
+        }
+    }
+}
+
+impl LocationFilter {
+    /// Without a vertical accuracy the vertical error is taken as this many times the horizontal
+    const VERTICAL_ACCURACY_FACTOR: f32 = 1.5;
+    /// Variance of an estimate no fix has corrected yet, in m² and m²/s²,
+    /// large enough for the first fix to be taken almost as is
+    const INITIAL_VARIANCE: f32 = 1e6;
+
+    /// Forgets all fixes, so the next one is taken almost as is
+    pub fn reset(&mut self) {
+        *self = Self {
+            acceleration_noise: self.acceleration_noise,
+            speed_noise: self.speed_noise,
+            ..default()
+        };
+    }
+
+    /// Grows the variances by the noise accumulated since the previous fix
+    fn predict(&mut self, timestamp: i64) {
+        let Some(last_fix) = self.last_fix else {
+            return;
+        };
+        let dt = ((timestamp - last_fix) as f32 * 1e-9).max(0.0);
+        let process_noise = self.acceleration_noise * self.acceleration_noise;
+
+        self.position_variance +=
+            self.velocity_variance * dt * dt + process_noise * dt.powi(3) / 3.0;
+        self.velocity_variance += Vec3::splat(process_noise * dt);
+    }
+
+    /// Gains for a measurement of the given per-axis variance, updating `variance` to match.
+    /// An infinite measurement variance leaves that axis alone.
+    fn gain(variance: &mut Vec3, measurement_variance: Vec3) -> Vec3 {
+        let gain = *variance / (*variance + measurement_variance);
+        *variance *= Vec3::ONE - gain;
+        gain
+    }
+}
+
+/// Fixes read from a CSV file and played back in real time, to test the fusion without a
+/// receiver. The header is `timestamp,latitude,longitude,altitude,accuracy,speed,bearing`
+/// with timestamps in seconds; speed and bearing may be left empty.
+#[derive(Debug, Default, Resource)]
+pub struct LocationReplay {
+    fixes: VecDeque<(f64, LocationFix)>,
+    /// Real time minus file time, fixed when the first fix is played
+    offset: Option<f64>,
+}
+
+impl LocationReplay {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut fixes = VecDeque::new();
+
+        for (number, line) in contents.lines().enumerate().skip(1) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+            if fields.len() < 5 {
+                return Err(format!("line {}: expected at least 5 fields", number + 1));
+            }
+            let required = |index: usize| {
+                fields[index]
+                    .parse::<f64>()
+                    .map_err(|error| format!("line {}, field {}: {}", number + 1, index + 1, error))
+            };
+            let optional = |index: usize| match fields.get(index) {
+                Some(field) if !field.is_empty() => {
+                    field.parse::<f32>().map(Some).map_err(|error| {
+                        format!("line {}, field {}: {}", number + 1, index + 1, error)
+                    })
+                }
+                _ => Ok(None),
+            };
+
+            fixes.push_back((
+                required(0)?,
+                LocationFix {
+                    latitude: required(1)?,
+                    longitude: required(2)?,
+                    altitude: required(3)?,
+                    accuracy: required(4)? as f32,
+                    vertical_accuracy: None,
+                    speed: optional(5)?,
+                    bearing: optional(6)?,
+                },
+            ));
+        }
+
+        info!("Loaded {} location fixes", fixes.len());
+        Ok(Self {
+            fixes,
+            offset: None,
+        })
+    }
+}
+
+fn replay_locations(
+    time: Res<Time<Real>>,
+    mut replay: ResMut<LocationReplay>,
+    mut locations: EventWriter<LocationMeasured>,
+) {
+    let Some(&(first, _)) = replay.fixes.front() else {
+        return;
+    };
+    let now = time.elapsed_secs_f64();
+    let offset = *replay.offset.get_or_insert(now - first);
+
+    while let Some(&(timestamp, fix)) = replay.fixes.front() {
+        if timestamp + offset > now {
+            break;
+        }
+        replay.fixes.pop_front();
+        locations.write(LocationMeasured(SensorEvent {
+            accuracy: SensorAccuracy::Unknown,
+            sensor_type: SensorType::Location,
+            timestamp: (timestamp * 1e9) as i64,
+            values: SensorValues::Location(fix),
+        }));
+    }
+}
+
+/// Applies each fix to the current estimate. Fixes are assumed to be fresh, their
+/// latency is not compensated. Until the orientation is aligned the estimate is not in
+/// east, north, up, so fixes are dropped.
+fn fuse_locations(
+    mut locations: EventReader<LocationMeasured>,
+    mut filter: ResMut<LocationFilter>,
+    mut origin: ResMut<GeodeticOrigin>,
+    mut states: ResMut<StateVector>,
+) {
+    if !states.is_aligned() {
+        locations.clear();
+        return;
+    }
+    for LocationMeasured(event) in locations.read() {
+        let Some(fix) = event.values.location() else {
+            continue;
+        };
+        if origin.frame().is_none() {
+            info!("Anchoring the local frame at {}", fix.geodetic());
+            origin.set(fix.geodetic());
+        }
+        let measured = origin
+            .frame()
+            .unwrap()
+            .geodetic_to_enu(&fix.geodetic())
+            .as_vec3();
+
+        filter.predict(event.timestamp);
+        filter.last_fix = Some(event.timestamp);
+
+        let vertical_accuracy = fix
+            .vertical_accuracy
+            .unwrap_or(fix.accuracy * LocationFilter::VERTICAL_ACCURACY_FACTOR);
+        let position_error = Vec3::new(fix.accuracy, fix.accuracy, vertical_accuracy);
+        let gain = LocationFilter::gain(
+            &mut filter.position_variance,
+            position_error * position_error,
+        );
+        states.correct_position(measured, gain);
+
+        // fixes carry no vertical speed, that is left to the inertial and barometric estimate
+        if let Some(velocity) = fix.velocity() {
+            let speed_variance = filter.speed_noise * filter.speed_noise;
+            let gain = LocationFilter::gain(
+                &mut filter.velocity_variance,
+                Vec3::new(speed_variance, speed_variance, f32::INFINITY),
+            );
+            states.correct_velocity(velocity, gain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geodetic::{Geodetic, LocalFrame};
+    use bevy::ecs::system::SystemId;
+    use bevy::math::DVec3;
+
+    const HEADER: &str = "timestamp,latitude,longitude,altitude,accuracy,speed,bearing\n";
+
+    #[test]
+    fn replay_parses_fixes() {
+        let contents = format!(
+            "{HEADER}12.5, 47.5, 8.25, 400.0, 4.0, 1.5, 90\n\n13.5,47.6,8.3,401,3,,\n14.5,47.7,8.35,402,2\n"
+        );
+        let replay = LocationReplay::parse(&contents).unwrap();
+        let fixes = replay.fixes.iter().copied().collect::<Vec<_>>();
+        assert_eq!(
+            fixes,
+            [
+                (
+                    12.5,
+                    LocationFix {
+                        latitude: 47.5,
+                        longitude: 8.25,
+                        altitude: 400.0,
+                        accuracy: 4.0,
+                        vertical_accuracy: None,
+                        speed: Some(1.5),
+                        bearing: Some(90.0),
+                    }
+                ),
+                (
+                    13.5,
+                    LocationFix {
+                        latitude: 47.6,
+                        longitude: 8.3,
+                        altitude: 401.0,
+                        accuracy: 3.0,
+                        vertical_accuracy: None,
+                        speed: None,
+                        bearing: None,
+                    }
+                ),
+                (
+                    14.5,
+                    LocationFix {
+                        latitude: 47.7,
+                        longitude: 8.35,
+                        altitude: 402.0,
+                        accuracy: 2.0,
+                        vertical_accuracy: None,
+                        speed: None,
+                        bearing: None,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(replay.offset, None);
+    }
+
+    #[test]
+    fn replay_errors_name_the_line_and_field() {
+        let error = |rows: &str| LocationReplay::parse(&format!("{HEADER}{rows}")).unwrap_err();
+
+        assert_eq!(
+            error("1,47,8,400,3\n2,47,8,400\n"),
+            "line 3: expected at least 5 fields"
+        );
+        let bad_longitude = error("1,47,east,400,3\n");
+        assert!(
+            bad_longitude.starts_with("line 2, field 3: "),
+            "{bad_longitude}"
+        );
+        let bad_bearing = error("1,47,8,400,3,1,\n2,47,8,400,3,,north\n");
+        assert!(
+            bad_bearing.starts_with("line 3, field 7: "),
+            "{bad_bearing}"
+        );
+    }
+
+    #[test]
+    fn variances_grow_between_fixes() {
+        let mut filter = LocationFilter {
+            position_variance: Vec3::splat(1.0),
+            velocity_variance: Vec3::splat(2.0),
+            ..default()
+        };
+
+        // nothing to grow from before the first fix
+        filter.predict(1_000_000_000);
+        assert_eq!(filter.position_variance, Vec3::splat(1.0));
+        assert_eq!(filter.velocity_variance, Vec3::splat(2.0));
+
+        // two seconds at 0.5 m/s² of acceleration noise
+        filter.last_fix = Some(1_000_000_000);
+        filter.predict(3_000_000_000);
+        let position = 1.0 + 2.0 * 4.0 + 0.25 * 8.0 / 3.0;
+        assert!(
+            filter
+                .position_variance
+                .abs_diff_eq(Vec3::splat(position), 1e-5)
+        );
+        assert!(filter.velocity_variance.abs_diff_eq(Vec3::splat(2.5), 1e-6));
+    }
+
+    #[test]
+    fn gain_falls_as_the_accuracy_worsens() {
+        let mut previous = 1.0;
+        for accuracy in [1.0, 2.0, 5.0, 20.0] {
+            let mut variance = Vec3::splat(25.0);
+            let gain = LocationFilter::gain(&mut variance, Vec3::splat(accuracy * accuracy));
+            let expected = 25.0 / (25.0 + accuracy * accuracy);
+            assert!(gain.abs_diff_eq(Vec3::splat(expected), 1e-6), "{gain}");
+            assert!(variance.abs_diff_eq(Vec3::splat(25.0 * (1.0 - expected)), 1e-4));
+            assert!(expected < previous);
+            previous = expected;
+        }
+
+        let mut variance = Vec3::splat(25.0);
+        let gain = LocationFilter::gain(&mut variance, Vec3::new(25.0, 25.0, f32::INFINITY));
+        assert_eq!(gain, Vec3::new(0.5, 0.5, 0.0));
+        assert_eq!(variance, Vec3::new(12.5, 12.5, 25.0));
+    }
+
+    fn fusion(states: StateVector) -> (World, SystemId) {
+        let mut world = World::new();
+        world.init_resource::<Events<LocationMeasured>>();
+        world.init_resource::<LocationFilter>();
+        world.init_resource::<GeodeticOrigin>();
+        world.insert_resource(states);
+        let fuse = world.register_system(fuse_locations);
+        (world, fuse)
+    }
+
+    fn measured(timestamp: i64, fix: LocationFix) -> LocationMeasured {
+        LocationMeasured(SensorEvent {
+            accuracy: SensorAccuracy::Unknown,
+            sensor_type: SensorType::Location,
+            timestamp,
+            values: SensorValues::Location(fix),
+        })
+    }
+
+    fn fix_at(geodetic: Geodetic, accuracy: f32) -> LocationFix {
+        LocationFix {
+            latitude: geodetic.latitude,
+            longitude: geodetic.longitude,
+            altitude: geodetic.altitude,
+            accuracy,
+            vertical_accuracy: None,
+            speed: None,
+            bearing: None,
+        }
+    }
+
+    fn aligned_at(position: Vec3, velocity: Vec3) -> StateVector {
+        let mut states = StateVector::default();
+        states.set_pose(position, velocity, Quat::IDENTITY);
+        states
+    }
+
+    #[test]
+    fn first_fix_anchors_the_origin() {
+        let (mut world, fuse) = fusion(aligned_at(Vec3::new(3.0, 4.0, 1.0), Vec3::ZERO));
+        let fix = fix_at(Geodetic::new(47.5, 8.25, 400.0), 4.0);
+        world.send_event(measured(1_000_000_000, fix));
+        world.run_system(fuse).unwrap();
+
+        let origin = world.resource::<GeodeticOrigin>().frame().unwrap().origin();
+        assert_eq!(origin, fix.geodetic());
+        // the first fix is taken almost as is
+        let position = world.resource::<StateVector>().position();
+        assert!(position.abs_diff_eq(Vec3::ZERO, 1e-3), "{position}");
+    }
+
+    #[test]
+    fn fixes_before_alignment_are_dropped() {
+        let (mut world, fuse) = fusion(StateVector::default());
+        let fix = fix_at(Geodetic::new(47.5, 8.25, 400.0), 4.0);
+        world.send_event(measured(1_000_000_000, fix));
+        world.run_system(fuse).unwrap();
+        assert!(world.resource::<GeodeticOrigin>().frame().is_none());
+        assert_eq!(world.resource::<LocationFilter>().last_fix, None);
+
+        // aligning later does not bring the dropped fix back
+        world
+            .resource_mut::<StateVector>()
+            .set_pose(Vec3::X, Vec3::ZERO, Quat::IDENTITY);
+        world.run_system(fuse).unwrap();
+        assert!(world.resource::<GeodeticOrigin>().frame().is_none());
+        assert_eq!(world.resource::<StateVector>().position(), Vec3::X);
+    }
+
+    #[test]
+    fn fixes_correct_position_and_velocity_by_the_gain() {
+        let origin = Geodetic::new(47.5, 8.25, 400.0);
+        let (mut world, fuse) = fusion(aligned_at(Vec3::ZERO, Vec3::new(0.0, 1.0, 0.5)));
+        world.resource_mut::<GeodeticOrigin>().set(origin);
+        *world.resource_mut::<LocationFilter>() = LocationFilter {
+            position_variance: Vec3::splat(25.0),
+            velocity_variance: Vec3::splat(0.25),
+            last_fix: Some(1_000_000_000),
+            ..default()
+        };
+
+        // 4 m east and 10 m north, heading east at 2 m/s, at the time of the previous fix
+        let enu = DVec3::new(4.0, 10.0, 2.0);
+        let geodetic = LocalFrame::new(origin).enu_to_geodetic(enu);
+        let fix = LocationFix {
+            speed: Some(2.0),
+            bearing: Some(90.0),
+            ..fix_at(geodetic, 5.0)
+        };
+        world.send_event(measured(1_000_000_000, fix));
+        world.run_system(fuse).unwrap();
+
+        // horizontally 25 m² against 25 m², vertically against 1.5 times the accuracy
+        let vertical_gain = 25.0 / (25.0 + 7.5 * 7.5);
+        let states = world.resource::<StateVector>();
+        let expected = enu.as_vec3() * Vec3::new(0.5, 0.5, vertical_gain);
+        assert!(
+            states.position().abs_diff_eq(expected, 1e-3),
+            "{}",
+            states.position()
+        );
+        // 0.25 m²/s² against the 0.5 m/s speed noise, the vertical speed is left alone
+        let velocity = states.velocity();
+        assert!(
+            velocity.abs_diff_eq(Vec3::new(1.0, 0.5, 0.5), 1e-5),
+            "{velocity}"
+        );
+
+        let filter = world.resource::<LocationFilter>();
+        assert_eq!(filter.last_fix, Some(1_000_000_000));
+        let variance = 25.0 * (1.0 - vertical_gain);
+        assert!(
+            filter
+                .position_variance
+                .abs_diff_eq(Vec3::new(12.5, 12.5, variance), 1e-4)
+        );
+        assert!(
+            filter
+                .velocity_variance
+                .abs_diff_eq(Vec3::new(0.125, 0.125, 0.25), 1e-6)
+        );
+    }
+}
@@ -0,0 +1,427 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    f32::consts::FRAC_1_SQRT_2,
+    net::{SocketAddr, UdpSocket},
+};
+
+use super::sensor::SensorClock;
+#[cfg(target_os = "android")]
+use super::sensor::{SensorData, SensorDataSeries};
+use super::state::{GeodeticOrigin, StateVector};
+use crate::geodetic::enu_to_ned;
+
+/// Streams the estimate as MAVLink 2 telemetry over UDP, so ground stations such as
+/// QGroundControl or Mission Planner show the phone as a vehicle
+pub struct MavlinkPlugin;
+
+impl Plugin for MavlinkPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MavlinkOutput>()
+            .init_resource::<MavlinkConnection>()
+            .init_resource::<SensorClock>()
+            .add_systems(PostUpdate, (open_mavlink_socket, send_mavlink).chain());
+    }
+}
+
+/// Where and how often to send. Changing it reopens the socket.
+#[derive(Clone, Debug, PartialEq, Resource, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MavlinkOutput {
+    /// Ground stations listen on port 14550 by default. `None` disables the output.
+    pub target: Option<SocketAddr>,
+    pub system_id: u8,
+    pub component_id: u8,
+    /// `MAV_TYPE` announced in the heartbeat
+    pub vehicle_type: u8,
+    /// Rate of every message but the heartbeat, which is sent once a second
+    pub rate_hz: f32,
+}
+
+impl Default for MavlinkOutput {
+    fn default() -> Self {
+        Self {
+            target: None,
+            system_id: 1,
+            // MAV_COMP_ID_AUTOPILOT1
+            component_id: 1,
+            // MAV_TYPE_GENERIC
+            vehicle_type: 0,
+            rate_hz: 10.0,
+        }
+    }
+}
+
+/// A message ready to be framed: its id, the `CRC_EXTRA` seed of its definition
+/// and the payload with the fields ordered by size as on the wire
+struct MavlinkMessage {
+    id: u32,
+    crc_extra: u8,
+    payload: Vec<u8>,
+}
+
+impl MavlinkMessage {
+    const STX_V2: u8 = 0xFD;
+
+    fn new(id: u32, crc_extra: u8) -> Self {
+        Self {
+            id,
+            crc_extra,
+            payload: Vec::new(),
+        }
+    }
+
+    fn u8(mut self, value: u8) -> Self {
+        self.payload.push(value);
+        self
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn i16(mut self, value: i16) -> Self {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn i32(mut self, value: i32) -> Self {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn f32(mut self, value: f32) -> Self {
+        self.payload.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn vec3(self, value: Vec3) -> Self {
+        self.f32(value.x).f32(value.y).f32(value.z)
+    }
+
+    fn frame(&self, sequence: u8, system_id: u8, component_id: u8) -> Vec<u8> {
+        // MAVLink 2 drops the trailing zeros of a payload, but keeps at least one byte
+        let length = self
+            .payload
+            .iter()
+            .rposition(|&byte| byte != 0)
+            .map_or(1, |last| last + 1);
+
+        let mut frame = vec![
+            Self::STX_V2,
+            length as u8,
+            0, // incompatibility flags
+            0, // compatibility flags
+            sequence,
+            system_id,
+            component_id,
+        ];
+        frame.extend_from_slice(&self.id.to_le_bytes()[..3]);
+        frame.extend_from_slice(&self.payload[..length]);
+        let crc = frame[1..]
+            .iter()
+            .chain([self.crc_extra].iter())
+            .fold(0xFFFF, |crc, &byte| crc_x25(crc, byte));
+        frame.extend_from_slice(&crc.to_le_bytes());
+        frame
+    }
+}
+
+/// One step of the CRC-16/MCRF4XX checksum MAVLink calls X.25
+fn crc_x25(crc: u16, byte: u8) -> u16 {
+    let mut tmp = byte ^ (crc & 0xFF) as u8;
+    tmp ^= tmp << 4;
+    let tmp = tmp as u16;
+    (crc >> 8) ^ (tmp << 8) ^ (tmp << 3) ^ (tmp >> 4)
+}
+
+/// Rotates the east, north, up frame onto north, east, down and, identically, the Android
+/// device frame (x right, y to the top, z out of the screen) onto forward, right, down
+/// with the top of the device forward
+const ENU_TO_NED: Quat = Quat::from_xyzw(FRAC_1_SQRT_2, FRAC_1_SQRT_2, 0.0, 0.0);
+
+fn to_frd(device: Vec3) -> Vec3 {
+    Vec3::new(device.y, device.x, -device.z)
+}
+
+fn heartbeat(vehicle_type: u8) -> MavlinkMessage {
+    MavlinkMessage::new(0, 50)
+        .u32(0) // custom_mode
+        .u8(vehicle_type)
+        .u8(0) // MAV_AUTOPILOT_GENERIC
+        .u8(0) // base_mode
+        .u8(4) // MAV_STATE_ACTIVE
+        .u8(3) // mavlink_version
+}
+
+fn attitude_quaternion(time_boot_ms: u32, orientation: Quat, angular_rate: Vec3) -> MavlinkMessage {
+    let q = ENU_TO_NED * orientation * ENU_TO_NED.inverse();
+    MavlinkMessage::new(31, 246)
+        .u32(time_boot_ms)
+        .f32(q.w)
+        .f32(q.x)
+        .f32(q.y)
+        .f32(q.z)
+        .vec3(to_frd(angular_rate))
+}
+
+fn local_position_ned(time_boot_ms: u32, states: &StateVector) -> MavlinkMessage {
+    MavlinkMessage::new(32, 185)
+        .u32(time_boot_ms)
+        .vec3(enu_to_ned(states.position().as_dvec3()).as_vec3())
+        .vec3(enu_to_ned(states.velocity().as_dvec3()).as_vec3())
+}
+
+/// `None` while the local frame has no geodetic origin
+fn global_position_int(
+    time_boot_ms: u32,
+    states: &StateVector,
+    origin: &GeodeticOrigin,
+) -> Option<MavlinkMessage> {
+    let coordinates = origin.to_geodetic(states.position())?;
+    // cm/s, north, east, down
+    let velocity = (enu_to_ned(states.velocity().as_dvec3()) * 100.0)
+        .clamp_length_max(i16::MAX as f64)
+        .as_ivec3();
+
+    // the altitude should be above mean sea level, the ellipsoid is the best there is
+    Some(
+        MavlinkMessage::new(33, 104)
+            .u32(time_boot_ms)
+            .i32((coordinates.latitude * 1e7).round() as i32)
+            .i32((coordinates.longitude * 1e7).round() as i32)
+            .i32((coordinates.altitude * 1e3).round() as i32)
+            .i32((states.position().z * 1e3).round() as i32)
+            .i16(velocity.x as i16)
+            .i16(velocity.y as i16)
+            .i16(velocity.z as i16)
+            // UINT16_MAX when unknown
+            .u16(states.heading().map_or(u16::MAX, |heading| {
+                (heading * 100.0).round() as u16 % 36_000
+            })),
+    )
+}
+
+/// `None` until the accelerometer and gyroscope both reported
+#[cfg(target_os = "android")]
+fn highres_imu(time_boot_us: u64, sensor_data: &SensorData) -> Option<MavlinkMessage> {
+    let latest_vec3 = |series: &SensorDataSeries| {
+        series
+            .has_data()
+            .then(|| series.latest()?.values.vec3().copied())
+            .flatten()
+    };
+    let acceleration = latest_vec3(&sensor_data.raw_accelerometer)?;
+    let angular_rate = latest_vec3(&sensor_data.gyroscope)?;
+    let magnetic_field = latest_vec3(&sensor_data.magnetic_field);
+    let pressure = sensor_data
+        .pressure
+        .has_data()
+        .then(|| sensor_data.pressure.latest()?.values.scalar())
+        .flatten();
+
+    // bits of the fields that hold a measurement
+    let mut fields_updated = 0b111_111;
+    if magnetic_field.is_some() {
+        fields_updated |= 0b111 << 6;
+    }
+    if pressure.is_some() {
+        fields_updated |= 1 << 9;
+    }
+
+    Some(
+        MavlinkMessage::new(105, 93)
+            .u64(time_boot_us)
+            .vec3(to_frd(acceleration))
+            .vec3(to_frd(angular_rate))
+            // Android reports µT, MAVLink wants gauss
+            .vec3(to_frd(magnetic_field.unwrap_or_default()) * 0.01)
+            .f32(pressure.unwrap_or_default()) // abs_pressure in hPa
+            .f32(0.0) // diff_pressure
+            .f32(0.0) // pressure_alt
+            .f32(0.0) // temperature
+            .u16(fields_updated),
+    )
+}
+
+#[derive(Default, Resource)]
+struct MavlinkConnection {
+    socket: Option<(UdpSocket, SocketAddr)>,
+    sequence: u8,
+    /// Real time in seconds of the last heartbeat and of the last other messages
+    last_heartbeat: Option<f64>,
+    last_sent: Option<f64>,
+}
+
+impl MavlinkConnection {
+    fn send(&mut self, output: &MavlinkOutput, message: MavlinkMessage) {
+        let Some((socket, target)) = &self.socket else {
+            return;
+        };
+        let frame = message.frame(self.sequence, output.system_id, output.component_id);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        if let Err(error) = socket.send_to(&frame, target) {
+            warn!("MAVLink output failed and was closed: {}", error);
+            self.socket = None;
+        }
+    }
+}
+
+fn open_mavlink_socket(output: Res<MavlinkOutput>, mut connection: ResMut<MavlinkConnection>) {
+    if !output.is_changed() {
+        return;
+    }
+
+    connection.socket = output.target.and_then(|target| {
+        let local: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        UdpSocket::bind(local)
+            .inspect_err(|error| warn!("Could not open MAVLink output to {}: {}", target, error))
+            .ok()
+            .map(|socket| (socket, target))
+    });
+}
+
+fn send_mavlink(
+    output: Res<MavlinkOutput>,
+    mut connection: ResMut<MavlinkConnection>,
+    states: Res<StateVector>,
+    origin: Res<GeodeticOrigin>,
+    clock: Res<SensorClock>,
+    time: Res<Time<Real>>,
+    #[cfg(target_os = "android")] sensor_data: Res<SensorData>,
+) {
+    if connection.socket.is_none() {
+        return;
+    }
+    let now = time.elapsed_secs_f64();
+    // app time stands in for the time since boot
+    let app_time = clock.app_time_now();
+    let time_boot_ms = (app_time / 1_000_000) as u32;
+
+    if connection
+        .last_heartbeat
+        .is_none_or(|last| now - last >= 1.0)
+    {
+        connection.last_heartbeat = Some(now);
+        connection.send(&output, heartbeat(output.vehicle_type));
+    }
+    if connection
+        .last_sent
+        .is_some_and(|last| now - last < 1.0 / output.rate_hz as f64)
+    {
+        return;
+    }
+    connection.last_sent = Some(now);
+
+    #[cfg(target_os = "android")]
+    let angular_rate = sensor_data
+        .gyroscope
+        .has_data()
+        .then(|| sensor_data.gyroscope.latest()?.values.vec3().copied())
+        .flatten()
+        .unwrap_or_default();
+    #[cfg(not(target_os = "android"))]
+    let angular_rate = Vec3::ZERO;
+
+    connection.send(
+        &output,
+        attitude_quaternion(time_boot_ms, states.orientation(), angular_rate),
+    );
+    connection.send(&output, local_position_ned(time_boot_ms, &states));
+    if let Some(message) = global_position_int(time_boot_ms, &states, &origin) {
+        connection.send(&output, message);
+    }
+    #[cfg(target_os = "android")]
+    if let Some(message) = highres_imu((app_time / 1_000) as u64, &sensor_data) {
+        connection.send(&output, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use std::time::Duration;
+
+    #[test]
+    fn heartbeat_frame_matches_the_reference() {
+        // MAV_TYPE_QUADROTOR, sequence 7, system 1, component 1
+        let frame = heartbeat(2).frame(7, 1, 1);
+        assert_eq!(
+            frame,
+            [
+                0xFD, 9, 0, 0, 7, 1, 1, // header
+                0, 0, 0, // message id
+                0, 0, 0, 0, 2, 0, 0, 4, 3, // payload
+                0xC5, 0x78, // CRC with CRC_EXTRA 50
+            ]
+        );
+    }
+
+    #[test]
+    fn crc_matches_the_check_value() {
+        let crc = b"123456789"
+            .iter()
+            .fold(0xFFFF, |crc, &byte| crc_x25(crc, byte));
+        assert_eq!(crc, 0x6F91);
+    }
+
+    #[test]
+    fn trailing_zeros_are_truncated() {
+        let frame = MavlinkMessage::new(0, 0).u32(0).frame(0, 1, 1);
+        assert_eq!(frame[1], 1);
+        assert_eq!(frame.len(), 10 + 1 + 2);
+    }
+
+    #[test]
+    fn messages_reach_the_target() {
+        let ground_station = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        ground_station
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut world = World::new();
+        world.insert_resource(MavlinkOutput {
+            target: Some(ground_station.local_addr().unwrap()),
+            system_id: 42,
+            ..default()
+        });
+        world.init_resource::<MavlinkConnection>();
+        world.init_resource::<StateVector>();
+        world.init_resource::<GeodeticOrigin>();
+        world.init_resource::<Time<Real>>();
+        world.init_resource::<SensorClock>();
+        #[cfg(target_os = "android")]
+        world.init_resource::<SensorData>();
+        world.run_system_once(open_mavlink_socket).unwrap();
+        world.run_system_once(send_mavlink).unwrap();
+
+        // without a geodetic origin there is no GLOBAL_POSITION_INT
+        let mut buffer = [0; 280];
+        for (sequence, id) in [0, 31, 32].into_iter().enumerate() {
+            let length = ground_station.recv(&mut buffer).unwrap();
+            let frame = &buffer[..length];
+            assert_eq!(frame[0], MavlinkMessage::STX_V2);
+            assert_eq!(frame.len(), 10 + frame[1] as usize + 2);
+            assert_eq!(frame[4], sequence as u8);
+            assert_eq!(frame[5], 42);
+            assert_eq!(u32::from_le_bytes([frame[7], frame[8], frame[9], 0]), id);
+        }
+    }
+}
@@ -0,0 +1,1178 @@
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::window::AppLifecycle;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use super::sensor::{SensorClock, SensorData};
+use super::state::StateVector;
+use crate::ffi::event::{SensorAccuracy, SensorEvent, SensorType, SensorValues};
+
+/// Records sensor samples and the estimate as MCAP files with ROS 2 messages, for
+/// Foxglove and ROS tooling, and plays such recordings back
+#[derive(Default)]
+pub struct McapPlugin {
+    /// Recording to play back, see [`McapReplay`]
+    pub replay: Option<PathBuf>,
+}
+
+impl Plugin for McapPlugin {
+    fn build(&self, app: &mut App) {
+        let replay = match &self.replay {
+            Some(path) => McapReplay::from_file(path).unwrap_or_else(|error| {
+                error!("Could not load MCAP replay {}: {}", path.display(), error);
+                McapReplay::default()
+            }),
+            None => McapReplay::default(),
+        };
+
+        app.init_resource::<McapRecording>()
+            .init_resource::<McapRecorder>()
+            .init_resource::<SensorData>()
+            .init_resource::<SensorClock>()
+            .insert_resource(replay)
+            .add_systems(Update, replay_mcap)
+            .add_systems(PostUpdate, record_state)
+            .add_systems(Last, manage_recording);
+
+        #[cfg(target_os = "android")]
+        app.add_systems(PostUpdate, record_sensor_events.before(record_state));
+    }
+}
+
+/// Where recordings go. Changing it starts a new file.
+#[derive(Clone, Debug, Default, PartialEq, Resource, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct McapRecording {
+    /// Directory to write `recording-<unix time>.mcap` files to, `None` to not record
+    pub directory: Option<PathBuf>,
+}
+
+/// Frame of the device axes as Android defines them: x right, y to the top of the
+/// screen, z out of the screen
+const DEVICE_FRAME: &str = "device";
+/// The local east, north, up frame of the estimate
+const WORLD_FRAME: &str = "map";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Topic {
+    Imu,
+    MagneticField,
+    Pose,
+    Odometry,
+}
+
+impl Topic {
+    const ALL: [Topic; 4] = [
+        Topic::Imu,
+        Topic::MagneticField,
+        Topic::Pose,
+        Topic::Odometry,
+    ];
+
+    /// Also used as the schema id, each topic has a schema of its own
+    fn channel_id(&self) -> u16 {
+        *self as u16 + 1
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Topic::Imu => "/imu",
+            Topic::MagneticField => "/magnetic_field",
+            Topic::Pose => "/pose",
+            Topic::Odometry => "/odom",
+        }
+    }
+
+    fn schema_name(&self) -> &'static str {
+        match self {
+            Topic::Imu => "sensor_msgs/msg/Imu",
+            Topic::MagneticField => "sensor_msgs/msg/MagneticField",
+            Topic::Pose => "geometry_msgs/msg/PoseStamped",
+            Topic::Odometry => "nav_msgs/msg/Odometry",
+        }
+    }
+
+    fn from_schema_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|topic| topic.schema_name() == name)
+    }
+
+    /// The `ros2msg` definition: the message itself followed by every type it uses
+    fn definition(&self) -> String {
+        let (fields, dependencies): (&str, &[&str]) = match self {
+            Topic::Imu => (
+                concat!(
+                    "std_msgs/Header header\n",
+                    "geometry_msgs/Quaternion orientation\n",
+                    "float64[9] orientation_covariance\n",
+                    "geometry_msgs/Vector3 angular_velocity\n",
+                    "float64[9] angular_velocity_covariance\n",
+                    "geometry_msgs/Vector3 linear_acceleration\n",
+                    "float64[9] linear_acceleration_covariance\n",
+                ),
+                &[HEADER, TIME, QUATERNION, VECTOR3],
+            ),
+            Topic::MagneticField => (
+                concat!(
+                    "std_msgs/Header header\n",
+                    "geometry_msgs/Vector3 magnetic_field\n",
+                    "float64[9] magnetic_field_covariance\n",
+                ),
+                &[HEADER, TIME, VECTOR3],
+            ),
+            Topic::Pose => (
+                "std_msgs/Header header\ngeometry_msgs/Pose pose\n",
+                &[HEADER, TIME, POSE, POINT, QUATERNION],
+            ),
+            Topic::Odometry => (
+                concat!(
+                    "std_msgs/Header header\n",
+                    "string child_frame_id\n",
+                    "geometry_msgs/PoseWithCovariance pose\n",
+                    "geometry_msgs/TwistWithCovariance twist\n",
+                ),
+                &[
+                    HEADER,
+                    TIME,
+                    POSE_WITH_COVARIANCE,
+                    POSE,
+                    POINT,
+                    QUATERNION,
+                    TWIST_WITH_COVARIANCE,
+                    TWIST,
+                    VECTOR3,
+                ],
+            ),
+        };
+
+        let mut definition = fields.to_string();
+        for dependency in dependencies {
+            definition.push_str(&"=".repeat(80));
+            definition.push_str("\nMSG: ");
+            definition.push_str(dependency);
+        }
+        definition
+    }
+}
+
+const HEADER: &str = "std_msgs/Header\nbuiltin_interfaces/Time stamp\nstring frame_id\n";
+const TIME: &str = "builtin_interfaces/Time\nint32 sec\nuint32 nanosec\n";
+const VECTOR3: &str = "geometry_msgs/Vector3\nfloat64 x\nfloat64 y\nfloat64 z\n";
+const POINT: &str = "geometry_msgs/Point\nfloat64 x\nfloat64 y\nfloat64 z\n";
+const QUATERNION: &str = "geometry_msgs/Quaternion\nfloat64 x\nfloat64 y\nfloat64 z\nfloat64 w\n";
+const POSE: &str =
+    "geometry_msgs/Pose\ngeometry_msgs/Point position\ngeometry_msgs/Quaternion orientation\n";
+const POSE_WITH_COVARIANCE: &str =
+    "geometry_msgs/PoseWithCovariance\ngeometry_msgs/Pose pose\nfloat64[36] covariance\n";
+const TWIST: &str =
+    "geometry_msgs/Twist\ngeometry_msgs/Vector3 linear\ngeometry_msgs/Vector3 angular\n";
+const TWIST_WITH_COVARIANCE: &str =
+    "geometry_msgs/TwistWithCovariance\ngeometry_msgs/Twist twist\nfloat64[36] covariance\n";
+
+/// Serializes a message as little endian CDR, the ROS 2 wire format
+struct CdrWriter {
+    buffer: Vec<u8>,
+}
+
+impl CdrWriter {
+    /// Encapsulation header: plain CDR, little endian
+    const HEADER: [u8; 4] = [0x00, 0x01, 0x00, 0x00];
+
+    fn new() -> Self {
+        Self {
+            buffer: Self::HEADER.to_vec(),
+        }
+    }
+
+    /// Pads to a multiple of `size`, counted from the end of the encapsulation header
+    fn align(&mut self, size: usize) {
+        while (self.buffer.len() - Self::HEADER.len()) % size != 0 {
+            self.buffer.push(0);
+        }
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.align(4);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.align(4);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn f64(&mut self, value: f64) -> &mut Self {
+        self.align(8);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.u32(value.len() as u32 + 1);
+        self.buffer.extend_from_slice(value.as_bytes());
+        self.buffer.push(0);
+        self
+    }
+
+    fn vec3(&mut self, value: Vec3) -> &mut Self {
+        self.f64(value.x as f64)
+            .f64(value.y as f64)
+            .f64(value.z as f64)
+    }
+
+    fn quat(&mut self, value: Quat) -> &mut Self {
+        self.f64(value.x as f64)
+            .f64(value.y as f64)
+            .f64(value.z as f64)
+            .f64(value.w as f64)
+    }
+
+    /// A covariance matrix, all zeros ("unknown") unless `first` is set
+    fn covariance(&mut self, size: usize, first: f64) -> &mut Self {
+        self.f64(first);
+        for _ in 1..size {
+            self.f64(0.0);
+        }
+        self
+    }
+
+    /// `std_msgs/Header` stamped with nanoseconds since the Unix epoch
+    fn header(&mut self, stamp: u64, frame_id: &str) -> &mut Self {
+        self.i32((stamp / 1_000_000_000) as i32)
+            .u32((stamp % 1_000_000_000) as u32)
+            .string(frame_id)
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+/// Counterpart of [`CdrWriter`]. Only little endian data is read.
+struct CdrReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> CdrReader<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        (data.get(..2)? == &CdrWriter::HEADER[..2]).then_some(Self { data, position: 4 })
+    }
+
+    fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+        // primitives are aligned to their size, counted like in the writer
+        self.position += (size - (self.position - 4) % size) % size;
+        let bytes = self.data.get(self.position..self.position + size)?;
+        self.position += size;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        Some(f64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn skip_string(&mut self) -> Option<()> {
+        let length = self.u32()? as usize;
+        self.position += length;
+        (self.position <= self.data.len()).then_some(())
+    }
+
+    fn vec3(&mut self) -> Option<Vec3> {
+        Some(Vec3::new(
+            self.f64()? as f32,
+            self.f64()? as f32,
+            self.f64()? as f32,
+        ))
+    }
+
+    fn quat(&mut self) -> Option<Quat> {
+        Some(Quat::from_xyzw(
+            self.f64()? as f32,
+            self.f64()? as f32,
+            self.f64()? as f32,
+            self.f64()? as f32,
+        ))
+    }
+
+    fn skip_covariance(&mut self, size: usize) -> Option<f64> {
+        let first = self.f64()?;
+        for _ in 1..size {
+            self.f64()?;
+        }
+        Some(first)
+    }
+
+    /// The stamp of a `std_msgs/Header` in nanoseconds
+    fn header(&mut self) -> Option<i64> {
+        let stamp = self.i32()? as i64 * 1_000_000_000 + self.u32()? as i64;
+        self.skip_string()?;
+        Some(stamp)
+    }
+}
+
+/// `orientation` is `None` when unknown, which ROS marks with a covariance of -1
+fn imu_message(
+    stamp: u64,
+    orientation: Option<Quat>,
+    angular_velocity: Vec3,
+    linear_acceleration: Vec3,
+) -> Vec<u8> {
+    CdrWriter::new()
+        .header(stamp, DEVICE_FRAME)
+        .quat(orientation.unwrap_or(Quat::IDENTITY))
+        .covariance(9, if orientation.is_some() { 0.0 } else { -1.0 })
+        .vec3(angular_velocity)
+        .covariance(9, 0.0)
+        .vec3(linear_acceleration)
+        .covariance(9, 0.0)
+        .finish()
+}
+
+fn magnetic_field_message(stamp: u64, micro_tesla: Vec3) -> Vec<u8> {
+    CdrWriter::new()
+        .header(stamp, DEVICE_FRAME)
+        .vec3(micro_tesla * 1e-6)
+        .covariance(9, 0.0)
+        .finish()
+}
+
+fn pose_message(stamp: u64, states: &StateVector) -> Vec<u8> {
+    CdrWriter::new()
+        .header(stamp, WORLD_FRAME)
+        .vec3(states.position())
+        .quat(states.orientation())
+        .finish()
+}
+
+/// The twist is in the device frame, as ROS expects
+fn odometry_message(stamp: u64, states: &StateVector) -> Vec<u8> {
+    CdrWriter::new()
+        .header(stamp, WORLD_FRAME)
+        .string(DEVICE_FRAME)
+        .vec3(states.position())
+        .quat(states.orientation())
+        .covariance(36, 0.0)
+        .vec3(states.orientation().inverse() * states.velocity())
+        .vec3(Vec3::ZERO)
+        .covariance(36, 0.0)
+        .finish()
+}
+
+/// Content of an MCAP record
+#[derive(Default)]
+struct McapRecord(Vec<u8>);
+
+impl McapRecord {
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Strings, byte arrays and maps are all prefixed with their length in bytes
+    fn bytes(self, value: &[u8]) -> Self {
+        let mut record = self.u32(value.len() as u32);
+        record.0.extend_from_slice(value);
+        record
+    }
+
+    fn string(self, value: &str) -> Self {
+        self.bytes(value.as_bytes())
+    }
+}
+
+const MCAP_MAGIC: &[u8; 8] = b"\x89MCAP0\r\n";
+
+mod opcode {
+    pub const HEADER: u8 = 0x01;
+    pub const FOOTER: u8 = 0x02;
+    pub const SCHEMA: u8 = 0x03;
+    pub const CHANNEL: u8 = 0x04;
+    pub const MESSAGE: u8 = 0x05;
+    pub const CHUNK: u8 = 0x06;
+    pub const DATA_END: u8 = 0x0F;
+}
+
+/// Streams records to an unchunked, unindexed MCAP file, which is still complete
+/// and readable if the app is killed before [`McapWriter::finish`]
+struct McapWriter {
+    file: BufWriter<File>,
+    sequence: u32,
+}
+
+impl McapWriter {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            sequence: 0,
+        };
+
+        writer.file.write_all(MCAP_MAGIC)?;
+        writer.record(
+            opcode::HEADER,
+            McapRecord::default()
+                .string("ros2")
+                .string("android-position-estimator"),
+        )?;
+        for topic in Topic::ALL {
+            writer.record(
+                opcode::SCHEMA,
+                McapRecord::default()
+                    .u16(topic.channel_id())
+                    .string(topic.schema_name())
+                    .string("ros2msg")
+                    .bytes(topic.definition().as_bytes()),
+            )?;
+            writer.record(
+                opcode::CHANNEL,
+                McapRecord::default()
+                    .u16(topic.channel_id())
+                    .u16(topic.channel_id())
+                    .string(topic.name())
+                    .string("cdr")
+                    .bytes(&[]),
+            )?;
+        }
+        Ok(writer)
+    }
+
+    fn record(&mut self, opcode: u8, record: McapRecord) -> io::Result<()> {
+        self.file.write_all(&[opcode])?;
+        self.file
+            .write_all(&(record.0.len() as u64).to_le_bytes())?;
+        self.file.write_all(&record.0)
+    }
+
+    fn message(&mut self, topic: Topic, stamp: u64, data: &[u8]) -> io::Result<()> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let mut record = McapRecord::default()
+            .u16(topic.channel_id())
+            .u32(self.sequence)
+            .u64(stamp)
+            .u64(stamp);
+        record.0.extend_from_slice(data);
+        self.record(opcode::MESSAGE, record)
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        // no CRCs and no summary section, both are optional
+        self.record(opcode::DATA_END, McapRecord::default().u32(0))?;
+        self.record(opcode::FOOTER, McapRecord::default().u64(0).u64(0).u32(0))?;
+        self.file.write_all(MCAP_MAGIC)?;
+        self.file.flush()
+    }
+}
+
+#[derive(Default, Resource)]
+struct McapRecorder {
+    writer: Option<McapWriter>,
+    /// Timestamps of the last recorded gyroscope and magnetometer samples
+    #[cfg(target_os = "android")]
+    cursors: (Option<i64>, Option<i64>),
+}
+
+impl McapRecorder {
+    fn write(&mut self, topic: Topic, stamp: u64, data: &[u8]) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        if let Err(error) = writer.message(topic, stamp, data) {
+            warn!("MCAP recording failed and was stopped: {}", error);
+            self.writer = None;
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(writer) = self.writer.take()
+            && let Err(error) = writer.finish()
+        {
+            warn!("Could not finish MCAP recording: {}", error);
+        }
+    }
+}
+
+/// Unix time in nanoseconds of an app time, which stamps messages and logs them
+fn stamp(clock: &SensorClock, app_time: i64) -> u64 {
+    clock.to_unix_time(app_time).max(0) as u64
+}
+
+/// Starts a file when recording is configured or the app comes back to the foreground,
+/// and finishes it when the app is suspended or exits
+fn manage_recording(
+    mut lifecycle_events: EventReader<AppLifecycle>,
+    mut exit_events: EventReader<AppExit>,
+    recording: Res<McapRecording>,
+    mut recorder: ResMut<McapRecorder>,
+    clock: Res<SensorClock>,
+) {
+    let mut resumed = false;
+    for event in lifecycle_events.read() {
+        match event {
+            AppLifecycle::Suspended => recorder.stop(),
+            AppLifecycle::Running => resumed = true,
+            _ => (),
+        }
+    }
+    if exit_events.read().count() > 0 {
+        recorder.stop();
+        return;
+    }
+    if recording.is_changed() {
+        recorder.stop();
+    } else if !resumed || recorder.writer.is_some() {
+        return;
+    }
+
+    let Some(directory) = &recording.directory else {
+        return;
+    };
+    let now = clock.to_unix_time(clock.app_time_now()) / 1_000_000_000;
+    let path = directory.join(format!("recording-{}.mcap", now));
+    match McapWriter::create(&path) {
+        Ok(writer) => {
+            info!("Recording to {}", path.display());
+            recorder.writer = Some(writer);
+            #[cfg(target_os = "android")]
+            {
+                recorder.cursors = (None, None);
+            }
+        }
+        Err(error) => warn!("Could not record to {}: {}", path.display(), error),
+    }
+}
+
+fn record_state(
+    states: Res<StateVector>,
+    mut recorder: ResMut<McapRecorder>,
+    clock: Res<SensorClock>,
+) {
+    // a replayed pose counts as aligned, so the device's starting frame is not recorded
+    if recorder.writer.is_none() || !states.is_changed() || !states.is_aligned() {
+        return;
+    }
+
+    let stamp = stamp(&clock, clock.app_time_now());
+    recorder.write(Topic::Pose, stamp, &pose_message(stamp, &states));
+    recorder.write(Topic::Odometry, stamp, &odometry_message(stamp, &states));
+}
+
+/// Writes an IMU message per new gyroscope sample and a magnetic field message per
+/// new magnetometer sample
+#[cfg(target_os = "android")]
+fn record_sensor_events(
+    sensor_data: Res<SensorData>,
+    clock: Res<SensorClock>,
+    mut recorder: ResMut<McapRecorder>,
+) {
+    let recorder = &mut *recorder;
+    if recorder.writer.is_none() {
+        return;
+    }
+    let (gyroscope_cursor, magnetic_field_cursor) = recorder.cursors;
+    let mut messages = Vec::new();
+
+    for event in sensor_data
+        .gyroscope
+        .since(gyroscope_cursor.unwrap_or(i64::MIN))
+        .filter(|event| !matches!(event.sensor_type, SensorType::Unavailable))
+    {
+        recorder.cursors.0 = Some(event.timestamp);
+        let (Some(app_time), Some(angular_velocity), Some(acceleration)) = (
+            clock.to_app_time(event.timestamp),
+            event.values.vec3(),
+            sensor_data.raw_accelerometer.vec3_at(event.timestamp),
+        ) else {
+            continue;
+        };
+        let orientation = sensor_data
+            .rotation
+            .sample_at(event.timestamp)
+            .and_then(|values| values.quat().copied());
+        let stamp = stamp(&clock, app_time);
+        messages.push((
+            Topic::Imu,
+            stamp,
+            imu_message(stamp, orientation, *angular_velocity, acceleration),
+        ));
+    }
+
+    for event in sensor_data
+        .magnetic_field
+        .since(magnetic_field_cursor.unwrap_or(i64::MIN))
+        .filter(|event| !matches!(event.sensor_type, SensorType::Unavailable))
+    {
+        recorder.cursors.1 = Some(event.timestamp);
+        let (Some(app_time), Some(field)) =
+            (clock.to_app_time(event.timestamp), event.values.vec3())
+        else {
+            continue;
+        };
+        let stamp = stamp(&clock, app_time);
+        messages.push((
+            Topic::MagneticField,
+            stamp,
+            magnetic_field_message(stamp, *field),
+        ));
+    }
+
+    messages.sort_by_key(|&(_, stamp, _)| stamp);
+    for (topic, stamp, data) in messages {
+        recorder.write(topic, stamp, &data);
+    }
+}
+
+/// Messages of an MCAP file with the topics written above, played back in real time.
+/// Uncompressed chunks are read, compressed ones are skipped.
+#[derive(Debug, Default, Resource)]
+pub struct McapReplay {
+    /// Log time in nanoseconds, schema and CDR data of each message
+    messages: VecDeque<(u64, Topic, Vec<u8>)>,
+    /// Real time minus log time in seconds, fixed when the first message is played
+    offset: Option<f64>,
+}
+
+impl McapReplay {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        if !bytes.starts_with(MCAP_MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an MCAP file",
+            ));
+        }
+
+        let mut replay = Self::default();
+        replay.read_records(
+            &bytes[MCAP_MAGIC.len()..],
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        );
+        replay
+            .messages
+            .make_contiguous()
+            .sort_by_key(|&(log_time, _, _)| log_time);
+        info!("Loaded {} MCAP messages", replay.messages.len());
+        Ok(replay)
+    }
+
+    /// Reads records until the footer or the end of `bytes`, a truncated record ends the
+    /// file like a footer would. Only messages of known schemas are kept.
+    fn read_records(
+        &mut self,
+        mut bytes: &[u8],
+        schemas: &mut HashMap<u16, Topic>,
+        channels: &mut HashMap<u16, Topic>,
+    ) {
+        fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+            Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+        }
+        fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+            Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+        }
+        fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+            Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+        }
+        fn string_at(bytes: &[u8], at: usize) -> Option<(&str, usize)> {
+            let length = u32_at(bytes, at)? as usize;
+            let string = std::str::from_utf8(bytes.get(at + 4..at + 4 + length)?).ok()?;
+            Some((string, at + 4 + length))
+        }
+
+        while let (Some(&opcode), Some(length)) = (bytes.first(), u64_at(bytes, 1)) {
+            let Some(content) = bytes.get(9..9 + length as usize) else {
+                return;
+            };
+            bytes = &bytes[9 + length as usize..];
+
+            match opcode {
+                opcode::SCHEMA => {
+                    if let (Some(id), Some((name, _))) = (u16_at(content, 0), string_at(content, 2))
+                        && let Some(topic) = Topic::from_schema_name(name)
+                    {
+                        schemas.insert(id, topic);
+                    }
+                }
+                opcode::CHANNEL => {
+                    if let (Some(id), Some(schema_id)) = (u16_at(content, 0), u16_at(content, 2))
+                        && let Some(&topic) = schemas.get(&schema_id)
+                    {
+                        channels.insert(id, topic);
+                    }
+                }
+                opcode::MESSAGE => {
+                    if let (Some(channel_id), Some(log_time), Some(data)) =
+                        (u16_at(content, 0), u64_at(content, 6), content.get(22..))
+                        && let Some(&topic) = channels.get(&channel_id)
+                    {
+                        self.messages.push_back((log_time, topic, data.to_vec()));
+                    }
+                }
+                opcode::CHUNK => {
+                    // start and end time, uncompressed size and CRC, then the compression
+                    if let Some((compression, records_at)) = string_at(content, 28)
+                        && compression.is_empty()
+                        && let Some(records_length) = u64_at(content, records_at)
+                        && let Some(records) =
+                            content.get(records_at + 8..records_at + 8 + records_length as usize)
+                    {
+                        self.read_records(records, schemas, channels);
+                    }
+                }
+                opcode::FOOTER => return,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// `SensorManager.STANDARD_GRAVITY`, in m/s²
+const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// Turns the recorded messages back into samples in [`SensorData`] and applies the
+/// recorded estimate to the [`StateVector`] directly.
+///
+/// An IMU message becomes gyroscope and raw accelerometer samples. With a known
+/// orientation it also gives the rotation vector, and gravity along that orientation,
+/// whose difference to the raw acceleration is the linear acceleration the estimator
+/// integrates. Without one, a replay only turns the estimate.
+fn replay_mcap(
+    time: Res<Time<Real>>,
+    mut replay: ResMut<McapReplay>,
+    mut states: ResMut<StateVector>,
+    mut sensor_data: ResMut<SensorData>,
+) {
+    let Some(&(first, _, _)) = replay.messages.front() else {
+        return;
+    };
+    let now = time.elapsed_secs_f64();
+    let offset = *replay.offset.get_or_insert(now - first as f64 * 1e-9);
+
+    while let Some((log_time, _, _)) = replay.messages.front() {
+        if *log_time as f64 * 1e-9 + offset > now {
+            break;
+        }
+        let (_, topic, data) = replay.messages.pop_front().unwrap();
+        let Some(mut reader) = CdrReader::new(&data) else {
+            continue;
+        };
+
+        let mut sample = |sensor_type, timestamp, values| {
+            sensor_data.add_event(SensorEvent {
+                accuracy: SensorAccuracy::Unknown,
+                sensor_type,
+                timestamp,
+                values,
+            });
+        };
+        match topic {
+            Topic::Imu => {
+                let Some(timestamp) = reader.header() else {
+                    continue;
+                };
+                let (Some(orientation), Some(orientation_covariance)) =
+                    (reader.quat(), reader.skip_covariance(9))
+                else {
+                    continue;
+                };
+                let (Some(angular_velocity), Some(_), Some(acceleration)) =
+                    (reader.vec3(), reader.skip_covariance(9), reader.vec3())
+                else {
+                    continue;
+                };
+
+                sample(
+                    SensorType::Gyroscope,
+                    timestamp,
+                    SensorValues::Vec3(angular_velocity),
+                );
+                sample(
+                    SensorType::RawAccelerometer,
+                    timestamp,
+                    SensorValues::Vec3(acceleration),
+                );
+                if orientation_covariance >= 0.0 {
+                    sample(
+                        SensorType::Rotation,
+                        timestamp,
+                        SensorValues::Quat(orientation),
+                    );
+                    // ROS accelerations include gravity, the estimator integrates them without
+                    let gravity = orientation.inverse() * Vec3::Z * STANDARD_GRAVITY;
+                    sample(SensorType::Gravity, timestamp, SensorValues::Vec3(gravity));
+                    sample(
+                        SensorType::Accelerometer,
+                        timestamp,
+                        SensorValues::Vec3(acceleration - gravity),
+                    );
+                }
+            }
+            Topic::MagneticField => {
+                if let (Some(timestamp), Some(field)) = (reader.header(), reader.vec3()) {
+                    sample(
+                        SensorType::MagneticField,
+                        timestamp,
+                        SensorValues::Vec3(field * 1e6),
+                    );
+                }
+            }
+            Topic::Pose => {
+                if let (Some(_), Some(position), Some(orientation)) =
+                    (reader.header(), reader.vec3(), reader.quat())
+                {
+                    let velocity = states.velocity();
+                    states.set_pose(position, velocity, orientation);
+                }
+            }
+            Topic::Odometry => {
+                if let (Some(_), Some(()), Some(position), Some(orientation)) = (
+                    reader.header(),
+                    reader.skip_string(),
+                    reader.vec3(),
+                    reader.quat(),
+                ) && let (Some(_), Some(velocity)) = (reader.skip_covariance(36), reader.vec3())
+                {
+                    states.set_pose(position, orientation * velocity, orientation);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::state::StatePlugin;
+    use std::time::Duration;
+
+    /// A file in the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("{}-{}.mcap", name, std::process::id())))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    const START: u64 = 1_700_000_000_000_000_000; // nanoseconds since the Unix epoch
+
+    fn record(path: &Path, messages: &[(Topic, u64, Vec<u8>)]) {
+        let mut writer = McapWriter::create(path).unwrap();
+        for (topic, stamp, data) in messages {
+            writer.message(*topic, *stamp, data).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    /// Replays `path` into the estimator, with real time under the test's control.
+    /// Low-pass filtering is off, so samples keep their recorded values.
+    fn replay_app(path: &Path) -> App {
+        let mut time = Time::<Real>::default();
+        time.update_with_instant(time.startup());
+        let mut app = App::new();
+        app.insert_resource(time)
+            .add_event::<AppLifecycle>()
+            .add_plugins((
+                StatePlugin,
+                McapPlugin {
+                    replay: Some(path.to_path_buf()),
+                },
+            ));
+        app.world_mut()
+            .resource_mut::<SensorData>()
+            .set_low_pass(f32::INFINITY, |_| 50.0);
+        app
+    }
+
+    /// Lets `seconds` of real time pass and runs a frame
+    fn advance(app: &mut App, seconds: f64) {
+        let mut time = app.world_mut().resource_mut::<Time<Real>>();
+        let now = time.last_update().unwrap() + Duration::from_secs_f64(seconds);
+        time.update_with_instant(now);
+        app.update();
+    }
+
+    #[test]
+    fn replayed_acceleration_moves_the_estimate() {
+        // lying flat with the top to the north, accelerating north at 1 m/s² for a second
+        let messages = (0..=100)
+            .map(|step| {
+                let stamp = START + step * 10_000_000;
+                let acceleration = Vec3::new(0.0, 1.0, STANDARD_GRAVITY);
+                let data = imu_message(stamp, Some(Quat::IDENTITY), Vec3::ZERO, acceleration);
+                (Topic::Imu, stamp, data)
+            })
+            .collect::<Vec<_>>();
+        let file = TempFile::new("replayed-acceleration");
+        record(&file.0, &messages);
+
+        let mut app = replay_app(&file.0);
+        for _ in 0..messages.len() {
+            advance(&mut app, 0.01);
+        }
+
+        let states = app.world().resource::<StateVector>();
+        assert!(states.is_aligned());
+        let velocity = states.velocity();
+        assert!((velocity.y - 1.0).abs() < 0.02, "{velocity}");
+        assert!(
+            velocity.x.abs() < 1e-3 && velocity.z.abs() < 1e-3,
+            "{velocity}"
+        );
+        let position = states.position();
+        assert!((position.y - 0.5).abs() < 0.02, "{position}");
+    }
+
+    #[test]
+    fn recorded_messages_read_back() {
+        const MS: u64 = 1_000_000;
+        let turned = Quat::from_rotation_z(0.5);
+        let linear_acceleration = Vec3::new(0.5, -0.25, 0.0);
+        let acceleration = turned.inverse() * Vec3::Z * STANDARD_GRAVITY + linear_acceleration;
+        let mut resting = StateVector::default();
+        resting.set_pose(Vec3::new(1.0, 2.0, 3.0), Vec3::ZERO, turned);
+        let tilted = Quat::from_rotation_x(0.3) * turned;
+        let mut moving = StateVector::default();
+        moving.set_pose(Vec3::new(4.0, 5.0, 6.0), Vec3::new(1.0, 2.0, 0.5), tilted);
+
+        let messages = [
+            (
+                Topic::Imu,
+                START,
+                imu_message(START, Some(turned), Vec3::new(0.1, 0.2, 0.3), acceleration),
+            ),
+            (
+                Topic::MagneticField,
+                START + 10 * MS,
+                magnetic_field_message(START + 10 * MS, Vec3::new(20.0, -5.0, -40.0)),
+            ),
+            (
+                Topic::Pose,
+                START + 20 * MS,
+                pose_message(START + 20 * MS, &resting),
+            ),
+            (
+                Topic::Odometry,
+                START + 30 * MS,
+                odometry_message(START + 30 * MS, &moving),
+            ),
+        ];
+
+        // ROS wants tesla and a twist in the device frame
+        let mut reader = CdrReader::new(&messages[1].2).unwrap();
+        assert_eq!(reader.header(), Some((START + 10 * MS) as i64));
+        let field = reader.vec3().unwrap();
+        assert!(
+            field.abs_diff_eq(Vec3::new(20e-6, -5e-6, -40e-6), 1e-12),
+            "{field}"
+        );
+        let mut reader = CdrReader::new(&messages[3].2).unwrap();
+        reader.header().unwrap();
+        reader.skip_string().unwrap();
+        reader.vec3().unwrap();
+        reader.quat().unwrap();
+        reader.skip_covariance(36).unwrap();
+        let twist = reader.vec3().unwrap();
+        let expected = tilted.inverse() * Vec3::new(1.0, 2.0, 0.5);
+        assert!(twist.abs_diff_eq(expected, 1e-6), "{twist}");
+
+        let file = TempFile::new("recorded-messages");
+        record(&file.0, &messages);
+        let replay = McapReplay::from_file(&file.0).unwrap();
+        let read = replay
+            .messages
+            .iter()
+            .map(|(stamp, topic, data)| (*topic, *stamp, data.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(read, messages);
+
+        // the first frame plays the first message, the others follow in real time
+        let mut app = replay_app(&file.0);
+        advance(&mut app, 0.0);
+        advance(&mut app, 0.015);
+        let sensor_data = app.world().resource::<SensorData>();
+        let timestamp = START as i64;
+        let vec3_at = |sensor_type, timestamp| {
+            sensor_data
+                .series(sensor_type)
+                .unwrap()
+                .vec3_at(timestamp)
+                .unwrap()
+        };
+        let gyroscope = vec3_at(SensorType::Gyroscope, timestamp);
+        assert!(
+            gyroscope.abs_diff_eq(Vec3::new(0.1, 0.2, 0.3), 1e-6),
+            "{gyroscope}"
+        );
+        let raw = vec3_at(SensorType::RawAccelerometer, timestamp);
+        assert!(raw.abs_diff_eq(acceleration, 1e-5), "{raw}");
+        let linear = vec3_at(SensorType::Accelerometer, timestamp);
+        assert!(linear.abs_diff_eq(linear_acceleration, 1e-5), "{linear}");
+        let rotation = sensor_data.rotation.quat_at(timestamp).unwrap();
+        assert!(rotation.abs_diff_eq(turned, 1e-6), "{rotation}");
+        let field = vec3_at(SensorType::MagneticField, (START + 10 * MS) as i64);
+        assert!(
+            field.abs_diff_eq(Vec3::new(20.0, -5.0, -40.0), 1e-4),
+            "{field}"
+        );
+
+        advance(&mut app, 0.01);
+        let states = app.world().resource::<StateVector>();
+        assert!(
+            states
+                .position()
+                .abs_diff_eq(Vec3::new(1.0, 2.0, 3.0), 1e-6)
+        );
+        assert!(states.orientation().abs_diff_eq(turned, 1e-6));
+        assert_eq!(states.velocity(), Vec3::ZERO);
+
+        advance(&mut app, 0.01);
+        let states = app.world().resource::<StateVector>();
+        assert!(
+            states
+                .position()
+                .abs_diff_eq(Vec3::new(4.0, 5.0, 6.0), 1e-6)
+        );
+        assert!(states.orientation().abs_diff_eq(tilted, 1e-6));
+        let velocity = states.velocity();
+        assert!(
+            velocity.abs_diff_eq(Vec3::new(1.0, 2.0, 0.5), 1e-5),
+            "{velocity}"
+        );
+    }
+
+    fn record_bytes(opcode: u8, record: McapRecord) -> Vec<u8> {
+        let mut bytes = vec![opcode];
+        bytes.extend_from_slice(&(record.0.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&record.0);
+        bytes
+    }
+
+    fn message_bytes(channel_id: u16, stamp: u64, data: &[u8]) -> Vec<u8> {
+        let mut record = McapRecord::default()
+            .u16(channel_id)
+            .u32(0)
+            .u64(stamp)
+            .u64(stamp);
+        record.0.extend_from_slice(data);
+        record_bytes(opcode::MESSAGE, record)
+    }
+
+    fn chunk_bytes(compression: &str, records: &[u8]) -> Vec<u8> {
+        let mut record = McapRecord::default()
+            .u64(START)
+            .u64(START)
+            .u64(records.len() as u64)
+            .u32(0)
+            .string(compression)
+            .u64(records.len() as u64);
+        record.0.extend_from_slice(records);
+        record_bytes(opcode::CHUNK, record)
+    }
+
+    #[test]
+    fn uncompressed_chunks_are_read() {
+        const MS: u64 = 1_000_000;
+        let field = |stamp, micro_tesla| magnetic_field_message(stamp, micro_tesla);
+        let topic = Topic::MagneticField;
+
+        let mut records = record_bytes(
+            opcode::SCHEMA,
+            McapRecord::default()
+                .u16(7)
+                .string(topic.schema_name())
+                .string("ros2msg")
+                .bytes(topic.definition().as_bytes()),
+        );
+        records.extend(record_bytes(
+            opcode::CHANNEL,
+            McapRecord::default()
+                .u16(3)
+                .u16(7)
+                .string(topic.name())
+                .string("cdr")
+                .bytes(&[]),
+        ));
+        records.extend(message_bytes(
+            3,
+            START + 2 * MS,
+            &field(START + 2 * MS, Vec3::X),
+        ));
+        // only readable once its channel is known, and skipped when compressed
+        let late = message_bytes(3, START, &field(START, Vec3::Y));
+        let compressed = message_bytes(3, START + MS, &field(START + MS, Vec3::Z));
+        let unknown = message_bytes(4, START + MS, &field(START + MS, Vec3::NEG_X));
+
+        let mut bytes = MCAP_MAGIC.to_vec();
+        bytes.extend(record_bytes(
+            opcode::HEADER,
+            McapRecord::default().string("ros2").string("test"),
+        ));
+        bytes.extend(chunk_bytes("", &records));
+        bytes.extend(chunk_bytes("zstd", &compressed));
+        bytes.extend(late);
+        bytes.extend(unknown);
+        bytes.extend(record_bytes(opcode::DATA_END, McapRecord::default().u32(0)));
+        bytes.extend(record_bytes(
+            opcode::FOOTER,
+            McapRecord::default().u64(0).u64(0).u32(0),
+        ));
+        bytes.extend_from_slice(MCAP_MAGIC);
+        let file = TempFile::new("uncompressed-chunks");
+        fs::write(&file.0, bytes).unwrap();
+
+        let replay = McapReplay::from_file(&file.0).unwrap();
+        let stamps = replay
+            .messages
+            .iter()
+            .map(|&(stamp, topic, _)| (stamp, topic))
+            .collect::<Vec<_>>();
+        assert_eq!(stamps, [(START, topic), (START + 2 * MS, topic)]);
+
+        let mut app = replay_app(&file.0);
+        advance(&mut app, 0.0);
+        advance(&mut app, 0.01);
+        let series = &app.world().resource::<SensorData>().magnetic_field;
+        let samples = series
+            .iter()
+            .filter(|event| !matches!(event.sensor_type, SensorType::Unavailable))
+            .map(|event| (event.timestamp, *event.values.vec3().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].0, START as i64);
+        assert!(samples[0].1.abs_diff_eq(Vec3::Y, 1e-5));
+        assert_eq!(samples[1].0, (START + 2 * MS) as i64);
+        assert!(samples[1].1.abs_diff_eq(Vec3::X, 1e-5));
+    }
+}
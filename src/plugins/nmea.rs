@@ -0,0 +1,492 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+};
+
+use super::sensor::SensorClock;
+use super::state::{GeodeticOrigin, StateVector};
+use super::trajectory::utc_date_time;
+use crate::geodetic::Geodetic;
+
+/// Publishes the estimate as NMEA 0183 sentences, so the phone can stand in for a
+/// dead-reckoning GPS receiver in mapping software and loggers
+pub struct NmeaPlugin;
+
+impl Plugin for NmeaPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NmeaOutput>()
+            .init_resource::<NmeaConnections>()
+            .init_resource::<SensorClock>()
+            .add_systems(PostUpdate, (open_nmea_sinks, send_nmea).chain());
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum NmeaSentence {
+    /// Time, position and fix quality
+    Gga,
+    /// Recommended minimum: time, date, position, speed and course
+    Rmc,
+    /// Course and speed over ground
+    Vtg,
+    /// True heading of the device
+    Hdt,
+}
+
+impl NmeaSentence {
+    pub const ALL: [NmeaSentence; 4] = [
+        NmeaSentence::Gga,
+        NmeaSentence::Rmc,
+        NmeaSentence::Vtg,
+        NmeaSentence::Hdt,
+    ];
+
+    const TALKER: &'static str = "GP";
+
+    /// The complete sentence including checksum and line ending, or `None` for a
+    /// sentence with coordinates when the geodetic origin is unknown and for HDT
+    /// before the heading is
+    pub fn format(&self, fix: &NmeaFix) -> Option<String> {
+        let (_, _, _, millis_of_day) = utc_date_time(fix.timestamp);
+        let centis_of_day = millis_of_day / 10;
+        let time = format!(
+            "{:02}{:02}{:02}.{:02}",
+            centis_of_day / 360_000,
+            centis_of_day / 6_000 % 60,
+            centis_of_day / 100 % 60,
+            centis_of_day % 100
+        );
+        let speed = fix.velocity.truncate().length();
+        let course = compass_degrees(fix.velocity.x, fix.velocity.y);
+        // NMEA 2.3 mode indicator, E for estimated (dead reckoning)
+        let mode = if fix.valid { 'E' } else { 'N' };
+
+        let mut body = String::from(Self::TALKER);
+        match self {
+            NmeaSentence::Gga => {
+                let coordinates = fix.coordinates?;
+                // quality 6 is "estimated (dead reckoning)". The altitude is above the
+                // ellipsoid, so the geoid separation is reported as zero.
+                write!(
+                    body,
+                    "GGA,{},{},{},{},00,,{:.1},M,0.0,M,,",
+                    time,
+                    latitude(coordinates.latitude),
+                    longitude(coordinates.longitude),
+                    if fix.valid { 6 } else { 0 },
+                    coordinates.altitude
+                )
+            }
+            NmeaSentence::Rmc => {
+                let coordinates = fix.coordinates?;
+                let (year, month, day, _) = utc_date_time(fix.timestamp);
+                write!(
+                    body,
+                    "RMC,{},{},{},{},{:.1},{:.1},{:02}{:02}{:02},,,{}",
+                    time,
+                    if fix.valid { 'A' } else { 'V' },
+                    latitude(coordinates.latitude),
+                    longitude(coordinates.longitude),
+                    speed * KNOTS_PER_METRE_PER_SECOND,
+                    course,
+                    day,
+                    month,
+                    year.rem_euclid(100),
+                    mode
+                )
+            }
+            NmeaSentence::Vtg => write!(
+                body,
+                "VTG,{:.1},T,,M,{:.1},N,{:.1},K,{}",
+                course,
+                speed * KNOTS_PER_METRE_PER_SECOND,
+                speed * 3.6,
+                mode
+            ),
+            NmeaSentence::Hdt => write!(body, "HDT,{:.1},T", fix.heading?),
+        }
+        .unwrap();
+
+        Some(format!("${}*{:02X}\r\n", body, checksum(&body)))
+    }
+}
+
+const KNOTS_PER_METRE_PER_SECOND: f32 = 3600.0 / 1852.0;
+
+/// XOR of all characters between `$` and `*`
+pub fn checksum(body: &str) -> u8 {
+    body.bytes().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+/// Degrees clockwise from north of an east, north direction, in `0..360`
+fn compass_degrees(east: f32, north: f32) -> f32 {
+    east.atan2(north).to_degrees().rem_euclid(360.0)
+}
+
+fn latitude(degrees: f64) -> String {
+    angle(degrees, 2, 'N', 'S')
+}
+
+fn longitude(degrees: f64) -> String {
+    angle(degrees, 3, 'E', 'W')
+}
+
+/// `ddmm.mmmmm` with `degree_digits` degree digits, a comma and the hemisphere
+fn angle(degrees: f64, degree_digits: usize, positive: char, negative: char) -> String {
+    // counted in whole 1e-5 minutes, so rounding can never produce 60 minutes
+    const UNITS_PER_MINUTE: u64 = 100_000;
+    let units = (degrees.abs() * 60.0 * UNITS_PER_MINUTE as f64).round() as u64;
+    let minutes = units % (60 * UNITS_PER_MINUTE);
+
+    format!(
+        "{:0width$}{:02}.{:05},{}",
+        units / (60 * UNITS_PER_MINUTE),
+        minutes / UNITS_PER_MINUTE,
+        minutes % UNITS_PER_MINUTE,
+        if degrees < 0.0 { negative } else { positive },
+        width = degree_digits
+    )
+}
+
+/// What the sentences report
+#[derive(Clone, Copy, Debug)]
+pub struct NmeaFix {
+    /// Wall clock time in seconds since the Unix epoch
+    pub timestamp: f64,
+    /// `None` while the local frame has no geodetic origin
+    pub coordinates: Option<Geodetic>,
+    /// East, north, up in m/s
+    pub velocity: Vec3,
+    /// Degrees clockwise from north the top of the device points to, `None` until the
+    /// orientation is north-referenced
+    pub heading: Option<f32>,
+    pub valid: bool,
+}
+
+impl NmeaFix {
+    pub fn new(timestamp: f64, states: &StateVector, origin: &GeodeticOrigin) -> Self {
+        Self {
+            timestamp,
+            coordinates: origin.to_geodetic(states.position()),
+            velocity: states.velocity(),
+            heading: states.heading(),
+            valid: states.is_valid(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NmeaSink {
+    /// Appends to a file
+    File(PathBuf),
+    /// Accepts TCP clients on the address and sends to all of them
+    TcpServer(SocketAddr),
+    /// Sends a datagram per update to the address
+    Udp(SocketAddr),
+}
+
+/// Where, what and how often to publish. Changing it reopens all sinks.
+#[derive(Clone, Debug, PartialEq, Resource, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NmeaOutput {
+    pub sinks: Vec<NmeaSink>,
+    pub sentences: Vec<NmeaSentence>,
+    pub rate_hz: f32,
+}
+
+impl Default for NmeaOutput {
+    fn default() -> Self {
+        Self {
+            sinks: Vec::new(),
+            sentences: NmeaSentence::ALL.to_vec(),
+            rate_hz: 1.0,
+        }
+    }
+}
+
+enum NmeaWriter {
+    File(File),
+    TcpServer {
+        listener: TcpListener,
+        clients: Vec<TcpClient>,
+    },
+    Udp {
+        socket: UdpSocket,
+        target: SocketAddr,
+    },
+}
+
+impl NmeaWriter {
+    fn open(sink: &NmeaSink) -> io::Result<Self> {
+        Ok(match sink {
+            NmeaSink::File(path) => {
+                NmeaWriter::File(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+            NmeaSink::TcpServer(address) => {
+                let listener = TcpListener::bind(address)?;
+                listener.set_nonblocking(true)?;
+                NmeaWriter::TcpServer {
+                    listener,
+                    clients: Vec::new(),
+                }
+            }
+            NmeaSink::Udp(target) => {
+                let local: SocketAddr = if target.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                NmeaWriter::Udp {
+                    socket: UdpSocket::bind(local)?,
+                    target: *target,
+                }
+            }
+        })
+    }
+
+    /// Errors mean the sink is unusable. A TCP client that fails is dropped on its own.
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            NmeaWriter::File(file) => file.write_all(data),
+            NmeaWriter::TcpServer { listener, clients } => {
+                loop {
+                    match listener.accept() {
+                        Ok((client, address)) => {
+                            info!("NMEA client connected from {}", address);
+                            client.set_nonblocking(true)?;
+                            client.set_nodelay(true)?;
+                            clients.push(TcpClient {
+                                stream: client,
+                                pending: Vec::new(),
+                            });
+                        }
+                        Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(error) => return Err(error),
+                    }
+                }
+                clients.retain_mut(|client| match client.send(data) {
+                    Ok(()) => true,
+                    Err(error) => {
+                        info!("NMEA client disconnected: {}", error);
+                        false
+                    }
+                });
+                Ok(())
+            }
+            NmeaWriter::Udp { socket, target } => socket.send_to(data, *target).map(|_| ()),
+        }
+    }
+}
+
+/// A client of [`NmeaSink::TcpServer`]. Its socket is nonblocking, so whatever it did not
+/// accept yet is kept and sent first next time.
+struct TcpClient {
+    stream: TcpStream,
+    pending: Vec<u8>,
+}
+
+impl TcpClient {
+    /// Clients that fall further behind than this are dropped
+    const MAX_PENDING: usize = 64 * 1024;
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.pending.len() + data.len() > Self::MAX_PENDING {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "client does not keep up",
+            ));
+        }
+        self.pending.extend_from_slice(data);
+
+        let mut written = 0;
+        while written < self.pending.len() {
+            match self.stream.write(&self.pending[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(count) => written += count,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        self.pending.drain(..written);
+        Ok(())
+    }
+}
+
+#[derive(Default, Resource)]
+struct NmeaConnections {
+    writers: Vec<NmeaWriter>,
+    /// Real time in seconds of the last update
+    last_sent: Option<f64>,
+}
+
+fn open_nmea_sinks(output: Res<NmeaOutput>, mut connections: ResMut<NmeaConnections>) {
+    if !output.is_changed() {
+        return;
+    }
+
+    connections.writers = output
+        .sinks
+        .iter()
+        .filter_map(|sink| {
+            NmeaWriter::open(sink)
+                .inspect_err(|error| warn!("Could not open NMEA output {:?}: {}", sink, error))
+                .ok()
+        })
+        .collect();
+}
+
+fn send_nmea(
+    output: Res<NmeaOutput>,
+    mut connections: ResMut<NmeaConnections>,
+    states: Res<StateVector>,
+    origin: Res<GeodeticOrigin>,
+    clock: Res<SensorClock>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed_secs_f64();
+    if connections.writers.is_empty()
+        || connections
+            .last_sent
+            .is_some_and(|last| now - last < 1.0 / output.rate_hz as f64)
+    {
+        return;
+    }
+    connections.last_sent = Some(now);
+
+    let timestamp = clock.to_unix_time(clock.app_time_now()) as f64 * 1e-9;
+    let fix = NmeaFix::new(timestamp, &states, &origin);
+    let data = output
+        .sentences
+        .iter()
+        .filter_map(|sentence| sentence.format(&fix))
+        .collect::<String>();
+
+    connections
+        .writers
+        .retain_mut(|writer| match writer.send(data.as_bytes()) {
+            Ok(()) => true,
+            Err(error) => {
+                warn!("NMEA output failed and was closed: {}", error);
+                false
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// 2024-03-09 12:35:19.25 UTC, 48°07.038' N 123°06' W, moving north-east at 5 m/s
+    fn fix() -> NmeaFix {
+        NmeaFix {
+            timestamp: 1_709_987_719.25,
+            coordinates: Some(Geodetic::new(48.1173, -123.1, 545.4)),
+            velocity: Vec3::new(3.0, 4.0, 0.0),
+            heading: Some(90.0),
+            valid: true,
+        }
+    }
+
+    #[test]
+    fn sentences_match_the_reference() {
+        let format = |sentence: NmeaSentence| sentence.format(&fix()).unwrap();
+        assert_eq!(
+            format(NmeaSentence::Gga),
+            "$GPGGA,123519.25,4807.03800,N,12306.00000,W,6,00,,545.4,M,0.0,M,,*6B\r\n"
+        );
+        assert_eq!(
+            format(NmeaSentence::Rmc),
+            "$GPRMC,123519.25,A,4807.03800,N,12306.00000,W,9.7,36.9,090324,,,E*7A\r\n"
+        );
+        assert_eq!(
+            format(NmeaSentence::Vtg),
+            "$GPVTG,36.9,T,,M,9.7,N,18.0,K,E*02\r\n"
+        );
+        assert_eq!(format(NmeaSentence::Hdt), "$GPHDT,90.0,T*0C\r\n");
+    }
+
+    #[test]
+    fn sentences_wait_for_what_they_report() {
+        let fix = NmeaFix {
+            coordinates: None,
+            heading: None,
+            ..fix()
+        };
+        assert_eq!(NmeaSentence::Gga.format(&fix), None);
+        assert_eq!(NmeaSentence::Rmc.format(&fix), None);
+        assert!(NmeaSentence::Vtg.format(&fix).is_some());
+        assert_eq!(NmeaSentence::Hdt.format(&fix), None);
+    }
+
+    #[test]
+    fn angle_rounding_carries_into_the_degrees() {
+        assert_eq!(latitude(12.0 + 59.999_995 / 60.0), "1300.00000,N");
+        assert_eq!(latitude(12.0 + 59.999_994 / 60.0), "1259.99999,N");
+        assert_eq!(longitude(-(179.0 + 59.999_995 / 60.0)), "18000.00000,W");
+        assert_eq!(longitude(0.0), "00000.00000,E");
+    }
+
+    /// A connected [`TcpClient`] and the peer it sends to
+    fn tcp_pair() -> (TcpClient, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let client = TcpClient {
+            stream,
+            pending: Vec::new(),
+        };
+        (client, peer)
+    }
+
+    #[test]
+    fn tcp_client_keeps_what_the_socket_did_not_accept() {
+        let (mut client, mut peer) = tcp_pair();
+        let data = (0..4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        // the peer does not read until the socket buffers are full
+        let mut sent = 0;
+        while client.pending.is_empty() {
+            client.send(&data).unwrap();
+            sent += data.len();
+        }
+
+        let mut received = Vec::new();
+        let mut buffer = [0; 64 * 1024];
+        while received.len() < sent {
+            client.send(&[]).unwrap();
+            let count = peer.read(&mut buffer).unwrap();
+            assert_ne!(count, 0);
+            received.extend_from_slice(&buffer[..count]);
+        }
+        assert!(client.pending.is_empty());
+        assert_eq!(received.len(), sent);
+        assert!(
+            received
+                .iter()
+                .enumerate()
+                .all(|(i, &byte)| byte == data[i % data.len()])
+        );
+    }
+
+    #[test]
+    fn tcp_client_that_falls_behind_is_dropped() {
+        let (mut client, _peer) = tcp_pair();
+        let data = [b'$'; 4096];
+
+        let error = (0..100_000).find_map(|_| client.send(&data).err()).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(client.pending.len() <= TcpClient::MAX_PENDING);
+    }
+}
@@ -0,0 +1,394 @@
+use bevy::prelude::*;
+use serde::{
+    Deserialize, Serialize,
+    de::{
+        IntoDeserializer,
+        value::{Error, StrDeserializer},
+    },
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{SocketAddr, UdpSocket},
+};
+
+#[cfg(target_os = "android")]
+use super::sensor::SensorData;
+use super::state::StateVector;
+use crate::ffi::event::SensorType;
+#[cfg(target_os = "android")]
+use crate::ffi::event::SensorValues;
+
+/// Sends the estimate and raw sensor channels as Open Sound Control messages over UDP,
+/// for audio and visual tools such as Max, Pure Data or TouchDesigner
+pub struct OscPlugin;
+
+impl Plugin for OscPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<OscOutput>()
+            .init_resource::<OscConnection>()
+            .add_systems(PostUpdate, (open_osc_socket, send_osc).chain());
+    }
+}
+
+/// Written as `quaternion`, `euler`, `position`, `velocity` or `sensor/<snake_case type>`
+/// in config files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum OscChannel {
+    /// `<namespace>/orientation/quaternion w x y z`
+    Quaternion,
+    /// `<namespace>/orientation/euler azimuth pitch roll` in degrees, as
+    /// `SensorManager.getOrientation` computes them, see [`android_orientation`]
+    Euler,
+    /// `<namespace>/position x y z` in metres, east, north, up
+    Position,
+    /// `<namespace>/velocity x y z` in m/s, east, north, up
+    Velocity,
+    /// `<namespace>/sensor/<snake_case type>` with the latest values, e.g.
+    /// `/phone/sensor/gyroscope x y z`. Only sent on Android.
+    Sensor(SensorType),
+}
+
+impl fmt::Display for OscChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OscChannel::Quaternion => write!(f, "quaternion"),
+            OscChannel::Euler => write!(f, "euler"),
+            OscChannel::Position => write!(f, "position"),
+            OscChannel::Velocity => write!(f, "velocity"),
+            OscChannel::Sensor(sensor_type) => write!(f, "sensor/{}", snake_case(*sensor_type)),
+        }
+    }
+}
+
+impl From<OscChannel> for String {
+    fn from(channel: OscChannel) -> Self {
+        channel.to_string()
+    }
+}
+
+impl TryFrom<String> for OscChannel {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        match name.as_str() {
+            "quaternion" => Ok(OscChannel::Quaternion),
+            "euler" => Ok(OscChannel::Euler),
+            "position" => Ok(OscChannel::Position),
+            "velocity" => Ok(OscChannel::Velocity),
+            _ => name
+                .strip_prefix("sensor/")
+                .and_then(|sensor_type| {
+                    // the same names as `SensorType` has everywhere else in the config
+                    let deserializer: StrDeserializer<'_, Error> = sensor_type.into_deserializer();
+                    SensorType::deserialize(deserializer).ok()
+                })
+                .map(OscChannel::Sensor)
+                .ok_or_else(|| format!("unknown OSC channel `{}`", name)),
+        }
+    }
+}
+
+/// Where to send to and which channels at what rate. Changing it reopens the socket.
+#[derive(Clone, Debug, PartialEq, Resource, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OscOutput {
+    /// `None` disables the output
+    pub target: Option<SocketAddr>,
+    /// Prefix of every address, e.g. `/phone`
+    pub namespace: String,
+    /// Channels to send and the rate of each in Hz
+    pub channels: HashMap<OscChannel, f32>,
+}
+
+impl Default for OscOutput {
+    fn default() -> Self {
+        Self {
+            target: None,
+            namespace: "/phone".to_string(),
+            channels: HashMap::from([
+                (OscChannel::Quaternion, 30.0),
+                (OscChannel::Euler, 30.0),
+                (OscChannel::Position, 30.0),
+                (OscChannel::Velocity, 30.0),
+            ]),
+        }
+    }
+}
+
+struct OscMessage {
+    address: String,
+    arguments: Vec<f32>,
+}
+
+impl OscMessage {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_string(out, &self.address);
+        write_string(out, &format!(",{}", "f".repeat(self.arguments.len())));
+        for argument in &self.arguments {
+            out.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+}
+
+/// OSC strings are null terminated and padded with nulls to a multiple of four bytes
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    out.extend_from_slice(string.as_bytes());
+    out.push(0);
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+}
+
+/// Wraps the messages in one bundle, to be dispatched immediately
+fn encode_bundle(messages: &[OscMessage]) -> Vec<u8> {
+    // time tag 1 means "immediately"
+    let mut bundle = b"#bundle\0".to_vec();
+    bundle.extend_from_slice(&1u64.to_be_bytes());
+    for message in messages {
+        let mut element = Vec::new();
+        message.encode(&mut element);
+        bundle.extend_from_slice(&(element.len() as i32).to_be_bytes());
+        bundle.extend_from_slice(&element);
+    }
+    bundle
+}
+
+/// `UncalibratedGyroscope` -> `uncalibrated_gyroscope`
+fn snake_case(sensor_type: SensorType) -> String {
+    let mut name = String::new();
+    for character in format!("{:?}", sensor_type).chars() {
+        if character.is_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(character.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Azimuth, pitch and roll in radians with the formulas of Android's
+/// `SensorManager.getOrientation`, applied to the rotation matrix of `orientation`.
+/// Azimuth is clockwise from north, pitch is negative with the top of the device tilted
+/// up and roll is positive with its right edge tilted down.
+fn android_orientation(orientation: Quat) -> [f32; 3] {
+    let rotation = Mat3::from_quat(orientation);
+    let (x, y, z) = (rotation.x_axis, rotation.y_axis, rotation.z_axis);
+    [
+        y.x.atan2(y.y),
+        (-y.z).clamp(-1.0, 1.0).asin(),
+        (-x.z).atan2(z.z),
+    ]
+}
+
+#[derive(Default, Resource)]
+struct OscConnection {
+    socket: Option<(UdpSocket, SocketAddr)>,
+    /// Real time in seconds each channel was last sent at
+    last_sent: HashMap<OscChannel, f64>,
+}
+
+fn open_osc_socket(output: Res<OscOutput>, mut connection: ResMut<OscConnection>) {
+    if !output.is_changed() {
+        return;
+    }
+
+    connection.socket = output.target.and_then(|target| {
+        let local: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        UdpSocket::bind(local)
+            .inspect_err(|error| warn!("Could not open OSC output to {}: {}", target, error))
+            .ok()
+            .map(|socket| (socket, target))
+    });
+}
+
+fn send_osc(
+    output: Res<OscOutput>,
+    mut connection: ResMut<OscConnection>,
+    states: Res<StateVector>,
+    time: Res<Time<Real>>,
+    #[cfg(target_os = "android")] sensor_data: Res<SensorData>,
+) {
+    if connection.socket.is_none() {
+        return;
+    }
+    let now = time.elapsed_secs_f64();
+    let namespace = output.namespace.trim_end_matches('/');
+
+    let mut messages = Vec::new();
+    for (&channel, &rate_hz) in &output.channels {
+        if connection
+            .last_sent
+            .get(&channel)
+            .is_some_and(|last| now - last < 1.0 / rate_hz as f64)
+        {
+            continue;
+        }
+
+        let message = match channel {
+            OscChannel::Quaternion => {
+                let q = states.orientation();
+                Some((
+                    "orientation/quaternion".to_string(),
+                    vec![q.w, q.x, q.y, q.z],
+                ))
+            }
+            OscChannel::Euler => {
+                let angles = android_orientation(states.orientation());
+                Some((
+                    "orientation/euler".to_string(),
+                    angles.map(f32::to_degrees).to_vec(),
+                ))
+            }
+            OscChannel::Position => Some((
+                "position".to_string(),
+                states.position().to_array().to_vec(),
+            )),
+            OscChannel::Velocity => Some((
+                "velocity".to_string(),
+                states.velocity().to_array().to_vec(),
+            )),
+            #[cfg(target_os = "android")]
+            OscChannel::Sensor(sensor_type) => sensor_data
+                .series(sensor_type)
+                .filter(|series| series.has_data())
+                .and_then(|series| series.latest())
+                .map(|event| {
+                    let arguments = match event.values {
+                        SensorValues::Scalar(value) => vec![value],
+                        SensorValues::Vec3(values) => values.to_array().to_vec(),
+                        SensorValues::Quat(q) => vec![q.w, q.x, q.y, q.z],
+                        SensorValues::Uncalibrated { values, bias } => {
+                            [values.to_array(), bias.to_array()].concat()
+                        }
+                        SensorValues::Location(fix) => vec![
+                            fix.latitude as f32,
+                            fix.longitude as f32,
+                            fix.altitude as f32,
+                        ],
+                    };
+                    (format!("sensor/{}", snake_case(sensor_type)), arguments)
+                }),
+            #[cfg(not(target_os = "android"))]
+            OscChannel::Sensor(_) => None,
+        };
+
+        if let Some((address, arguments)) = message {
+            connection.last_sent.insert(channel, now);
+            messages.push(OscMessage {
+                address: format!("{}/{}", namespace, address),
+                arguments,
+            });
+        }
+    }
+    if messages.is_empty() {
+        return;
+    }
+
+    let (socket, target) = connection.socket.as_ref().unwrap();
+    if let Err(error) = socket.send_to(&encode_bundle(&messages), target) {
+        warn!("OSC output failed and was closed: {}", error);
+        connection.socket = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::sensor::SENSOR_TYPES;
+
+    fn degrees(orientation: Quat) -> [f32; 3] {
+        android_orientation(orientation).map(f32::to_degrees)
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        assert!(
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(actual, expected)| (actual - expected).abs() < 1e-3),
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn orientation_follows_android_conventions() {
+        // flat, screen up, top pointing north
+        assert_close(degrees(Quat::IDENTITY), [0.0, 0.0, 0.0]);
+        // top pointing east
+        assert_close(
+            degrees(Quat::from_rotation_z(-90f32.to_radians())),
+            [90.0, 0.0, 0.0],
+        );
+        // top tilted up
+        assert_close(
+            degrees(Quat::from_rotation_x(30f32.to_radians())),
+            [0.0, -30.0, 0.0],
+        );
+        // right edge tilted down
+        assert_close(
+            degrees(Quat::from_rotation_y(20f32.to_radians())),
+            [0.0, 0.0, 20.0],
+        );
+        // all at once, applied as azimuth, then pitch, then roll
+        let orientation = Quat::from_rotation_z(-40f32.to_radians())
+            * Quat::from_rotation_x(-25f32.to_radians())
+            * Quat::from_rotation_y(15f32.to_radians());
+        assert_close(degrees(orientation), [40.0, 25.0, 15.0]);
+    }
+
+    #[test]
+    fn bundle_matches_the_reference() {
+        let bundle = encode_bundle(&[OscMessage {
+            address: "/phone/euler".to_string(),
+            arguments: vec![1.0, -2.5, 0.5],
+        }]);
+
+        let mut expected = b"#bundle\0".to_vec();
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]); // time tag "immediately"
+        expected.extend_from_slice(&[0, 0, 0, 36]); // element size
+        expected.extend_from_slice(b"/phone/euler\0\0\0\0"); // 12 bytes, a whole word of nulls
+        expected.extend_from_slice(b",fff\0\0\0\0");
+        expected.extend_from_slice(&[0x3F, 0x80, 0x00, 0x00]); // 1.0
+        expected.extend_from_slice(&[0xC0, 0x20, 0x00, 0x00]); // -2.5
+        expected.extend_from_slice(&[0x3F, 0x00, 0x00, 0x00]); // 0.5
+        assert_eq!(bundle, expected);
+    }
+
+    #[test]
+    fn channels_round_trip_through_their_names() {
+        let channels = [
+            OscChannel::Quaternion,
+            OscChannel::Euler,
+            OscChannel::Position,
+            OscChannel::Velocity,
+        ]
+        .into_iter()
+        .chain(SENSOR_TYPES.map(OscChannel::Sensor));
+        for channel in channels {
+            let name = String::from(channel);
+            assert_eq!(OscChannel::try_from(name.clone()), Ok(channel), "{name}");
+        }
+
+        assert_eq!(
+            String::from(OscChannel::Sensor(SensorType::UncalibratedGyroscope)),
+            "sensor/uncalibrated_gyroscope"
+        );
+        for unknown in [
+            "",
+            "orientation",
+            "sensor/",
+            "sensor/thermometer",
+            "gyroscope",
+        ] {
+            assert_eq!(
+                OscChannel::try_from(unknown.to_string()),
+                Err(format!("unknown OSC channel `{unknown}`"))
+            );
+        }
+    }
+}
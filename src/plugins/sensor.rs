@@ -1,131 +1,724 @@
+#![cfg_attr(not(target_os = "android"), allow(dead_code))]
+
+#[cfg(target_os = "android")]
+use bevy::diagnostic::Diagnostics;
 use bevy::prelude::*;
 use bevy::window::AppLifecycle;
 use bevy_debug_text_overlay::screen_print;
-use std::collections::VecDeque;
+#[cfg(target_os = "android")]
+use std::collections::HashSet;
+use std::collections::{HashMap, VecDeque, vec_deque};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::ffi::error::SensorError;
+use crate::ffi::event::{SensorAccuracy, SensorEvent, SensorType, SensorValues};
+#[cfg(target_os = "android")]
+use crate::ffi::sensor::{Sensor, SensorEventQueue, SensorInfo, SensorManager};
 
-use crate::ffi::sensor::{
-    Sensor, SensorEvent, SensorEventQueue, SensorManager, SensorType, SensorValues,
-};
+#[cfg(target_os = "android")]
+use super::diagnostics::QUEUE_LENGTH;
 
+#[cfg(target_os = "android")]
 pub struct SensorPlugin;
 
+#[cfg(target_os = "android")]
 impl Plugin for SensorPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(SensorData::default())
-            .insert_non_send_resource(Sensors::default())
+        app.init_resource::<SensorData>()
+            .insert_resource(SensorConfig::default())
+            .insert_resource(SensorSynchronizer::default())
+            .init_resource::<SensorCapabilities>()
+            .init_resource::<SensorFallbacks>()
+            .init_resource::<SensorStatus>()
+            .init_resource::<SensorAccuracies>()
+            .init_resource::<SensorClock>()
+            .insert_non_send_resource(Sensors::<SensorManager>::default())
+            .add_event::<SensorFrame>()
+            .add_event::<SensorFailure>()
+            .add_event::<SensorAccuracyChanged>()
+            .add_event::<SensorSessionChanged>()
             .add_systems(PostStartup, setup_sensors)
             .add_systems(
                 Update,
                 (
-                    handle_lifetime,
-                    (update_sensor_data, print_sensor_data).chain(),
+                    (handle_lifetime, apply_sensor_config).chain(),
+                    (
+                        update_sensor_data,
+                        (
+                            synchronize_sensor_data,
+                            print_sensor_data,
+                            print_sensor_rates,
+                        ),
+                    )
+                        .chain(),
+                    (track_sensor_failures, print_sensor_status).chain(),
+                    print_sensor_accuracy,
                 ),
             );
     }
 }
 
-struct Sensors {
-    // manager: Option<SensorManager>,
-    queue: Option<SensorEventQueue>,
-    sensors: Vec<Sensor>,
+/// Requested state of a single sensor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SensorSettings {
+    pub enabled: bool,
+    pub rate_hz: f32,
+    /// How long the hardware may batch samples in its FIFO before reporting them, in
+    /// microseconds. `0` reports every sample right away, sensors without a FIFO ignore it.
+    pub max_report_latency_us: i64,
+}
+
+impl SensorSettings {
+    /// Sampling period in microseconds, as expected by the NDK
+    pub fn sampling_period_us(&self) -> i32 {
+        (1e6 / self.rate_hz.max(f32::EPSILON)) as i32
+    }
+}
+
+impl Default for SensorSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            rate_hz: 1e6 / DEFAULT_SAMPLING_PERIOD as f32,
+            max_report_latency_us: 0,
+        }
+    }
+}
+
+/// Which sensors are used and how fast they are asked to report.
+/// Changes are pushed to the event queue while the app is running.
+#[derive(Clone, Debug, Resource)]
+pub struct SensorConfig {
+    sensors: HashMap<SensorType, SensorSettings>,
+}
+
+impl SensorConfig {
+    pub fn get(&self, sensor_type: SensorType) -> SensorSettings {
+        self.sensors
+            .get(&sensor_type)
+            .copied()
+            .unwrap_or(SensorSettings {
+                enabled: false,
+                ..default()
+            })
+    }
+
+    pub fn set(&mut self, sensor_type: SensorType, settings: SensorSettings) {
+        self.sensors.insert(sensor_type, settings);
+    }
+
+    pub fn set_rate(&mut self, sensor_type: SensorType, rate_hz: f32) {
+        self.sensors.entry(sensor_type).or_default().rate_hz = rate_hz;
+    }
+
+    pub fn set_enabled(&mut self, sensor_type: SensorType, enabled: bool) {
+        self.sensors.entry(sensor_type).or_default().enabled = enabled;
+    }
+
+    pub fn set_max_report_latency(&mut self, sensor_type: SensorType, latency_us: i64) {
+        self.sensors
+            .entry(sensor_type)
+            .or_default()
+            .max_report_latency_us = latency_us;
+    }
+
+    /// Longest report latency of any enabled sensor, in microseconds
+    pub fn max_report_latency_us(&self) -> i64 {
+        self.sensors
+            .values()
+            .filter(|settings| settings.enabled)
+            .map(|settings| settings.max_report_latency_us)
+            .max()
+            .unwrap_or(0)
+    }
 }
 
-impl Default for Sensors {
+impl Default for SensorConfig {
     fn default() -> Self {
         Self {
-            // manager: None,
+            sensors: SENSOR_TYPES
+                .iter()
+                .map(|&sensor_type| {
+                    let settings = SensorSettings {
+                        enabled: DEFAULT_ENABLED.contains(&sensor_type),
+                        ..default()
+                    };
+                    (sensor_type, settings)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Whether the app is consuming sensor data, driven by the app lifecycle
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SensorSession {
+    /// The app has not been running yet
+    #[default]
+    Stopped,
+    /// In the foreground, sensors are enabled as configured
+    Running,
+    /// In the background, every sensor is disabled
+    Suspended,
+}
+
+impl SensorSession {
+    /// Session the app should be in after a lifecycle event
+    fn for_lifecycle(event: &AppLifecycle) -> Self {
+        match event {
+            AppLifecycle::Running | AppLifecycle::WillResume => SensorSession::Running,
+            AppLifecycle::Idle | AppLifecycle::WillSuspend | AppLifecycle::Suspended => {
+                SensorSession::Suspended
+            }
+        }
+    }
+}
+
+/// The sensor session moved from `previous` to `session`
+#[derive(Clone, Copy, Debug, Event)]
+pub struct SensorSessionChanged {
+    pub previous: SensorSession,
+    pub session: SensorSession,
+}
+
+/// Every sensor type the plugin reads
+pub const SENSOR_TYPES: [SensorType; 12] = [
+    SensorType::Accelerometer,
+    SensorType::RawAccelerometer,
+    SensorType::UncalibratedAccelerometer,
+    SensorType::Gyroscope,
+    SensorType::UncalibratedGyroscope,
+    SensorType::MagneticField,
+    SensorType::UncalibratedMagneticField,
+    SensorType::Rotation,
+    SensorType::GameRotation,
+    SensorType::Compass,
+    SensorType::Gravity,
+    SensorType::Pressure,
+];
+
+const DEFAULT_SAMPLING_PERIOD: i32 = 1_000_000 / 50; // microseconds (50 Hz)
+
+/// Sensors switched on without any configuration. The raw and uncalibrated
+/// channels are opt-in for estimators that do not rely on Android's fused outputs.
+const DEFAULT_ENABLED: [SensorType; 7] = [
+    SensorType::Accelerometer,
+    SensorType::RawAccelerometer,
+    SensorType::Gyroscope,
+    SensorType::Rotation,
+    SensorType::Compass,
+    SensorType::Gravity,
+    SensorType::Pressure,
+];
+
+/// Sensor manager the session logic in [`Sensors`] runs against,
+/// the NDK on Android and a fake in tests
+trait SensorBackend {
+    type Sensor;
+    type Queue: SensorQueue<Sensor = Self::Sensor>;
+
+    fn create_event_queue(&self) -> Result<Self::Queue, SensorError>;
+}
+
+/// Event queue of a [`SensorBackend`]
+trait SensorQueue {
+    type Sensor;
+
+    fn enable_sensor(
+        &self,
+        sensor: &Self::Sensor,
+        sampling_period_us: i32,
+        max_report_latency_us: i64,
+    ) -> Result<(), SensorError>;
+
+    fn set_event_rate(
+        &self,
+        sensor: &Self::Sensor,
+        sampling_period_us: i32,
+    ) -> Result<(), SensorError>;
+
+    fn disable_sensor(&self, sensor: &Self::Sensor) -> Result<(), SensorError>;
+
+    fn get_events(&mut self) -> Result<Vec<SensorEvent>, SensorError>;
+
+    fn destroy(self) -> Result<(), SensorError>;
+}
+
+#[cfg(target_os = "android")]
+impl SensorBackend for SensorManager {
+    type Sensor = Sensor;
+    type Queue = SensorEventQueue;
+
+    fn create_event_queue(&self) -> Result<SensorEventQueue, SensorError> {
+        SensorManager::create_event_queue(self)
+    }
+}
+
+#[cfg(target_os = "android")]
+impl SensorQueue for SensorEventQueue {
+    type Sensor = Sensor;
+
+    fn enable_sensor(
+        &self,
+        sensor: &Sensor,
+        sampling_period_us: i32,
+        max_report_latency_us: i64,
+    ) -> Result<(), SensorError> {
+        SensorEventQueue::enable_sensor(self, sensor, sampling_period_us, max_report_latency_us)
+    }
+
+    fn set_event_rate(&self, sensor: &Sensor, sampling_period_us: i32) -> Result<(), SensorError> {
+        SensorEventQueue::set_event_rate(self, sensor, sampling_period_us)
+    }
+
+    fn disable_sensor(&self, sensor: &Sensor) -> Result<(), SensorError> {
+        SensorEventQueue::disable_sensor(self, sensor)
+    }
+
+    fn get_events(&mut self) -> Result<Vec<SensorEvent>, SensorError> {
+        SensorEventQueue::get_events(self)
+    }
+
+    fn destroy(self) -> Result<(), SensorError> {
+        SensorEventQueue::destroy(self)
+    }
+}
+
+/// Owns the sensor objects of a [`SensorBackend`]. The manager and sensors live as long as
+/// the app, the event queue only while the session is running. On Android it is attached to
+/// the looper of the thread that creates it, so it is built anew on every resume, e.g. after
+/// the activity was recreated.
+struct Sensors<B: SensorBackend> {
+    manager: Option<B>,
+    queue: Option<B::Queue>,
+    sensors: Vec<(SensorType, B::Sensor)>,
+    session: SensorSession,
+    /// Settings each enabled sensor was last registered with
+    enabled: HashMap<SensorType, SensorSettings>,
+}
+
+impl<B: SensorBackend> Default for Sensors<B> {
+    fn default() -> Self {
+        Self {
+            manager: None,
             queue: None,
             sensors: Vec::new(),
+            session: SensorSession::default(),
+            enabled: HashMap::new(),
         }
     }
 }
 
-impl Sensors {
-    const SAMPLING_PERIOD: i32 = 1_000_000 / 50; // microseconds (50 Hz)
+impl<B: SensorBackend> Sensors<B> {
+    /// Moves the session to `session`, enabling or disabling the sensors on the way.
+    /// Returns `None` if the session already was in that state, so nothing is toggled twice.
+    fn transition(
+        &mut self,
+        session: SensorSession,
+        config: &SensorConfig,
+    ) -> Option<(SensorSessionChanged, Vec<SensorFailure>)> {
+        let previous = self.session;
+        if previous == session {
+            return None;
+        }
 
-    fn enable(&self) {
-        dbg!("Enabling sensors...");
-        self.sensors.iter().for_each(|sensor| {
-            self.queue
-                .as_ref()
-                .unwrap()
-                .enable_sensor(&sensor, Self::SAMPLING_PERIOD);
-        })
+        self.session = session;
+        let failures = match session {
+            SensorSession::Running => self.enable(config),
+            SensorSession::Stopped | SensorSession::Suspended => self.disable(),
+        };
+        Some((SensorSessionChanged { previous, session }, failures))
     }
 
-    fn get_events(&self) -> Vec<SensorEvent> {
-        if let Some(queue) = &self.queue {
-            queue.get_events()
-        } else {
+    fn enable(&mut self, config: &SensorConfig) -> Vec<SensorFailure> {
+        info!("Enabling sensors");
+        if self.queue.is_none()
+            && let Some(manager) = &self.manager
+        {
+            match manager.create_event_queue() {
+                Ok(queue) => self.queue = Some(queue),
+                Err(error) => return vec![SensorFailure::new(None, error)],
+            }
+        }
+        self.apply(config)
+    }
+
+    /// Brings every sensor in line with `config`, enabling, disabling or
+    /// changing the rate of each as needed. Does nothing outside a running session.
+    /// A sensor that fails is reported and left alone, the others are still applied.
+    fn apply(&mut self, config: &SensorConfig) -> Vec<SensorFailure> {
+        let mut failures = Vec::new();
+        if self.session != SensorSession::Running {
+            return failures;
+        }
+        let Some(queue) = &self.queue else {
             warn!("Sensor event queue not initialized!");
-            Vec::new()
+            return failures;
+        };
+
+        for (sensor_type, sensor) in &self.sensors {
+            let settings = config.get(*sensor_type);
+            let result = match (settings.enabled, self.enabled.get(sensor_type)) {
+                (true, None) => Self::register(queue, sensor, &settings),
+                (true, Some(applied)) if *applied == settings => Ok(()),
+                (true, Some(applied))
+                    if applied.max_report_latency_us == settings.max_report_latency_us =>
+                {
+                    queue.set_event_rate(sensor, settings.sampling_period_us())
+                }
+                // the report latency can only be changed by registering the sensor again
+                (true, Some(_)) => queue
+                    .disable_sensor(sensor)
+                    .and_then(|_| Self::register(queue, sensor, &settings)),
+                (false, Some(_)) => queue.disable_sensor(sensor),
+                (false, None) => Ok(()),
+            };
+
+            match (&result, settings.enabled) {
+                (Ok(()), true) => {
+                    self.enabled.insert(*sensor_type, settings);
+                }
+                (_, false) => {
+                    self.enabled.remove(sensor_type);
+                }
+                (Err(_), true) => (),
+            }
+
+            if let Err(error) = result {
+                failures.push(SensorFailure::new(Some(*sensor_type), error));
+            }
+        }
+        failures
+    }
+
+    fn register(
+        queue: &B::Queue,
+        sensor: &B::Sensor,
+        settings: &SensorSettings,
+    ) -> Result<(), SensorError> {
+        queue.enable_sensor(
+            sensor,
+            settings.sampling_period_us(),
+            settings.max_report_latency_us,
+        )
+    }
+
+    fn get_events(&mut self) -> Result<Vec<SensorEvent>, SensorFailure> {
+        if let Some(queue) = &mut self.queue {
+            queue
+                .get_events()
+                .map_err(|error| SensorFailure::new(None, error))
+        } else {
+            if self.session == SensorSession::Running {
+                warn!("Sensor event queue not initialized!");
+            }
+            Ok(Vec::new())
         }
     }
 
-    fn disable(&self) {
-        dbg!("Disabling sensors...");
-        self.sensors.iter().for_each(|sensor| {
-            self.queue.as_ref().unwrap().disable_sensor(&sensor);
+    /// Disables every sensor and destroys the queue
+    fn disable(&mut self) -> Vec<SensorFailure> {
+        info!("Disabling sensors");
+        let Some(queue) = self.queue.take() else {
+            return Vec::new();
+        };
+
+        let mut failures: Vec<SensorFailure> = self
+            .sensors
+            .iter()
+            .filter(|(sensor_type, _)| self.enabled.remove(sensor_type).is_some())
+            .filter_map(|(sensor_type, sensor)| {
+                queue
+                    .disable_sensor(sensor)
+                    .err()
+                    .map(|error| SensorFailure::new(Some(*sensor_type), error))
+            })
+            .collect();
+        if let Err(error) = queue.destroy() {
+            failures.push(SensorFailure::new(None, error));
+        }
+        failures
+    }
+}
+
+/// A sensor call that failed, reported instead of aborting the app
+#[derive(Clone, Debug, Event)]
+pub struct SensorFailure {
+    /// Sensor the failing call was about, `None` for the manager and queue
+    pub sensor_type: Option<SensorType>,
+    pub error: SensorError,
+}
+
+impl SensorFailure {
+    fn new(sensor_type: Option<SensorType>, error: SensorError) -> Self {
+        Self { sensor_type, error }
+    }
+}
+
+impl std::fmt::Display for SensorFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.sensor_type {
+            Some(sensor_type) => write!(f, "{:?}: {}", sensor_type, self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+/// Health of the sensor layer as shown on screen
+#[derive(Debug, Default, Resource)]
+pub struct SensorStatus {
+    pub failure_count: u64,
+    pub last_failure: Option<SensorFailure>,
+}
+
+/// A sensor reported a different accuracy than before, e.g. the magnetometer lost its calibration
+#[derive(Clone, Debug, Event)]
+pub struct SensorAccuracyChanged {
+    pub sensor_type: SensorType,
+    /// `None` for the first event of a sensor
+    pub previous: Option<SensorAccuracy>,
+    pub accuracy: SensorAccuracy,
+    pub timestamp: i64,
+}
+
+/// Accuracy each sensor reported with its latest event
+#[derive(Debug, Default, Resource)]
+pub struct SensorAccuracies {
+    levels: HashMap<SensorType, SensorAccuracy>,
+}
+
+impl SensorAccuracies {
+    pub fn get(&self, sensor_type: SensorType) -> Option<SensorAccuracy> {
+        self.levels.get(&sensor_type).copied()
+    }
+
+    /// Records the accuracy of `event`, returning the change if it differs from the last one
+    fn update(&mut self, event: &SensorEvent) -> Option<SensorAccuracyChanged> {
+        let previous = self.levels.insert(event.sensor_type, event.accuracy);
+        (previous != Some(event.accuracy)).then_some(SensorAccuracyChanged {
+            sensor_type: event.sensor_type,
+            previous,
+            accuracy: event.accuracy,
+            timestamp: event.timestamp,
         })
     }
 }
 
+/// How much history a [`SensorDataSeries`] keeps around
+#[derive(Clone, Copy, Debug)]
+pub enum SeriesRetention {
+    /// Keep the newest `n` samples
+    Samples(usize),
+    /// Keep every sample that is at most this many nanoseconds older than the newest one
+    Window(i64),
+}
+
+/// Optional rate limiting stage in front of a [`SensorDataSeries`].
+/// Samples closer than `min_interval` to the last accepted one are rejected.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Decimator {
+    /// Minimum time between two accepted samples in nanoseconds, `0` lets everything through
+    pub min_interval: i64,
+}
+
+impl Decimator {
+    pub fn disabled() -> Self {
+        Self { min_interval: 0 }
+    }
+
+    /// Keeps at most `rate_hz` samples per second
+    pub fn from_rate(rate_hz: f32) -> Self {
+        Self {
+            min_interval: (1e9 / rate_hz) as i64,
+        }
+    }
+
+    fn accepts(&self, timestamp: i64, last_accepted: i64) -> bool {
+        timestamp - last_accepted >= self.min_interval
+    }
+}
+
+/// What happened to a sample passed to [`SensorDataSeries::add`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleOutcome {
+    Accepted,
+    /// Rejected by the series' [`Decimator`]
+    Decimated,
+    /// Not newer than the latest sample in the series
+    OutOfOrder,
+}
+
+/// Running counters of everything a [`SensorDataSeries`] has been fed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SeriesStats {
+    pub accepted: u64,
+    pub decimated: u64,
+    pub out_of_order: u64,
+    /// Accepted samples that have since fallen out of the retention window
+    pub expired: u64,
+}
+
+impl SeriesStats {
+    pub fn rejected(&self) -> u64 {
+        self.decimated + self.out_of_order
+    }
+}
+
 #[derive(Debug)]
 pub struct SensorDataSeries {
     series: VecDeque<SensorEvent>,
-    size: usize,
+    retention: SeriesRetention,
+    decimator: Decimator,
+    stats: SeriesStats,
     lp_alpha: f32,
 }
 
 impl SensorDataSeries {
-    const MIN_DELTA_TIME: i64 = Sensors::SAMPLING_PERIOD as i64 * 1_000; // nanoseconds
+    const NOMINAL_PERIOD: i64 = DEFAULT_SAMPLING_PERIOD as i64 * 1_000; // nanoseconds
 
-    pub fn new(size: usize) -> Self {
-        let mut series = VecDeque::with_capacity(size);
+    pub fn new(retention: SeriesRetention) -> Self {
+        let mut series = VecDeque::with_capacity(Self::capacity_hint(retention));
         series.push_back(SensorEvent::default());
 
         Self {
             series,
-            size,
+            retention,
+            decimator: Decimator::disabled(),
+            stats: SeriesStats::default(),
             lp_alpha: 0.2738, // 3Hz filter
         }
     }
 
-    pub fn add(&mut self, mut sensor_event: SensorEvent) -> Option<SensorEvent> {
-        // newest data lives at the back, oldest at the front
-        let mut expired_data = None;
+    /// Number of samples the policy is expected to hold, so the ring buffer rarely reallocates
+    fn capacity_hint(retention: SeriesRetention) -> usize {
+        match retention {
+            SeriesRetention::Samples(size) => size.max(1),
+            SeriesRetention::Window(window) => (window / Self::NOMINAL_PERIOD).max(0) as usize + 1,
+        }
+    }
 
-        if self.series.len() >= self.size {
-            expired_data = self.series.pop_front();
+    pub fn decimator(&self) -> Decimator {
+        self.decimator
+    }
+
+    pub fn set_decimator(&mut self, decimator: Decimator) {
+        self.decimator = decimator;
+    }
+
+    pub fn stats(&self) -> SeriesStats {
+        self.stats
+    }
+
+    pub fn retention(&self) -> SeriesRetention {
+        self.retention
+    }
+
+    pub fn set_retention(&mut self, retention: SeriesRetention) {
+        self.retention = retention;
+        self.expire();
+
+        let capacity = Self::capacity_hint(retention);
+        if capacity > self.series.capacity() {
+            self.series.reserve(capacity - self.series.len());
         }
+    }
 
-        if sensor_event.timestamp - self.latest().unwrap().timestamp >= Self::MIN_DELTA_TIME {
-            // low-pass filter
-            sensor_event.values = match (self.latest().unwrap().values, sensor_event.values) {
-                (SensorValues::Vec3(vector_latest), SensorValues::Vec3(vector_new)) => {
-                    SensorValues::Vec3(
-                        vector_latest * (1. - self.lp_alpha) + vector_new * self.lp_alpha,
-                    )
-                }
-                (SensorValues::Quat(quat_latest), SensorValues::Quat(quat_new)) => {
-                    SensorValues::Quat(
-                        quat_latest * (1. - self.lp_alpha) + quat_new * self.lp_alpha,
-                    )
-                }
-                _ => sensor_event.values,
-            };
+    /// Smoothing factor of the low-pass filter applied by [`SensorDataSeries::add`],
+    /// from 0 (frozen) to 1 (unfiltered)
+    pub fn lp_alpha(&self) -> f32 {
+        self.lp_alpha
+    }
 
-            self.series.push_back(sensor_event);
+    /// Tunes the low-pass filter to `cutoff_hz` for samples arriving at `rate_hz`
+    pub fn set_low_pass(&mut self, cutoff_hz: f32, rate_hz: f32) {
+        let dt = 1.0 / rate_hz;
+        let rc = 1.0 / (std::f32::consts::TAU * cutoff_hz);
+        self.lp_alpha = (dt / (rc + dt)).clamp(0.0, 1.0);
+    }
+
+    pub fn add(&mut self, mut sensor_event: SensorEvent) -> SampleOutcome {
+        // newest data lives at the back, oldest at the front
+        let latest_timestamp = self.latest().unwrap().timestamp;
+
+        if sensor_event.timestamp <= latest_timestamp {
+            self.stats.out_of_order += 1;
+            return SampleOutcome::OutOfOrder;
         }
 
-        expired_data
+        if !self
+            .decimator
+            .accepts(sensor_event.timestamp, latest_timestamp)
+        {
+            self.stats.decimated += 1;
+            return SampleOutcome::Decimated;
+        }
+
+        // low-pass filter
+        sensor_event.values = match (self.latest().unwrap().values, sensor_event.values) {
+            (SensorValues::Scalar(scalar_latest), SensorValues::Scalar(scalar_new)) => {
+                SensorValues::Scalar(
+                    scalar_latest * (1. - self.lp_alpha) + scalar_new * self.lp_alpha,
+                )
+            }
+            (SensorValues::Vec3(vector_latest), SensorValues::Vec3(vector_new)) => {
+                SensorValues::Vec3(
+                    vector_latest * (1. - self.lp_alpha) + vector_new * self.lp_alpha,
+                )
+            }
+            (SensorValues::Quat(quat_latest), SensorValues::Quat(quat_new)) => {
+                SensorValues::Quat(quat_latest * (1. - self.lp_alpha) + quat_new * self.lp_alpha)
+            }
+            (
+                SensorValues::Uncalibrated {
+                    values: values_latest,
+                    bias: bias_latest,
+                },
+                SensorValues::Uncalibrated { values, bias },
+            ) => SensorValues::Uncalibrated {
+                values: values_latest * (1. - self.lp_alpha) + values * self.lp_alpha,
+                bias: bias_latest * (1. - self.lp_alpha) + bias * self.lp_alpha,
+            },
+            _ => sensor_event.values,
+        };
+
+        self.series.push_back(sensor_event);
+        self.stats.accepted += 1;
+        self.expire();
+
+        SampleOutcome::Accepted
+    }
+
+    /// Drops everything the retention policy no longer covers. The latest sample is always kept.
+    fn expire(&mut self) {
+        let keep = match self.retention {
+            SeriesRetention::Samples(size) => size.max(1),
+            SeriesRetention::Window(window) => {
+                let newest = self.latest().unwrap().timestamp;
+                self.series.len()
+                    - self
+                        .series
+                        .partition_point(|event| newest - event.timestamp > window)
+                        .min(self.series.len() - 1)
+            }
+        };
+
+        while self.series.len() > keep {
+            let expired_data = self.series.pop_front().unwrap();
+            if !matches!(expired_data.sensor_type, SensorType::Unavailable) {
+                self.stats.expired += 1;
+            }
+        }
     }
 
     pub fn t_minus(&self, index: usize) -> Option<&SensorEvent> {
-        if index >= self.size {
+        if let SeriesRetention::Samples(size) = self.retention
+            && index >= size
+        {
             warn!("Invalid to access to SensorDataSeries with index {}", index);
             return None;
-        } else if index >= self.series.len() {
+        }
+
+        if index >= self.series.len() {
             return None;
         }
 
@@ -139,85 +732,880 @@ impl SensorDataSeries {
     pub fn oldest(&self) -> Option<&SensorEvent> {
         self.series.front()
     }
+
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    /// Time covered by the history in nanoseconds
+    pub fn span(&self) -> i64 {
+        match (self.oldest(), self.latest()) {
+            (Some(oldest), Some(latest)) => latest.timestamp - oldest.timestamp,
+            _ => 0,
+        }
+    }
+
+    /// Effective rate of the accepted samples over the retained history, in Hz
+    pub fn measured_rate(&self) -> Option<f32> {
+        let oldest = self
+            .iter()
+            .find(|event| !matches!(event.sensor_type, SensorType::Unavailable))?;
+        let latest = self.latest()?;
+        let samples = self
+            .series
+            .partition_point(|event| event.timestamp <= latest.timestamp)
+            - self
+                .series
+                .partition_point(|event| event.timestamp < oldest.timestamp);
+
+        if samples < 2 {
+            return None;
+        }
+        Some((samples - 1) as f32 / ((latest.timestamp - oldest.timestamp) as f32 * 1e-9))
+    }
+
+    /// Standard deviation of the time between the retained samples, in seconds
+    pub fn interval_jitter(&self) -> Option<f32> {
+        let timestamps = self
+            .iter()
+            .filter(|event| !matches!(event.sensor_type, SensorType::Unavailable))
+            .map(|event| event.timestamp)
+            .collect::<Vec<_>>();
+        let intervals = timestamps
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]) as f32 * 1e-9)
+            .collect::<Vec<_>>();
+        if intervals.len() < 2 {
+            return None;
+        }
+
+        let mean = intervals.iter().sum::<f32>() / intervals.len() as f32;
+        let variance = intervals
+            .iter()
+            .map(|interval| (interval - mean).powi(2))
+            .sum::<f32>()
+            / intervals.len() as f32;
+        Some(variance.sqrt())
+    }
+
+    /// Iterates over the history from oldest to newest
+    pub fn iter(&self) -> vec_deque::Iter<'_, SensorEvent> {
+        self.series.iter()
+    }
+
+    /// Samples with `start <= timestamp <= end`, oldest first
+    pub fn range(&self, start: i64, end: i64) -> vec_deque::Iter<'_, SensorEvent> {
+        // timestamps are strictly increasing, so both bounds can be binary searched
+        let from = self.series.partition_point(|event| event.timestamp < start);
+        let to = self
+            .series
+            .partition_point(|event| event.timestamp <= end)
+            .max(from);
+        self.series.range(from..to)
+    }
+
+    /// Samples strictly newer than `timestamp`, oldest first
+    pub fn since(&self, timestamp: i64) -> vec_deque::Iter<'_, SensorEvent> {
+        let from = self
+            .series
+            .partition_point(|event| event.timestamp <= timestamp);
+        self.series.range(from..)
+    }
+
+    /// Whether anything but the placeholder sample has been recorded
+    pub fn has_data(&self) -> bool {
+        self.latest()
+            .is_some_and(|event| !matches!(event.sensor_type, SensorType::Unavailable))
+    }
+
+    /// Value of the series at an arbitrary `timestamp`, interpolated between the samples
+    /// on either side of it. `None` outside of the recorded history, nothing is extrapolated.
+    pub fn sample_at(&self, timestamp: i64) -> Option<SensorValues> {
+        let after = self
+            .series
+            .partition_point(|event| event.timestamp < timestamp);
+        let next = self.series.get(after)?;
+        if next.timestamp == timestamp {
+            return Some(next.values);
+        }
+
+        let previous = self.series.get(after.checked_sub(1)?)?;
+        if matches!(previous.sensor_type, SensorType::Unavailable) {
+            return None;
+        }
+
+        let t =
+            (timestamp - previous.timestamp) as f32 / (next.timestamp - previous.timestamp) as f32;
+        previous.values.interpolate(&next.values, t)
+    }
+
+    /// Accuracy of the last sample at or before `timestamp`
+    pub fn accuracy_at(&self, timestamp: i64) -> Option<SensorAccuracy> {
+        let index = self
+            .series
+            .partition_point(|event| event.timestamp <= timestamp)
+            .checked_sub(1)?;
+        let event = &self.series[index];
+        (!matches!(event.sensor_type, SensorType::Unavailable)).then_some(event.accuracy)
+    }
+
+    /// Like [`Self::sample_at`], but `None` where the sensor reported itself as unusable
+    pub fn trusted_sample_at(&self, timestamp: i64) -> Option<SensorValues> {
+        if self.accuracy_at(timestamp)?.weight() <= 0.0 {
+            return None;
+        }
+        self.sample_at(timestamp)
+    }
+
+    pub fn vec3_at(&self, timestamp: i64) -> Option<Vec3> {
+        self.sample_at(timestamp)
+            .and_then(|values| values.vec3().copied())
+    }
+
+    pub fn quat_at(&self, timestamp: i64) -> Option<Quat> {
+        self.sample_at(timestamp)
+            .and_then(|values| values.quat().copied())
+    }
+}
+
+impl<'a> IntoIterator for &'a SensorDataSeries {
+    type Item = &'a SensorEvent;
+    type IntoIter = vec_deque::Iter<'a, SensorEvent>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
 }
 
 #[derive(Debug, Resource)]
 pub struct SensorData {
     pub accelerometer: SensorDataSeries,
+    pub raw_accelerometer: SensorDataSeries,
+    pub uncalibrated_accelerometer: SensorDataSeries,
     pub gyroscope: SensorDataSeries,
+    pub uncalibrated_gyroscope: SensorDataSeries,
+    pub magnetic_field: SensorDataSeries,
+    pub uncalibrated_magnetic_field: SensorDataSeries,
     pub rotation: SensorDataSeries,
+    pub game_rotation: SensorDataSeries,
     pub compass: SensorDataSeries,
     pub gravity: SensorDataSeries,
+    pub pressure: SensorDataSeries,
 }
 
 impl SensorData {
-    fn add_event(&mut self, event: SensorEvent) {
-        match event.sensor_type {
-            SensorType::Accelerometer => self.accelerometer.add(event),
-            SensorType::Gyroscope => self.gyroscope.add(event),
-            SensorType::Rotation => self.rotation.add(event),
-            SensorType::Compass => self.compass.add(event),
-            SensorType::Gravity => self.gravity.add(event),
+    const DEFAULT_RETENTION: SeriesRetention = SeriesRetention::Window(5_000_000_000); // 5 seconds
+
+    pub fn new(retention: SeriesRetention) -> Self {
+        Self {
+            accelerometer: SensorDataSeries::new(retention),
+            raw_accelerometer: SensorDataSeries::new(retention),
+            uncalibrated_accelerometer: SensorDataSeries::new(retention),
+            gyroscope: SensorDataSeries::new(retention),
+            uncalibrated_gyroscope: SensorDataSeries::new(retention),
+            magnetic_field: SensorDataSeries::new(retention),
+            uncalibrated_magnetic_field: SensorDataSeries::new(retention),
+            rotation: SensorDataSeries::new(retention),
+            game_rotation: SensorDataSeries::new(retention),
+            compass: SensorDataSeries::new(retention),
+            gravity: SensorDataSeries::new(retention),
+            pressure: SensorDataSeries::new(retention),
+        }
+    }
+
+    pub fn series(&self, sensor_type: SensorType) -> Option<&SensorDataSeries> {
+        match sensor_type {
+            SensorType::Accelerometer => Some(&self.accelerometer),
+            SensorType::RawAccelerometer => Some(&self.raw_accelerometer),
+            SensorType::UncalibratedAccelerometer => Some(&self.uncalibrated_accelerometer),
+            SensorType::Gyroscope => Some(&self.gyroscope),
+            SensorType::UncalibratedGyroscope => Some(&self.uncalibrated_gyroscope),
+            SensorType::MagneticField => Some(&self.magnetic_field),
+            SensorType::UncalibratedMagneticField => Some(&self.uncalibrated_magnetic_field),
+            SensorType::Rotation => Some(&self.rotation),
+            SensorType::GameRotation => Some(&self.game_rotation),
+            SensorType::Compass => Some(&self.compass),
+            SensorType::Gravity => Some(&self.gravity),
+            SensorType::Pressure => Some(&self.pressure),
             _ => None,
-        };
+        }
+    }
+
+    pub fn series_mut(&mut self, sensor_type: SensorType) -> Option<&mut SensorDataSeries> {
+        match sensor_type {
+            SensorType::Accelerometer => Some(&mut self.accelerometer),
+            SensorType::RawAccelerometer => Some(&mut self.raw_accelerometer),
+            SensorType::UncalibratedAccelerometer => Some(&mut self.uncalibrated_accelerometer),
+            SensorType::Gyroscope => Some(&mut self.gyroscope),
+            SensorType::UncalibratedGyroscope => Some(&mut self.uncalibrated_gyroscope),
+            SensorType::MagneticField => Some(&mut self.magnetic_field),
+            SensorType::UncalibratedMagneticField => Some(&mut self.uncalibrated_magnetic_field),
+            SensorType::Rotation => Some(&mut self.rotation),
+            SensorType::GameRotation => Some(&mut self.game_rotation),
+            SensorType::Compass => Some(&mut self.compass),
+            SensorType::Gravity => Some(&mut self.gravity),
+            SensorType::Pressure => Some(&mut self.pressure),
+            _ => None,
+        }
+    }
+
+    fn all_series(&self) -> [&SensorDataSeries; 12] {
+        [
+            &self.accelerometer,
+            &self.raw_accelerometer,
+            &self.uncalibrated_accelerometer,
+            &self.gyroscope,
+            &self.uncalibrated_gyroscope,
+            &self.magnetic_field,
+            &self.uncalibrated_magnetic_field,
+            &self.rotation,
+            &self.game_rotation,
+            &self.compass,
+            &self.gravity,
+            &self.pressure,
+        ]
+    }
+
+    fn all_series_mut(&mut self) -> [&mut SensorDataSeries; 12] {
+        [
+            &mut self.accelerometer,
+            &mut self.raw_accelerometer,
+            &mut self.uncalibrated_accelerometer,
+            &mut self.gyroscope,
+            &mut self.uncalibrated_gyroscope,
+            &mut self.magnetic_field,
+            &mut self.uncalibrated_magnetic_field,
+            &mut self.rotation,
+            &mut self.game_rotation,
+            &mut self.compass,
+            &mut self.gravity,
+            &mut self.pressure,
+        ]
+    }
+
+    /// The same cutoff on every series, each tuned to the rate `rate_hz` gives for its sensor
+    pub fn set_low_pass(&mut self, cutoff_hz: f32, rate_hz: impl Fn(SensorType) -> f32) {
+        for sensor_type in SENSOR_TYPES {
+            if let Some(series) = self.series_mut(sensor_type) {
+                series.set_low_pass(cutoff_hz, rate_hz(sensor_type));
+            }
+        }
+    }
+
+    pub fn set_retention(&mut self, retention: SeriesRetention) {
+        for series in self.all_series_mut() {
+            series.set_retention(retention);
+        }
+    }
+
+    /// Widens time based retention to hold two full hardware batches of `latency_us`,
+    /// so a burst of backlogged samples is not expired before it has been consumed
+    pub fn cover_report_latency(&mut self, latency_us: i64) {
+        let window = latency_us * 1_000 * 2;
+        for series in self.all_series_mut() {
+            if let SeriesRetention::Window(current) = series.retention()
+                && current < window
+            {
+                series.set_retention(SeriesRetention::Window(window));
+            }
+        }
+    }
+
+    /// Every sensor resampled at `timestamp`. Sensors that report themselves as
+    /// unreliable there are left out, see [`SensorDataSeries::trusted_sample_at`].
+    pub fn frame_at(&self, timestamp: i64) -> SensorFrame {
+        let sample = |series: &SensorDataSeries| series.trusted_sample_at(timestamp);
+        let vec3 = |series: &SensorDataSeries| sample(series)?.vec3().copied();
+        let quat = |series: &SensorDataSeries| sample(series)?.quat().copied();
+
+        SensorFrame {
+            timestamp,
+            accelerometer: vec3(&self.accelerometer),
+            raw_accelerometer: vec3(&self.raw_accelerometer),
+            uncalibrated_accelerometer: sample(&self.uncalibrated_accelerometer),
+            gyroscope: vec3(&self.gyroscope),
+            uncalibrated_gyroscope: sample(&self.uncalibrated_gyroscope),
+            magnetic_field: vec3(&self.magnetic_field),
+            uncalibrated_magnetic_field: sample(&self.uncalibrated_magnetic_field),
+            rotation: quat(&self.rotation),
+            game_rotation: quat(&self.game_rotation),
+            compass: quat(&self.compass),
+            gravity: vec3(&self.gravity),
+            pressure: sample(&self.pressure).and_then(|values| values.scalar()),
+        }
+    }
+
+    /// Latest timestamp that every sensor which has reported so far can be sampled at
+    pub fn common_horizon(&self) -> Option<i64> {
+        self.all_series()
+            .into_iter()
+            .filter(|series| series.has_data())
+            .map(|series| series.latest().unwrap().timestamp)
+            .min()
+    }
+
+    /// Rate limits a single sensor independently of the others
+    pub fn set_decimator(&mut self, sensor_type: SensorType, decimator: Decimator) {
+        if let Some(series) = self.series_mut(sensor_type) {
+            series.set_decimator(decimator);
+        }
+    }
+
+    pub(super) fn add_event(&mut self, event: SensorEvent) -> Option<SampleOutcome> {
+        self.series_mut(event.sensor_type)
+            .map(|series| series.add(event))
     }
 }
 
 impl Default for SensorData {
     fn default() -> Self {
+        Self::new(Self::DEFAULT_RETENTION)
+    }
+}
+
+/// A snapshot of every sensor resampled at the same instant.
+/// Sensors without usable data around `timestamp` are left as `None`.
+#[derive(Clone, Debug, Event)]
+pub struct SensorFrame {
+    pub timestamp: i64,
+    pub accelerometer: Option<Vec3>,
+    pub raw_accelerometer: Option<Vec3>,
+    /// [`SensorValues::Uncalibrated`], keeping the bias next to the reading
+    pub uncalibrated_accelerometer: Option<SensorValues>,
+    pub gyroscope: Option<Vec3>,
+    pub uncalibrated_gyroscope: Option<SensorValues>,
+    pub magnetic_field: Option<Vec3>,
+    pub uncalibrated_magnetic_field: Option<SensorValues>,
+    pub rotation: Option<Quat>,
+    pub game_rotation: Option<Quat>,
+    pub compass: Option<Quat>,
+    pub gravity: Option<Vec3>,
+    pub pressure: Option<f32>,
+}
+
+/// Aligns the independently timed sensor streams onto a common clock,
+/// emitting one [`SensorFrame`] per `period`
+#[derive(Debug, Resource)]
+pub struct SensorSynchronizer {
+    /// Time between two frames in nanoseconds
+    pub period: i64,
+    last_frame: Option<i64>,
+}
+
+impl SensorSynchronizer {
+    /// Backlog after which the synchronizer skips ahead instead of catching up, e.g. after a resume
+    const MAX_BACKLOG: i64 = 1_000_000_000; // nanoseconds
+
+    pub fn new(period: i64) -> Self {
+        Self {
+            period,
+            last_frame: None,
+        }
+    }
+
+    /// Timestamps of the frames that became available up to `horizon`
+    fn pending(&mut self, horizon: i64) -> Vec<i64> {
+        let period = self.period.max(1);
+        let mut next = match self.last_frame {
+            Some(last) if horizon - last <= Self::MAX_BACKLOG => last + period,
+            _ => horizon - horizon.rem_euclid(period),
+        };
+
+        let mut timestamps = Vec::new();
+        while next <= horizon {
+            timestamps.push(next);
+            self.last_frame = Some(next);
+            next += period;
+        }
+        timestamps
+    }
+}
+
+impl Default for SensorSynchronizer {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLING_PERIOD as i64 * 1_000)
+    }
+}
+
+/// Relates sensor timestamps (boot time in nanoseconds) to app time, the elapsed
+/// [`Time<Real>`] in nanoseconds, so samples, recordings and network output share one timebase.
+/// The offset is fitted to the least delayed event of each second. It therefore absorbs the
+/// smallest delivery latency, and [`Self::latency_ms`] is the delay on top of that.
+///
+/// Wall clock time is app time plus an offset taken once when the clock is created, so
+/// later adjustments of the system clock do not make outputs jump.
+#[derive(Debug, Resource)]
+pub struct SensorClock {
+    /// App time zero, the startup of [`Time<Real>`]
+    startup: Instant,
+    /// Unix time minus app time, in nanoseconds
+    unix_offset: i64,
+    /// Smallest `app - sensor` offset seen in each of the last seconds, with its app time
+    minima: VecDeque<(i64, i64)>,
+    fit: Option<ClockFit>,
+    /// Smoothed delay of the newest event of each read, in nanoseconds
+    latency: Option<f32>,
+}
+
+impl FromWorld for SensorClock {
+    fn from_world(world: &mut World) -> Self {
+        let startup = world
+            .get_resource::<Time<Real>>()
+            .map_or_else(Instant::now, |time| time.startup());
+        let app_now = Instant::now().duration_since(startup).as_nanos() as i64;
+        let unix_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64;
         Self {
-            accelerometer: SensorDataSeries::new(5),
-            gyroscope: SensorDataSeries::new(5),
-            rotation: SensorDataSeries::new(5),
-            compass: SensorDataSeries::new(5),
-            gravity: SensorDataSeries::new(5),
+            startup,
+            unix_offset: unix_now - app_now,
+            minima: VecDeque::new(),
+            fit: None,
+            latency: None,
         }
     }
 }
 
-fn setup_sensors(mut sensors: NonSendMut<Sensors>) {
-    let manager = SensorManager::new();
-    let queue = manager.create_event_queue();
+/// `offset = intercept + drift * (app_time - reference)`
+#[derive(Clone, Copy, Debug)]
+struct ClockFit {
+    reference: i64,
+    intercept: f64,
+    drift: f64,
+}
+
+impl SensorClock {
+    const BUCKET: i64 = 1_000_000_000; // nanoseconds
+    const WINDOW: usize = 60; // buckets
+    const LATENCY_ALPHA: f32 = 0.1;
 
-    [
-        SensorType::Accelerometer,
-        SensorType::Gyroscope,
-        SensorType::Rotation,
-        SensorType::Compass,
-        SensorType::Gravity,
-    ]
-    .iter()
-    .for_each(|&sensor_type| {
-        sensors
-            .sensors
-            .push(manager.get_default_sensor(sensor_type));
+    /// App time at this very moment, more precise than the frame's `time.elapsed()`
+    pub fn app_time_now(&self) -> i64 {
+        Instant::now().duration_since(self.startup).as_nanos() as i64
+    }
+
+    /// Nanoseconds since the Unix epoch an app time corresponds to
+    pub fn to_unix_time(&self, app_time: i64) -> i64 {
+        app_time + self.unix_offset
+    }
+
+    /// Feeds the newest sensor timestamp of a read made at `app_time`
+    fn observe(&mut self, app_time: i64, sensor_timestamp: i64) {
+        let offset = app_time - sensor_timestamp;
+        match self.minima.back_mut() {
+            Some((time, minimum)) if *time / Self::BUCKET == app_time / Self::BUCKET => {
+                if offset < *minimum {
+                    *time = app_time;
+                    *minimum = offset;
+                }
+            }
+            _ => {
+                self.minima.push_back((app_time, offset));
+                if self.minima.len() > Self::WINDOW {
+                    self.minima.pop_front();
+                }
+            }
+        }
+        self.fit = Self::fit(&self.minima);
+
+        if let Some(delivered) = self.to_app_time(sensor_timestamp) {
+            let latency = (app_time - delivered) as f32;
+            self.latency = Some(match self.latency {
+                Some(smoothed) => smoothed + (latency - smoothed) * Self::LATENCY_ALPHA,
+                None => latency,
+            });
+        }
+    }
+
+    /// Least squares line through the per-second minima. A constant offset until
+    /// enough seconds have been seen to tell drift from noise.
+    fn fit(minima: &VecDeque<(i64, i64)>) -> Option<ClockFit> {
+        let &(reference, first) = minima.front()?;
+        if minima.len() < 3 {
+            return Some(ClockFit {
+                reference,
+                intercept: minima.iter().map(|&(_, offset)| offset).min()? as f64,
+                drift: 0.0,
+            });
+        }
+
+        // relative to the first point, so the sums stay well within f64 precision
+        let points = minima
+            .iter()
+            .map(|&(time, offset)| ((time - reference) as f64, (offset - first) as f64));
+        let n = minima.len() as f64;
+        let (sum_x, sum_y, sum_xx, sum_xy) = points.fold(
+            (0.0, 0.0, 0.0, 0.0),
+            |(sum_x, sum_y, sum_xx, sum_xy), (x, y)| {
+                (sum_x + x, sum_y + y, sum_xx + x * x, sum_xy + x * y)
+            },
+        );
+        let denominator = n * sum_xx - sum_x * sum_x;
+        let drift = if denominator.abs() > f64::EPSILON {
+            (n * sum_xy - sum_x * sum_y) / denominator
+        } else {
+            0.0
+        };
+
+        Some(ClockFit {
+            reference,
+            intercept: first as f64 + (sum_y - drift * sum_x) / n,
+            drift,
+        })
+    }
+
+    /// `app - sensor` at the given app time
+    pub fn offset_at(&self, app_time: i64) -> Option<i64> {
+        let fit = self.fit?;
+        Some((fit.intercept + fit.drift * (app_time - fit.reference) as f64) as i64)
+    }
+
+    /// App time a sensor timestamp corresponds to
+    pub fn to_app_time(&self, sensor_timestamp: i64) -> Option<i64> {
+        let approximate = sensor_timestamp + self.fit?.intercept as i64;
+        Some(sensor_timestamp + self.offset_at(approximate)?)
+    }
+
+    /// Sensor timestamp an app time corresponds to
+    pub fn to_sensor_time(&self, app_time: i64) -> Option<i64> {
+        Some(app_time - self.offset_at(app_time)?)
+    }
+
+    /// Rate at which the sensor clock runs slow against app time, in parts per million
+    pub fn drift_ppm(&self) -> Option<f64> {
+        Some(self.fit?.drift * 1e6)
+    }
+
+    pub fn latency_ms(&self) -> Option<f32> {
+        Some(self.latency? * 1e-6)
+    }
+}
+
+/// Every sensor the device reports, for picking fallbacks and for display
+#[cfg(target_os = "android")]
+#[derive(Debug, Default, Resource)]
+pub struct SensorCapabilities {
+    pub sensors: Vec<SensorInfo>,
+    available: HashSet<SensorType>,
+}
+
+#[cfg(target_os = "android")]
+impl SensorCapabilities {
+    pub fn new(sensors: Vec<SensorInfo>) -> Self {
+        let available = sensors.iter().filter_map(|info| info.sensor_type).collect();
+        Self { sensors, available }
+    }
+
+    pub fn has(&self, sensor_type: SensorType) -> bool {
+        self.available.contains(&sensor_type)
+    }
+
+    fn log_report(&self) {
+        info!("Found {} sensors:", self.sensors.len());
+        for info in &self.sensors {
+            info!(
+                "  {} ({}) by {}: resolution {}, min delay {} us, FIFO {}/{} events",
+                info.name,
+                info.type_name,
+                info.vendor,
+                info.resolution,
+                info.min_delay_us,
+                info.fifo_reserved_event_count,
+                info.fifo_max_event_count
+            );
+        }
+        for sensor_type in SENSOR_TYPES {
+            if !self.has(sensor_type) {
+                warn!("No {:?} sensor on this device", sensor_type);
+            }
+        }
+    }
+}
+
+/// Stand-ins for sensors the device lacks, derived from the raw accelerometer.
+/// Decided once at startup from the [`SensorCapabilities`].
+#[derive(Debug, Default, Resource)]
+pub struct SensorFallbacks {
+    /// Gravity is estimated by low-pass filtering the raw accelerometer
+    pub gravity_from_accelerometer: bool,
+    /// Linear acceleration is the raw accelerometer minus gravity
+    pub linear_acceleration_from_accelerometer: bool,
+    gravity: Option<(i64, Vec3)>,
+}
+
+impl SensorFallbacks {
+    const GRAVITY_TIME_CONSTANT: f32 = 0.5; // seconds
+
+    #[cfg(target_os = "android")]
+    pub fn for_capabilities(capabilities: &SensorCapabilities) -> Self {
+        let has_raw = capabilities.has(SensorType::RawAccelerometer);
+        Self {
+            gravity_from_accelerometer: has_raw && !capabilities.has(SensorType::Gravity),
+            linear_acceleration_from_accelerometer: has_raw
+                && !capabilities.has(SensorType::Accelerometer),
+            gravity: None,
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.gravity_from_accelerometer || self.linear_acceleration_from_accelerometer
+    }
+
+    /// Events standing in for missing sensors, derived from a raw accelerometer `event`
+    fn derive(&mut self, event: &SensorEvent, sensor_data: &SensorData) -> Vec<SensorEvent> {
+        let mut derived = Vec::new();
+        let (SensorType::RawAccelerometer, Some(&raw)) = (event.sensor_type, event.values.vec3())
+        else {
+            return derived;
+        };
+
+        let gravity = if self.gravity_from_accelerometer {
+            let gravity = match self.gravity {
+                Some((timestamp, gravity)) => {
+                    let dt = (event.timestamp - timestamp) as f32 * 1e-9;
+                    gravity.lerp(raw, dt / (Self::GRAVITY_TIME_CONSTANT + dt))
+                }
+                None => raw,
+            };
+            self.gravity = Some((event.timestamp, gravity));
+            derived.push(SensorEvent {
+                sensor_type: SensorType::Gravity,
+                values: SensorValues::Vec3(gravity),
+                ..event.clone()
+            });
+            Some(gravity)
+        } else {
+            sensor_data
+                .gravity
+                .latest()
+                .and_then(|gravity| gravity.values.vec3().copied())
+        };
+
+        if self.linear_acceleration_from_accelerometer
+            && let Some(gravity) = gravity
+        {
+            derived.push(SensorEvent {
+                sensor_type: SensorType::Accelerometer,
+                values: SensorValues::Vec3(raw - gravity),
+                ..event.clone()
+            });
+        }
+
+        derived
+    }
+}
+
+#[cfg(target_os = "android")]
+fn setup_sensors(
+    mut sensors: NonSendMut<Sensors<SensorManager>>,
+    mut capabilities: ResMut<SensorCapabilities>,
+    mut fallbacks: ResMut<SensorFallbacks>,
+    mut config: ResMut<SensorConfig>,
+    mut failures: EventWriter<SensorFailure>,
+) {
+    let manager = match SensorManager::new() {
+        Ok(manager) => manager,
+        Err(error) => {
+            failures.write(SensorFailure::new(None, error));
+            return;
+        }
+    };
+
+    *capabilities =
+        SensorCapabilities::new(manager.get_sensor_list().iter().map(Sensor::info).collect());
+    capabilities.log_report();
+
+    SENSOR_TYPES.iter().for_each(|&sensor_type| {
+        if let Some(sensor) = manager.get_default_sensor(sensor_type) {
+            sensors.sensors.push((sensor_type, sensor));
+        }
     });
 
-    // sensors.manager = Some(manager);
-    sensors.queue = Some(queue);
+    *fallbacks = SensorFallbacks::for_capabilities(&capabilities);
+    if fallbacks.is_degraded() {
+        warn!("Running in degraded mode: {:?}", fallbacks);
+        config.set_enabled(SensorType::RawAccelerometer, true);
+    }
+
+    // the event queue is created once the session starts running
+    sensors.manager = Some(manager);
 }
 
-fn handle_lifetime(mut lifetime_events: EventReader<AppLifecycle>, sensors: NonSend<Sensors>) {
+#[cfg(target_os = "android")]
+fn handle_lifetime(
+    mut lifetime_events: EventReader<AppLifecycle>,
+    mut sensors: NonSendMut<Sensors<SensorManager>>,
+    config: Res<SensorConfig>,
+    mut session_changes: EventWriter<SensorSessionChanged>,
+    mut failures: EventWriter<SensorFailure>,
+) {
     for event in lifetime_events.read() {
-        match event {
-            AppLifecycle::Idle => sensors.disable(),
-            AppLifecycle::Running => sensors.enable(),
-            AppLifecycle::WillSuspend => sensors.disable(),
-            AppLifecycle::Suspended => sensors.disable(),
-            AppLifecycle::WillResume => sensors.enable(),
+        if let Some((change, transition_failures)) =
+            sensors.transition(SensorSession::for_lifecycle(event), &config)
+        {
+            info!(
+                "Sensor session: {:?} -> {:?}",
+                change.previous, change.session
+            );
+            session_changes.write(change);
+            failures.write_batch(transition_failures);
         }
     }
 }
 
-fn update_sensor_data(sensors: NonSend<Sensors>, mut sensor_data: ResMut<SensorData>) {
-    let events = sensors.get_events();
-    screen_print!("Sensor queue length: {}", &events.len());
-    events.iter().for_each(|event| {
-        sensor_data.add_event(event.clone());
-    });
+#[cfg(target_os = "android")]
+fn apply_sensor_config(
+    mut sensors: NonSendMut<Sensors<SensorManager>>,
+    mut sensor_data: ResMut<SensorData>,
+    config: Res<SensorConfig>,
+    mut failures: EventWriter<SensorFailure>,
+) {
+    if !config.is_changed() {
+        return;
+    }
+    sensor_data.cover_report_latency(config.max_report_latency_us());
+    if !config.is_added() {
+        failures.write_batch(sensors.apply(&config));
+    }
+}
+
+#[cfg(target_os = "android")]
+fn update_sensor_data(
+    mut sensors: NonSendMut<Sensors<SensorManager>>,
+    mut sensor_data: ResMut<SensorData>,
+    mut fallbacks: ResMut<SensorFallbacks>,
+    mut accuracies: ResMut<SensorAccuracies>,
+    mut accuracy_changes: EventWriter<SensorAccuracyChanged>,
+    mut clock: ResMut<SensorClock>,
+    mut diagnostics: Diagnostics,
+    mut failures: EventWriter<SensorFailure>,
+) {
+    let mut events = match sensors.get_events() {
+        Ok(events) => events,
+        Err(failure) => {
+            failures.write(failure);
+            return;
+        }
+    };
+    if let Some(newest) = events.iter().map(|event| event.timestamp).max() {
+        let now = clock.app_time_now();
+        clock.observe(now, newest);
+    }
+    // Batched sensors flush their FIFOs independently, so a read can hold a burst of
+    // old samples from one sensor after newer ones from another
+    events.sort_by_key(|event| event.timestamp);
+    diagnostics.add_measurement(&QUEUE_LENGTH, || events.len() as f64);
+    let mut rejected = 0;
+    for event in &events {
+        if let Some(change) = accuracies.update(event) {
+            accuracy_changes.write(change);
+        }
+        let derived = fallbacks.derive(event, &sensor_data);
+        let outcome = sensor_data.add_event(event.clone());
+        for derived_event in derived {
+            sensor_data.add_event(derived_event);
+        }
+        if matches!(
+            outcome,
+            Some(SampleOutcome::Decimated | SampleOutcome::OutOfOrder)
+        ) {
+            rejected += 1;
+        }
+    }
+    if rejected > 0 {
+        debug!("Rejected {} of {} sensor samples", rejected, events.len());
+    }
+}
+
+fn track_sensor_failures(
+    mut failures: EventReader<SensorFailure>,
+    mut status: ResMut<SensorStatus>,
+) {
+    for failure in failures.read() {
+        error!("Sensor failure: {}", failure);
+        status.failure_count += 1;
+        status.last_failure = Some(failure.clone());
+    }
+}
+
+fn print_sensor_status(status: Res<SensorStatus>) {
+    if let Some(failure) = &status.last_failure {
+        screen_print!(
+            col: Color::srgb(1.0, 0.3, 0.3),
+            "Sensor errors: {} (last: {})",
+            status.failure_count,
+            failure
+        );
+    }
+}
+
+fn print_sensor_accuracy(
+    accuracies: Res<SensorAccuracies>,
+    mut accuracy_changes: EventReader<SensorAccuracyChanged>,
+) {
+    for change in accuracy_changes.read() {
+        info!(
+            "{:?} accuracy: {:?} -> {:?}",
+            change.sensor_type, change.previous, change.accuracy
+        );
+    }
+
+    for sensor_type in SENSOR_TYPES {
+        let Some(accuracy) = accuracies.get(sensor_type) else {
+            continue;
+        };
+        let color = match accuracy {
+            SensorAccuracy::High => Color::srgb(0.3, 1.0, 0.3),
+            SensorAccuracy::Medium => Color::srgb(0.8, 1.0, 0.3),
+            SensorAccuracy::Low => Color::srgb(1.0, 0.7, 0.2),
+            SensorAccuracy::Unreliable | SensorAccuracy::NoContact => Color::srgb(1.0, 0.3, 0.3),
+            SensorAccuracy::Unknown => Color::srgb(0.7, 0.7, 0.7),
+        };
+        screen_print!(col: color, "{:?} accuracy: {:?}", sensor_type, accuracy);
+    }
+
+    let compass_needs_calibration = [SensorType::Compass, SensorType::MagneticField]
+        .into_iter()
+        .filter_map(|sensor_type| accuracies.get(sensor_type))
+        .any(|accuracy| matches!(accuracy, SensorAccuracy::Low | SensorAccuracy::Unreliable));
+    if compass_needs_calibration {
+        screen_print!(
+            col: Color::srgb(1.0, 0.7, 0.2),
+            "Compass needs calibration: wave the phone in a figure-eight"
+        );
+    }
+}
+
+fn synchronize_sensor_data(
+    sensor_data: Res<SensorData>,
+    mut synchronizer: ResMut<SensorSynchronizer>,
+    mut frames: EventWriter<SensorFrame>,
+) {
+    let Some(horizon) = sensor_data.common_horizon() else {
+        return;
+    };
+
+    for timestamp in synchronizer.pending(horizon) {
+        frames.write(sensor_data.frame_at(timestamp));
+    }
 }
 
-fn print_sensor_data(sensor_data: Res<SensorData>) {
+fn print_sensor_data(
+    sensor_data: Res<SensorData>,
+    fallbacks: Res<SensorFallbacks>,
+    clock: Res<SensorClock>,
+) {
+    if fallbacks.gravity_from_accelerometer {
+        screen_print!("Degraded: gravity estimated from accelerometer");
+    }
+    if fallbacks.linear_acceleration_from_accelerometer {
+        screen_print!("Degraded: linear acceleration estimated from accelerometer");
+    }
     screen_print!(
         "Accel: {:?}",
         sensor_data.accelerometer.latest().unwrap().values
@@ -235,4 +1623,530 @@ fn print_sensor_data(sensor_data: Res<SensorData>) {
         "Gravity: {:?}",
         sensor_data.gravity.latest().unwrap().values
     );
+    screen_print!(
+        "Pressure: {:?}",
+        sensor_data.pressure.latest().unwrap().values
+    );
+    screen_print!("Accel samples: {:?}", sensor_data.accelerometer.stats());
+    if let (Some(latency), Some(drift)) = (clock.latency_ms(), clock.drift_ppm()) {
+        screen_print!("Sensor latency: {:.1} ms, drift: {:.0} ppm", latency, drift);
+    }
+}
+
+fn print_sensor_rates(sensor_data: Res<SensorData>, config: Res<SensorConfig>) {
+    for sensor_type in SENSOR_TYPES {
+        let settings = config.get(sensor_type);
+        let measured = sensor_data
+            .series(sensor_type)
+            .and_then(SensorDataSeries::measured_rate);
+        match (settings.enabled, measured) {
+            (false, _) => screen_print!("{:?} rate: disabled", sensor_type),
+            (true, Some(measured)) => screen_print!(
+                "{:?} rate: {:.1} Hz (requested {:.1} Hz)",
+                sensor_type,
+                measured,
+                settings.rate_hz
+            ),
+            (true, None) => screen_print!(
+                "{:?} rate: - (requested {:.1} Hz)",
+                sensor_type,
+                settings.rate_hz
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    /// A call made on the fake backend, sensors are identified by their type
+    #[derive(Clone, Debug, PartialEq)]
+    enum Call {
+        CreateQueue,
+        Enable(SensorType, i32, i64),
+        SetEventRate(SensorType, i32),
+        Disable(SensorType),
+        Destroy,
+    }
+
+    /// Records every call and fails those about the sensors in `failing`
+    #[derive(Clone, Default)]
+    struct FakeBackend {
+        calls: Rc<RefCell<Vec<Call>>>,
+        failing: Rc<RefCell<HashSet<SensorType>>>,
+    }
+
+    impl FakeBackend {
+        fn take_calls(&self) -> Vec<Call> {
+            self.calls.take()
+        }
+
+        fn record(&self, sensor: &SensorType, call: Call) -> Result<(), SensorError> {
+            self.calls.borrow_mut().push(call);
+            if self.failing.borrow().contains(sensor) {
+                Err(SensorError::EnableFailed(-22))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl SensorBackend for FakeBackend {
+        type Sensor = SensorType;
+        type Queue = FakeBackend;
+
+        fn create_event_queue(&self) -> Result<FakeBackend, SensorError> {
+            self.calls.borrow_mut().push(Call::CreateQueue);
+            Ok(self.clone())
+        }
+    }
+
+    impl SensorQueue for FakeBackend {
+        type Sensor = SensorType;
+
+        fn enable_sensor(
+            &self,
+            sensor: &SensorType,
+            sampling_period_us: i32,
+            max_report_latency_us: i64,
+        ) -> Result<(), SensorError> {
+            self.record(
+                sensor,
+                Call::Enable(*sensor, sampling_period_us, max_report_latency_us),
+            )
+        }
+
+        fn set_event_rate(
+            &self,
+            sensor: &SensorType,
+            sampling_period_us: i32,
+        ) -> Result<(), SensorError> {
+            self.record(sensor, Call::SetEventRate(*sensor, sampling_period_us))
+        }
+
+        fn disable_sensor(&self, sensor: &SensorType) -> Result<(), SensorError> {
+            self.record(sensor, Call::Disable(*sensor))
+        }
+
+        fn get_events(&mut self) -> Result<Vec<SensorEvent>, SensorError> {
+            Ok(Vec::new())
+        }
+
+        fn destroy(self) -> Result<(), SensorError> {
+            self.calls.borrow_mut().push(Call::Destroy);
+            Ok(())
+        }
+    }
+
+    const MS: i64 = 1_000_000; // nanoseconds
+
+    fn scalar(timestamp: i64, value: f32) -> SensorEvent {
+        SensorEvent {
+            accuracy: SensorAccuracy::High,
+            sensor_type: SensorType::Pressure,
+            timestamp,
+            values: SensorValues::Scalar(value),
+        }
+    }
+
+    /// A series that stores samples as they come, without low-pass filtering them
+    fn unfiltered(retention: SeriesRetention) -> SensorDataSeries {
+        let mut series = SensorDataSeries::new(retention);
+        series.set_low_pass(f32::INFINITY, 50.0);
+        series
+    }
+
+    fn timestamps<'a>(events: impl Iterator<Item = &'a SensorEvent>) -> Vec<i64> {
+        events.map(|event| event.timestamp).collect()
+    }
+
+    fn fake_sensors(backend: &FakeBackend, sensor_types: &[SensorType]) -> Sensors<FakeBackend> {
+        Sensors {
+            manager: Some(backend.clone()),
+            sensors: sensor_types
+                .iter()
+                .map(|&sensor_type| (sensor_type, sensor_type))
+                .collect(),
+            ..default()
+        }
+    }
+
+    #[test]
+    fn transition_enables_configured_sensors_once() {
+        let backend = FakeBackend::default();
+        let mut sensors = fake_sensors(
+            &backend,
+            &[
+                SensorType::Accelerometer,
+                SensorType::Gyroscope,
+                SensorType::MagneticField,
+            ],
+        );
+        let config = SensorConfig::default();
+
+        let (change, failures) = sensors.transition(SensorSession::Running, &config).unwrap();
+        assert_eq!(change.previous, SensorSession::Stopped);
+        assert_eq!(change.session, SensorSession::Running);
+        assert!(failures.is_empty());
+        // the magnetometer is not enabled by default
+        assert_eq!(
+            backend.take_calls(),
+            vec![
+                Call::CreateQueue,
+                Call::Enable(SensorType::Accelerometer, 20_000, 0),
+                Call::Enable(SensorType::Gyroscope, 20_000, 0),
+            ]
+        );
+
+        assert!(
+            sensors
+                .transition(SensorSession::Running, &config)
+                .is_none()
+        );
+        assert!(backend.take_calls().is_empty());
+    }
+
+    #[test]
+    fn suspend_disables_sensors_and_resume_enables_them_again() {
+        let backend = FakeBackend::default();
+        let mut sensors = fake_sensors(
+            &backend,
+            &[SensorType::Accelerometer, SensorType::Gyroscope],
+        );
+        let config = SensorConfig::default();
+        sensors.transition(SensorSession::Running, &config);
+        backend.take_calls();
+
+        let (change, failures) = sensors
+            .transition(SensorSession::Suspended, &config)
+            .unwrap();
+        assert_eq!(change.previous, SensorSession::Running);
+        assert!(failures.is_empty());
+        assert_eq!(
+            backend.take_calls(),
+            vec![
+                Call::Disable(SensorType::Accelerometer),
+                Call::Disable(SensorType::Gyroscope),
+                Call::Destroy,
+            ]
+        );
+        assert!(sensors.queue.is_none());
+        assert!(sensors.enabled.is_empty());
+
+        sensors.transition(SensorSession::Running, &config);
+        assert_eq!(
+            backend.take_calls(),
+            vec![
+                Call::CreateQueue,
+                Call::Enable(SensorType::Accelerometer, 20_000, 0),
+                Call::Enable(SensorType::Gyroscope, 20_000, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_only_touches_changed_sensors() {
+        let backend = FakeBackend::default();
+        let mut sensors = fake_sensors(
+            &backend,
+            &[
+                SensorType::Accelerometer,
+                SensorType::Gyroscope,
+                SensorType::Rotation,
+                SensorType::Pressure,
+            ],
+        );
+        let mut config = SensorConfig::default();
+        sensors.transition(SensorSession::Running, &config);
+        backend.take_calls();
+
+        config.set_rate(SensorType::Accelerometer, 100.0);
+        config.set_max_report_latency(SensorType::Gyroscope, 100_000);
+        config.set_enabled(SensorType::Rotation, false);
+        assert!(sensors.apply(&config).is_empty());
+        assert_eq!(
+            backend.take_calls(),
+            vec![
+                Call::SetEventRate(SensorType::Accelerometer, 10_000),
+                // a new report latency needs the sensor registered again
+                Call::Disable(SensorType::Gyroscope),
+                Call::Enable(SensorType::Gyroscope, 20_000, 100_000),
+                Call::Disable(SensorType::Rotation),
+            ]
+        );
+        assert_eq!(sensors.enabled[&SensorType::Accelerometer].rate_hz, 100.0);
+        assert!(!sensors.enabled.contains_key(&SensorType::Rotation));
+
+        assert!(sensors.apply(&config).is_empty());
+        assert!(backend.take_calls().is_empty());
+    }
+
+    #[test]
+    fn apply_outside_a_running_session_does_nothing() {
+        let backend = FakeBackend::default();
+        let mut sensors = fake_sensors(&backend, &[SensorType::Accelerometer]);
+
+        assert!(sensors.apply(&SensorConfig::default()).is_empty());
+        assert!(backend.take_calls().is_empty());
+        assert!(sensors.enabled.is_empty());
+    }
+
+    #[test]
+    fn failed_sensor_is_reported_and_retried() {
+        let backend = FakeBackend::default();
+        backend.failing.borrow_mut().insert(SensorType::Gyroscope);
+        let mut sensors = fake_sensors(
+            &backend,
+            &[SensorType::Accelerometer, SensorType::Gyroscope],
+        );
+        let config = SensorConfig::default();
+
+        let (_, failures) = sensors.transition(SensorSession::Running, &config).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].sensor_type, Some(SensorType::Gyroscope));
+        assert!(sensors.enabled.contains_key(&SensorType::Accelerometer));
+        assert!(!sensors.enabled.contains_key(&SensorType::Gyroscope));
+        backend.take_calls();
+
+        backend.failing.borrow_mut().clear();
+        assert!(sensors.apply(&config).is_empty());
+        assert_eq!(
+            backend.take_calls(),
+            vec![Call::Enable(SensorType::Gyroscope, 20_000, 0)]
+        );
+        assert!(sensors.enabled.contains_key(&SensorType::Gyroscope));
+    }
+
+    #[test]
+    fn disable_skips_sensors_that_never_came_up() {
+        let backend = FakeBackend::default();
+        backend.failing.borrow_mut().insert(SensorType::Gyroscope);
+        let mut sensors = fake_sensors(
+            &backend,
+            &[SensorType::Accelerometer, SensorType::Gyroscope],
+        );
+        let config = SensorConfig::default();
+        sensors.transition(SensorSession::Running, &config);
+        backend.take_calls();
+
+        let (_, failures) = sensors
+            .transition(SensorSession::Suspended, &config)
+            .unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(
+            backend.take_calls(),
+            vec![Call::Disable(SensorType::Accelerometer), Call::Destroy]
+        );
+    }
+
+    #[test]
+    fn sample_retention_keeps_the_newest_samples() {
+        let mut series = unfiltered(SeriesRetention::Samples(3));
+        for t in 1..=5 {
+            series.add(scalar(t * MS, t as f32));
+        }
+
+        assert_eq!(timestamps(series.iter()), vec![3 * MS, 4 * MS, 5 * MS]);
+        // the placeholder the series starts with does not count
+        assert_eq!(series.stats().expired, 2);
+    }
+
+    #[test]
+    fn window_retention_keeps_samples_within_the_window() {
+        let mut series = unfiltered(SeriesRetention::Window(2 * MS));
+        for t in 1..=5 {
+            series.add(scalar(t * MS, t as f32));
+        }
+
+        assert_eq!(timestamps(series.iter()), vec![3 * MS, 4 * MS, 5 * MS]);
+        assert_eq!(series.span(), 2 * MS);
+
+        // the latest sample stays, however narrow the window
+        series.set_retention(SeriesRetention::Window(0));
+        assert_eq!(timestamps(series.iter()), vec![5 * MS]);
+    }
+
+    #[test]
+    fn range_includes_both_bounds() {
+        let mut series = unfiltered(SeriesRetention::Samples(10));
+        for t in 1..=5 {
+            series.add(scalar(t * MS, t as f32));
+        }
+
+        assert_eq!(
+            timestamps(series.range(2 * MS, 4 * MS)),
+            vec![2 * MS, 3 * MS, 4 * MS]
+        );
+        assert_eq!(
+            timestamps(series.range(2 * MS + 1, 4 * MS - 1)),
+            vec![3 * MS]
+        );
+        assert_eq!(timestamps(series.range(5 * MS, 9 * MS)), vec![5 * MS]);
+        assert!(series.range(6 * MS, 9 * MS).next().is_none());
+        assert!(series.range(4 * MS, 2 * MS).next().is_none());
+    }
+
+    #[test]
+    fn since_excludes_the_cursor() {
+        let mut series = unfiltered(SeriesRetention::Samples(10));
+        for t in 1..=5 {
+            series.add(scalar(t * MS, t as f32));
+        }
+
+        assert_eq!(timestamps(series.since(3 * MS)), vec![4 * MS, 5 * MS]);
+        assert_eq!(
+            timestamps(series.since(3 * MS - 1)),
+            vec![3 * MS, 4 * MS, 5 * MS]
+        );
+        // the placeholder sits at timestamp 0
+        assert_eq!(series.since(0).count(), 5);
+        assert!(series.since(5 * MS).next().is_none());
+    }
+
+    fn vec3(sensor_type: SensorType, timestamp: i64, value: Vec3) -> SensorEvent {
+        SensorEvent {
+            sensor_type,
+            values: SensorValues::Vec3(value),
+            ..scalar(timestamp, 0.0)
+        }
+    }
+
+    #[test]
+    fn sample_at_interpolates_between_neighbours() {
+        let mut series = unfiltered(SeriesRetention::Samples(10));
+        series.add(vec3(SensorType::Gyroscope, 10 * MS, Vec3::ZERO));
+        series.add(vec3(
+            SensorType::Gyroscope,
+            20 * MS,
+            Vec3::new(1.0, -2.0, 4.0),
+        ));
+
+        assert_eq!(series.vec3_at(10 * MS), Some(Vec3::ZERO));
+        assert_eq!(series.vec3_at(15 * MS), Some(Vec3::new(0.5, -1.0, 2.0)));
+        assert_eq!(series.vec3_at(20 * MS), Some(Vec3::new(1.0, -2.0, 4.0)));
+        // nothing is extrapolated, nor interpolated from the placeholder
+        assert_eq!(series.vec3_at(25 * MS), None);
+        assert_eq!(series.vec3_at(5 * MS), None);
+    }
+
+    #[test]
+    fn sample_at_slerps_quaternions() {
+        let mut series = unfiltered(SeriesRetention::Samples(10));
+        let event = |timestamp, rotation| SensorEvent {
+            sensor_type: SensorType::Rotation,
+            values: SensorValues::Quat(rotation),
+            ..scalar(timestamp, 0.0)
+        };
+        series.add(event(10 * MS, Quat::IDENTITY));
+        series.add(event(20 * MS, Quat::from_rotation_z(1.0)));
+
+        let halfway = series.quat_at(15 * MS).unwrap();
+        assert!(halfway.angle_between(Quat::from_rotation_z(0.5)) < 1e-5);
+    }
+
+    #[test]
+    fn trusted_sample_at_skips_unreliable_samples() {
+        let mut series = unfiltered(SeriesRetention::Samples(10));
+        series.add(vec3(SensorType::MagneticField, 10 * MS, Vec3::X));
+        series.add(SensorEvent {
+            accuracy: SensorAccuracy::Unreliable,
+            ..vec3(SensorType::MagneticField, 20 * MS, Vec3::Y)
+        });
+        series.add(vec3(SensorType::MagneticField, 30 * MS, Vec3::Z));
+
+        assert!(series.trusted_sample_at(15 * MS).is_some());
+        assert!(series.trusted_sample_at(25 * MS).is_none());
+        assert!(series.trusted_sample_at(30 * MS).is_some());
+    }
+
+    #[test]
+    fn frame_at_resamples_every_sensor() {
+        let mut sensor_data = SensorData::new(SeriesRetention::Samples(10));
+        sensor_data.set_low_pass(f32::INFINITY, |_| 50.0);
+        sensor_data.add_event(vec3(SensorType::Gyroscope, 10 * MS, Vec3::ZERO));
+        sensor_data.add_event(vec3(SensorType::Gyroscope, 30 * MS, Vec3::X));
+        sensor_data.add_event(vec3(SensorType::Accelerometer, 12 * MS, Vec3::Z));
+        sensor_data.add_event(vec3(SensorType::Accelerometer, 22 * MS, Vec3::Z));
+
+        // the accelerometer is behind, so it bounds what can be resampled
+        assert_eq!(sensor_data.common_horizon(), Some(22 * MS));
+        let frame = sensor_data.frame_at(20 * MS);
+        assert_eq!(frame.gyroscope, Some(Vec3::new(0.5, 0.0, 0.0)));
+        assert_eq!(frame.accelerometer, Some(Vec3::Z));
+        assert_eq!(frame.rotation, None);
+    }
+
+    #[test]
+    fn synchronizer_emits_each_frame_once() {
+        let mut synchronizer = SensorSynchronizer::new(10 * MS);
+
+        // the first frame is aligned to the period
+        assert_eq!(synchronizer.pending(25 * MS), vec![20 * MS]);
+        assert_eq!(synchronizer.pending(29 * MS), vec![]);
+        assert_eq!(
+            synchronizer.pending(51 * MS),
+            vec![30 * MS, 40 * MS, 50 * MS]
+        );
+        assert_eq!(synchronizer.pending(51 * MS), vec![]);
+    }
+
+    #[test]
+    fn synchronizer_skips_a_long_backlog() {
+        let mut synchronizer = SensorSynchronizer::new(10 * MS);
+        synchronizer.pending(20 * MS);
+
+        assert_eq!(synchronizer.pending(2_035 * MS), vec![2_030 * MS]);
+        assert_eq!(synchronizer.pending(2_040 * MS), vec![2_040 * MS]);
+    }
+
+    #[test]
+    fn decimator_rejects_samples_closer_than_its_interval() {
+        let decimator = Decimator::from_rate(100.0);
+        assert_eq!(decimator.min_interval, 10 * MS);
+        assert!(decimator.accepts(1_010 * MS, 1_000 * MS));
+        assert!(!decimator.accepts(1_009 * MS, 1_000 * MS));
+        assert!(Decimator::disabled().accepts(1_000 * MS + 1, 1_000 * MS));
+    }
+
+    #[test]
+    fn add_reports_what_happened_to_each_sample() {
+        let mut series = unfiltered(SeriesRetention::Samples(10));
+        series.set_decimator(Decimator::from_rate(100.0));
+
+        assert_eq!(series.add(scalar(1_000 * MS, 1.0)), SampleOutcome::Accepted);
+        assert_eq!(
+            series.add(scalar(1_005 * MS, 2.0)),
+            SampleOutcome::Decimated
+        );
+        assert_eq!(series.add(scalar(1_010 * MS, 3.0)), SampleOutcome::Accepted);
+        assert_eq!(
+            series.add(scalar(1_010 * MS, 4.0)),
+            SampleOutcome::OutOfOrder
+        );
+        assert_eq!(series.add(scalar(990 * MS, 5.0)), SampleOutcome::OutOfOrder);
+
+        let stats = series.stats();
+        assert_eq!(stats.accepted, 2);
+        assert_eq!(stats.decimated, 1);
+        assert_eq!(stats.out_of_order, 2);
+        assert_eq!(stats.rejected(), 3);
+        assert_eq!(timestamps(series.since(0)), vec![1_000 * MS, 1_010 * MS]);
+    }
+
+    #[test]
+    fn decimator_is_set_per_sensor() {
+        let mut sensor_data = SensorData::new(SeriesRetention::Samples(10));
+        sensor_data.set_decimator(SensorType::Gyroscope, Decimator::from_rate(50.0));
+
+        for t in [1_000, 1_010, 1_020] {
+            sensor_data.add_event(vec3(SensorType::Gyroscope, t * MS, Vec3::X));
+            sensor_data.add_event(vec3(SensorType::Accelerometer, t * MS, Vec3::Z));
+        }
+        assert_eq!(sensor_data.gyroscope.stats().accepted, 2);
+        assert_eq!(sensor_data.accelerometer.stats().accepted, 3);
+    }
 }
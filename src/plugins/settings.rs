@@ -0,0 +1,353 @@
+use bevy::prelude::*;
+
+use super::config::Config;
+use super::state::GapPolicy;
+
+/// On-screen panel to tune the [`Config`] at runtime, which applies and saves the changes
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, spawn_settings_panel).add_systems(
+            Update,
+            (
+                (toggle_settings_panel, step_settings, highlight_buttons),
+                show_settings.run_if(resource_changed::<Config>),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// One row of the settings panel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SettingField {
+    GapPolicy,
+    LowPassCutoff,
+    /// Rate of the sensors without an entry of their own in `sensors.rates`, which the
+    /// panel leaves as they are
+    SensorRate,
+    History,
+    AccelerationNoise,
+    SpeedNoise,
+    TrailLength,
+    DragSensitivity,
+    ZoomSensitivity,
+}
+
+impl SettingField {
+    const ALL: [SettingField; 9] = [
+        SettingField::GapPolicy,
+        SettingField::LowPassCutoff,
+        SettingField::SensorRate,
+        SettingField::History,
+        SettingField::AccelerationNoise,
+        SettingField::SpeedNoise,
+        SettingField::TrailLength,
+        SettingField::DragSensitivity,
+        SettingField::ZoomSensitivity,
+    ];
+
+    const GAP_POLICIES: [GapPolicy; 4] = [
+        GapPolicy::Rest,
+        GapPolicy::Reset,
+        GapPolicy::Coast,
+        GapPolicy::Invalidate,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            SettingField::GapPolicy => "Gap policy",
+            SettingField::LowPassCutoff => "Low-pass cutoff",
+            SettingField::SensorRate => "Default sensor rate",
+            SettingField::History => "Sensor history",
+            SettingField::AccelerationNoise => "Acceleration noise",
+            SettingField::SpeedNoise => "GNSS speed noise",
+            SettingField::TrailLength => "Trail length",
+            SettingField::DragSensitivity => "Drag sensitivity",
+            SettingField::ZoomSensitivity => "Zoom sensitivity",
+        }
+    }
+
+    /// The value as shown in the panel
+    fn display(&self, config: &Config) -> String {
+        match self {
+            SettingField::GapPolicy => format!("{:?}", config.estimator.gap_policy),
+            SettingField::LowPassCutoff => format!("{:.1} Hz", config.sensors.low_pass_cutoff_hz),
+            SettingField::SensorRate => format!("{:.0} Hz", config.sensors.rate_hz),
+            SettingField::History => format!("{:.0} s", config.sensors.history_seconds),
+            SettingField::AccelerationNoise => {
+                format!("{:.2} m/s²", config.estimator.acceleration_noise)
+            }
+            SettingField::SpeedNoise => format!("{:.2} m/s", config.estimator.speed_noise),
+            SettingField::TrailLength => format!("{}", config.visualization.trail_length),
+            SettingField::DragSensitivity => format!("{:.4}", config.touch.drag_sensitivity),
+            SettingField::ZoomSensitivity => format!("{:.3}", config.touch.zoom_sensitivity),
+        }
+    }
+
+    /// Moves the value `steps` steps up or down, keeping it in its range
+    fn step(&self, config: &mut Config, steps: i32) {
+        // noise and sensitivities span orders of magnitude, so they step by a factor
+        let scale = |value: f32, min: f32, max: f32| (value * 1.25f32.powi(steps)).clamp(min, max);
+        let add = |value: f32, step: f32, min: f32, max: f32| {
+            (value + step * steps as f32).clamp(min, max)
+        };
+        let sensors = &mut config.sensors;
+        let estimator = &mut config.estimator;
+        let touch = &mut config.touch;
+
+        match self {
+            SettingField::GapPolicy => {
+                let index = Self::GAP_POLICIES
+                    .iter()
+                    .position(|policy| *policy == estimator.gap_policy)
+                    .unwrap_or(0) as i32;
+                estimator.gap_policy = Self::GAP_POLICIES
+                    [(index + steps).rem_euclid(Self::GAP_POLICIES.len() as i32) as usize];
+            }
+            SettingField::LowPassCutoff => {
+                // above half the sample rate the filter passes everything anyway
+                let nyquist = (sensors.lowest_rate_hz() / 2.0).max(0.5);
+                sensors.low_pass_cutoff_hz = add(sensors.low_pass_cutoff_hz, 0.5, 0.5, nyquist);
+            }
+            SettingField::SensorRate => {
+                sensors.rate_hz = add(sensors.rate_hz, 10.0, 10.0, 400.0);
+                sensors.low_pass_cutoff_hz = sensors
+                    .low_pass_cutoff_hz
+                    .min(sensors.lowest_rate_hz() / 2.0);
+            }
+            SettingField::History => {
+                sensors.history_seconds = add(sensors.history_seconds, 1.0, 1.0, 60.0)
+            }
+            SettingField::AccelerationNoise => {
+                estimator.acceleration_noise = scale(estimator.acceleration_noise, 0.01, 10.0)
+            }
+            SettingField::SpeedNoise => {
+                estimator.speed_noise = scale(estimator.speed_noise, 0.01, 10.0)
+            }
+            SettingField::TrailLength => {
+                let trail_length = &mut config.visualization.trail_length;
+                *trail_length =
+                    (*trail_length as i64 + 100 * steps as i64).clamp(0, 10_000) as usize
+            }
+            SettingField::DragSensitivity => {
+                touch.drag_sensitivity = scale(touch.drag_sensitivity, 0.0005, 0.05)
+            }
+            SettingField::ZoomSensitivity => {
+                touch.zoom_sensitivity = scale(touch.zoom_sensitivity, 0.005, 0.5)
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+struct SettingsToggle;
+
+#[derive(Component)]
+struct SettingsPanel;
+
+#[derive(Component)]
+struct StepButton {
+    field: SettingField,
+    steps: i32,
+}
+
+#[derive(Component)]
+struct SettingValue(SettingField);
+
+const BUTTON_COLOR: Color = Color::srgba(0.2, 0.2, 0.2, 0.9);
+const PRESSED_COLOR: Color = Color::srgba(0.4, 0.4, 0.4, 0.9);
+const FONT_SIZE: f32 = 26.0;
+
+/// Large enough to hit with a finger
+fn step_button(field: SettingField, steps: i32, label: &str) -> impl Bundle {
+    (
+        Button,
+        StepButton { field, steps },
+        Node {
+            width: Val::Px(72.0),
+            height: Val::Px(72.0),
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..default()
+        },
+        BackgroundColor(BUTTON_COLOR),
+        children![(Text::new(label), TextFont::from_font_size(FONT_SIZE * 1.5),)],
+    )
+}
+
+fn spawn_settings_panel(mut commands: Commands) {
+    commands.spawn((
+        Button,
+        SettingsToggle,
+        Node {
+            position_type: PositionType::Absolute,
+            right: Val::Px(16.0),
+            bottom: Val::Px(16.0),
+            padding: UiRect::all(Val::Px(20.0)),
+            ..default()
+        },
+        BackgroundColor(BUTTON_COLOR),
+        children![(Text::new("Settings"), TextFont::from_font_size(FONT_SIZE))],
+    ));
+
+    commands
+        .spawn((
+            SettingsPanel,
+            Node {
+                display: Display::None,
+                position_type: PositionType::Absolute,
+                right: Val::Px(16.0),
+                bottom: Val::Px(112.0),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(8.0),
+                padding: UiRect::all(Val::Px(16.0)),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(0.05, 0.05, 0.05, 0.85)),
+        ))
+        .with_children(|panel| {
+            for field in SettingField::ALL {
+                panel
+                    .spawn(Node {
+                        align_items: AlignItems::Center,
+                        column_gap: Val::Px(12.0),
+                        ..default()
+                    })
+                    .with_children(|row| {
+                        row.spawn((
+                            Text::new(field.label()),
+                            TextFont::from_font_size(FONT_SIZE),
+                            Node {
+                                width: Val::Px(280.0),
+                                ..default()
+                            },
+                        ));
+                        row.spawn(step_button(field, -1, "-"));
+                        row.spawn((
+                            Text::new(""),
+                            TextFont::from_font_size(FONT_SIZE),
+                            TextLayout::new_with_justify(JustifyText::Center),
+                            SettingValue(field),
+                            Node {
+                                width: Val::Px(160.0),
+                                ..default()
+                            },
+                        ));
+                        row.spawn(step_button(field, 1, "+"));
+                    });
+            }
+        });
+}
+
+fn toggle_settings_panel(
+    toggles: Query<&Interaction, (Changed<Interaction>, With<SettingsToggle>)>,
+    mut panels: Query<&mut Node, With<SettingsPanel>>,
+) {
+    if !toggles
+        .iter()
+        .any(|interaction| *interaction == Interaction::Pressed)
+    {
+        return;
+    }
+    for mut panel in &mut panels {
+        panel.display = match panel.display {
+            Display::None => Display::Flex,
+            _ => Display::None,
+        };
+    }
+}
+
+fn step_settings(
+    buttons: Query<(&Interaction, &StepButton), Changed<Interaction>>,
+    mut config: ResMut<Config>,
+) {
+    for (interaction, button) in &buttons {
+        if *interaction == Interaction::Pressed {
+            button.field.step(&mut config, button.steps);
+        }
+    }
+}
+
+fn highlight_buttons(
+    mut buttons: Query<(&Interaction, &mut BackgroundColor), (Changed<Interaction>, With<Button>)>,
+) {
+    for (interaction, mut color) in &mut buttons {
+        color.0 = match interaction {
+            Interaction::Pressed => PRESSED_COLOR,
+            _ => BUTTON_COLOR,
+        };
+    }
+}
+
+fn show_settings(config: Res<Config>, mut values: Query<(&mut Text, &SettingValue)>) {
+    for (mut text, SettingValue(field)) in &mut values {
+        text.0 = field.display(&config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::event::SensorType;
+    use crate::plugins::config::SensorRate;
+    use bevy::ecs::system::RunSystemOnce;
+
+    #[test]
+    fn stepping_keeps_per_sensor_rates() {
+        let mut config = Config::default();
+        config.sensors.rates = vec![SensorRate {
+            sensor: SensorType::Pressure,
+            rate_hz: 10.0,
+        }];
+        let rates = config.sensors.rates.clone();
+
+        for field in SettingField::ALL {
+            field.step(&mut config, 3);
+            field.step(&mut config, -1);
+        }
+        assert_eq!(config.sensors.rates, rates);
+        assert_eq!(config.sensors.rate_hz, 70.0);
+        assert_eq!(config.validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn gap_policy_cycles_through_every_policy() {
+        let mut config = Config::default();
+        let mut seen = Vec::new();
+        for _ in 0..SettingField::GAP_POLICIES.len() {
+            seen.push(config.estimator.gap_policy);
+            SettingField::GapPolicy.step(&mut config, 1);
+        }
+        assert_eq!(seen, SettingField::GAP_POLICIES);
+        assert_eq!(config.estimator.gap_policy, GapPolicy::Rest);
+
+        SettingField::GapPolicy.step(&mut config, -1);
+        assert_eq!(config.estimator.gap_policy, GapPolicy::Invalidate);
+        assert_eq!(SettingField::GapPolicy.display(&config), "Invalidate");
+    }
+
+    #[test]
+    fn panel_shows_the_loaded_config() {
+        let mut config = Config::default();
+        config.sensors.rate_hz = 120.0;
+        config.visualization.trail_length = 1234;
+
+        let mut world = World::new();
+        world.insert_resource(config);
+        world.run_system_once(spawn_settings_panel).unwrap();
+        world.run_system_once(show_settings).unwrap();
+
+        let mut values = world.query::<(&Text, &SettingValue)>();
+        let mut shown = |field| {
+            values
+                .iter(&world)
+                .find(|(_, value)| value.0 == field)
+                .map(|(text, _)| text.0.clone())
+                .unwrap()
+        };
+        assert_eq!(shown(SettingField::SensorRate), "120 Hz");
+        assert_eq!(shown(SettingField::TrailLength), "1234");
+    }
+}
@@ -1,60 +1,824 @@
 use bevy::prelude::*;
 use bevy_debug_text_overlay::screen_print;
+use serde::{Deserialize, Serialize};
+#[cfg(target_os = "android")]
+use std::{fs, io, path::PathBuf};
 
+use super::sensor::{SensorData, SensorDataSeries};
 #[cfg(target_os = "android")]
-use super::sensor::SensorData;
+use super::sensor::{SensorSession, SensorSessionChanged};
+use crate::ffi::event::{SensorType, SensorValues};
+use crate::geodetic::{Geodetic, LocalFrame};
 
 pub struct StatePlugin;
 
 impl Plugin for StatePlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(StateVector::default());
+        // the estimator idles on an empty SensorData until something feeds it
+        app.insert_resource(StateVector::default())
+            .init_resource::<EstimatorConfig>()
+            .init_resource::<GeodeticOrigin>()
+            .init_resource::<SensorData>()
+            .init_resource::<Integrator>()
+            .init_resource::<Barometer>()
+            .add_systems(Update, update_state_vector);
 
         #[cfg(target_os = "android")]
-        app.add_systems(Update, update_state_vector);
+        app.add_systems(Startup, restore_estimate)
+            .add_systems(Update, save_estimate_on_suspend);
         app.add_systems(PostUpdate, print_state);
     }
 }
 
+/// What the estimator does when a sensor stream resumes after more than
+/// [`EstimatorConfig::max_gap`] without samples, e.g. after the app was suspended
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapPolicy {
+    /// Keep position and orientation, but assume the device came to rest: the
+    /// velocity is zeroed
+    #[default]
+    Rest,
+    /// Start over with [`StateVector::reset`], waiting to be aligned again
+    Reset,
+    /// Carry on across the gap at the last known velocity
+    Coast,
+    /// Keep integrating, but flag the estimate as invalid until it is reset
+    Invalidate,
+}
+
+#[derive(Clone, Debug, PartialEq, Resource)]
+pub struct EstimatorConfig {
+    pub gap_policy: GapPolicy,
+    /// Longest time between two samples of a stream that is still integrated, in seconds
+    pub max_gap: f32,
+}
+
+impl Default for EstimatorConfig {
+    fn default() -> Self {
+        Self {
+            gap_policy: GapPolicy::default(),
+            max_gap: 0.5,
+        }
+    }
+}
+
+/// Estimate in the local frame, whose axes point east, north and up like the world
+/// frame of Android's rotation vectors. [`GeodeticOrigin`] anchors it on the globe.
+/// The orientation only refers to that frame once it [`is_aligned`](Self::is_aligned),
+/// until then it is relative to how the device was held when the estimate started.
 #[derive(Debug, Default, Resource)]
 pub struct StateVector {
     position: Vec3,
     velocity: Vec3,
     orientation: Quat,
     rotation: Quat,
+    invalid: bool,
+    aligned: bool,
+}
+
+impl StateVector {
+    /// East, north, up in metres from the origin
+    pub fn position(&self) -> Vec3 {
+        self.position
+    }
+
+    pub fn velocity(&self) -> Vec3 {
+        self.velocity
+    }
+
+    pub fn orientation(&self) -> Quat {
+        self.orientation
+    }
+
+    /// Degrees clockwise from north the top of the device points to, in `0..360`.
+    /// `None` until the orientation [is aligned](Self::is_aligned), as only then is it
+    /// north-referenced.
+    pub fn heading(&self) -> Option<f32> {
+        let top = self.orientation * Vec3::Y;
+        self.aligned
+            .then(|| top.x.atan2(top.y).to_degrees().rem_euclid(360.0))
+    }
+
+    /// Whether the orientation has been aligned with east, north and up by a rotation vector.
+    /// No acceleration is integrated before.
+    pub fn is_aligned(&self) -> bool {
+        self.aligned
+    }
+
+    /// `false` once a gap was bridged under [`GapPolicy::Invalidate`]
+    pub fn is_valid(&self) -> bool {
+        !self.invalid
+    }
+
+    /// Starts over at the origin, at rest and valid, waiting to be aligned again
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Moves the position towards a `measured` one, per axis by `gain`
+    /// from 0 (keep the estimate) to 1 (take the measurement)
+    pub fn correct_position(&mut self, measured: Vec3, gain: Vec3) {
+        self.position += (measured - self.position) * gain;
+    }
+
+    /// Replaces the estimate, e.g. with one played back from a recording.
+    /// `orientation` must be in the local frame, it counts as aligned.
+    pub fn set_pose(&mut self, position: Vec3, velocity: Vec3, orientation: Quat) {
+        self.position = position;
+        self.velocity = velocity;
+        self.orientation = orientation;
+        self.aligned = true;
+    }
+
+    /// Moves the velocity towards a `measured` one, like [`StateVector::correct_position`]
+    pub fn correct_velocity(&mut self, measured: Vec3, gain: Vec3) {
+        self.velocity += (measured - self.velocity) * gain;
+    }
+}
+
+/// Where the origin of the local frame is on the WGS84 ellipsoid. Without it, positions
+/// are only known relative to where the estimate started.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct GeodeticOrigin {
+    frame: Option<LocalFrame>,
+}
+
+impl GeodeticOrigin {
+    pub fn set(&mut self, origin: Geodetic) {
+        self.frame = Some(LocalFrame::new(origin));
+    }
+
+    pub fn clear(&mut self) {
+        self.frame = None;
+    }
+
+    pub fn frame(&self) -> Option<&LocalFrame> {
+        self.frame.as_ref()
+    }
+
+    /// Coordinates of a `position` in the local frame, if the origin is known
+    pub fn to_geodetic(&self, position: Vec3) -> Option<Geodetic> {
+        self.frame
+            .as_ref()
+            .map(|frame| frame.enu_to_geodetic(position.as_dvec3()))
+    }
+}
+
+impl StateVector {
+    /// Applies `policy` to a gap of `dt` seconds in the accelerometer stream
+    fn bridge_gap(&mut self, policy: GapPolicy, dt: f32) {
+        match policy {
+            GapPolicy::Rest => self.velocity = Vec3::ZERO,
+            GapPolicy::Reset => self.reset(),
+            GapPolicy::Coast => self.position += self.velocity * dt,
+            GapPolicy::Invalidate => self.invalid = true,
+        }
+    }
+
+    /// Trapezoidal integration of acceleration -> velocity -> position between two
+    /// accelerometer samples `dt` seconds apart. Accelerations are in the device frame.
+    fn integrate_acceleration(&mut self, accel_previous: Vec3, accel: Vec3, dt: f32) {
+        let velocity_previous = self.velocity;
+        let accel_world_previous = self.orientation * accel_previous;
+        let accel_world = self.orientation * accel;
+
+        self.velocity += (accel_world_previous + accel_world) * dt * 0.5;
+        self.position += (velocity_previous + self.velocity) * dt * 0.5;
+    }
+
+    /// Complementary filter gains pulling the vertical channel towards the barometric altitude,
+    /// in 1/s for the position and 1/s² for the velocity
+    const ALTITUDE_POSITION_GAIN: f32 = 1.0;
+    const ALTITUDE_VELOCITY_GAIN: f32 = 0.25;
+
+    /// Corrects the component of position and velocity along `up` with a barometric
+    /// `altitude` measured `dt` seconds after the previous one. Horizontal axes are untouched.
+    fn correct_altitude(&mut self, up: Vec3, altitude: f32, dt: f32) {
+        let error = altitude - self.position.dot(up);
+        self.position += up * error * (Self::ALTITUDE_POSITION_GAIN * dt).min(1.0);
+        self.velocity += up * error * Self::ALTITUDE_VELOCITY_GAIN * dt;
+    }
+
+    /// Rate at which the orientation is pulled towards the rotation vector, in 1/s. The
+    /// gyroscope follows quick turns, the rotation vector removes its drift and keeps north.
+    const ORIENTATION_GAIN: f32 = 0.5;
+
+    /// Corrects the orientation towards an `absolute` one in the local frame, such as
+    /// Android's rotation vector, measured `dt` seconds after the previous one and trusted
+    /// by `weight`. The first one aligns the orientation outright.
+    fn correct_orientation(&mut self, absolute: Quat, weight: f32, dt: f32) {
+        let absolute = absolute.normalize();
+        if !self.aligned {
+            self.orientation = absolute;
+            self.aligned = true;
+            return;
+        }
+        let gain = (Self::ORIENTATION_GAIN * weight * dt).min(1.0);
+        self.orientation = self.orientation.slerp(absolute, gain).normalize();
+    }
+
+    /// Trapezoidal integration of the body angular rate between two gyroscope samples
+    fn integrate_angular_rate(&mut self, rate_previous: Vec3, rate: Vec3, dt: f32) {
+        self.rotation = Quat::from_scaled_axis(rate * dt);
+        self.orientation = (self.orientation
+            * Quat::from_scaled_axis((rate_previous + rate) * dt * 0.5))
+        .normalize();
+    }
+}
+
+/// Relative altitude from static pressure, using the international barometric formula
+#[derive(Debug, Default, Resource)]
+pub struct Barometer {
+    /// Pressure in hPa and the altitude it was taken at, set from the first sample after a reset
+    reference: Option<(f32, f32)>,
+}
+
+impl Barometer {
+    /// Re-anchors the altitude on the next pressure sample, e.g. after the weather has changed.
+    /// The estimated altitude at that moment is kept, so the trajectory does not jump.
+    pub fn reset_reference(&mut self) {
+        self.reference = None;
+    }
+
+    /// Altitude for `pressure` hPa. Without a reference, `pressure` becomes the
+    /// reference at `current_altitude`.
+    fn altitude(&mut self, pressure: f32, current_altitude: f32) -> f32 {
+        let (reference_pressure, reference_altitude) =
+            *self.reference.get_or_insert((pressure, current_altitude));
+        reference_altitude + 44_330.0 * (1.0 - (pressure / reference_pressure).powf(1.0 / 5.255))
+    }
+}
+
+/// Last sample integrated from each stream, so every sample is stepped exactly once
+/// no matter how many of them arrive per frame
+#[derive(Debug, Default, Resource)]
+struct Integrator {
+    accelerometer: Option<(i64, Vec3)>,
+    gyroscope: Option<(i64, Vec3)>,
+    pressure: Option<i64>,
+    /// Last sample of the [`absolute_orientation`] stream
+    rotation: Option<i64>,
+}
+
+impl Integrator {
+    /// Drops every cursor that is ahead of its stream. The sensor clock starts over with
+    /// the device, so this happens when an estimate saved before a reboot was restored.
+    /// Returns whether anything was dropped.
+    fn forget_future(&mut self, sensor_data: &SensorData) -> bool {
+        let latest = |series: &SensorDataSeries| {
+            series
+                .has_data()
+                .then(|| series.latest().unwrap().timestamp)
+        };
+        let mut forgot = false;
+        let mut forget = |cursor: Option<i64>, series: &SensorDataSeries| {
+            let ahead = cursor
+                .zip(latest(series))
+                .is_some_and(|(cursor, latest)| cursor > latest);
+            forgot |= ahead;
+            ahead
+        };
+
+        if forget(
+            self.accelerometer.map(|(t, _)| t),
+            &sensor_data.accelerometer,
+        ) {
+            self.accelerometer = None;
+        }
+        if forget(self.gyroscope.map(|(t, _)| t), &sensor_data.gyroscope) {
+            self.gyroscope = None;
+        }
+        if forget(self.pressure, &sensor_data.pressure) {
+            self.pressure = None;
+        }
+        if forget(self.rotation, absolute_orientation(sensor_data)) {
+            self.rotation = None;
+        }
+        forgot
+    }
+}
+
+/// North-referenced orientation the gyroscope is corrected with: the rotation vector, or the
+/// geomagnetic rotation vector on devices that lack it
+fn absolute_orientation(sensor_data: &SensorData) -> &SensorDataSeries {
+    if sensor_data.rotation.has_data() || !sensor_data.compass.has_data() {
+        &sensor_data.rotation
+    } else {
+        &sensor_data.compass
+    }
+}
+
+fn update_state_vector(
+    sensor_data: Res<SensorData>,
+    config: Res<EstimatorConfig>,
+    mut integrator: ResMut<Integrator>,
+    mut barometer: ResMut<Barometer>,
+    mut states: ResMut<StateVector>,
+) {
+    if integrator.forget_future(&sensor_data) {
+        warn!("Sensor clock went backwards, bridging the gap");
+        states.bridge_gap(config.gap_policy, 0.0);
+    }
+    // a negative step means the clock was reset, e.g. by a reboot between suspend and resume
+    let is_gap = |dt: f32| !(0.0..=config.max_gap).contains(&dt);
+
+    let new_samples = |series: &SensorDataSeries, cursor: Option<i64>| {
+        series
+            .since(cursor.unwrap_or(i64::MIN))
+            .filter(|event| !matches!(event.sensor_type, SensorType::Unavailable))
+            // the trapezoid simply spans samples the sensor marked as unusable
+            .filter(|event| event.accuracy.weight() > 0.0)
+            .map(|event| {
+                (
+                    event.timestamp,
+                    event.sensor_type,
+                    event.values,
+                    event.accuracy.weight(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Walk all streams merged in timestamp order. The sort is stable and gyroscope samples
+    // go first, so a rotation is applied before an acceleration at the same instant, and
+    // the rotation vector corrects the orientation last.
+    let mut samples = new_samples(
+        &sensor_data.gyroscope,
+        integrator.gyroscope.map(|(timestamp, _)| timestamp),
+    );
+    samples.extend(new_samples(
+        &sensor_data.accelerometer,
+        integrator.accelerometer.map(|(timestamp, _)| timestamp),
+    ));
+    samples.extend(new_samples(&sensor_data.pressure, integrator.pressure));
+    samples.extend(new_samples(
+        absolute_orientation(&sensor_data),
+        integrator.rotation,
+    ));
+    samples.sort_by_key(|&(timestamp, _, _, _)| timestamp);
+
+    for (timestamp, sensor_type, values, weight) in samples {
+        match (sensor_type, values) {
+            (SensorType::Accelerometer, SensorValues::Vec3(value)) => {
+                // in the device's starting frame, the acceleration would be integrated
+                // along the wrong axes
+                if let Some((previous_timestamp, previous_value)) = integrator.accelerometer
+                    && states.aligned
+                {
+                    let dt = (timestamp - previous_timestamp) as f32 * 1e-9;
+                    if is_gap(dt) {
+                        info!("{:.2} s gap in accelerometer data", dt);
+                        states.bridge_gap(config.gap_policy, dt.max(0.0));
+                    } else {
+                        states.integrate_acceleration(previous_value, value, dt);
+                    }
+                }
+                integrator.accelerometer = Some((timestamp, value));
+            }
+            (SensorType::Gyroscope, SensorValues::Vec3(value)) => {
+                if let Some((previous_timestamp, previous_value)) = integrator.gyroscope {
+                    let dt = (timestamp - previous_timestamp) as f32 * 1e-9;
+                    // the orientation is kept across a gap, there is nothing to integrate
+                    if !is_gap(dt) {
+                        states.integrate_angular_rate(previous_value, value, dt);
+                    }
+                }
+                integrator.gyroscope = Some((timestamp, value));
+            }
+            (SensorType::Pressure, SensorValues::Scalar(pressure)) => {
+                // Android's gravity vector points up, away from the ground. The last sample
+                // up to the pressure one, so later samples of the same frame do not matter.
+                let up = sensor_data
+                    .gravity
+                    .range(i64::MIN, timestamp)
+                    .rfind(|event| !matches!(event.sensor_type, SensorType::Unavailable))
+                    .and_then(|event| event.values.vec3().copied())
+                    .map(|gravity| (states.orientation * gravity).normalize_or_zero())
+                    .filter(|up| *up != Vec3::ZERO);
+                if let Some(up) = up
+                    && states.aligned
+                {
+                    let dt = integrator
+                        .pressure
+                        .map(|previous| (timestamp - previous) as f32 * 1e-9)
+                        .filter(|&dt| !is_gap(dt))
+                        .unwrap_or(0.0);
+                    let altitude = barometer.altitude(pressure, states.position.dot(up));
+                    states.correct_altitude(up, altitude, dt);
+                }
+                integrator.pressure = Some(timestamp);
+            }
+            (SensorType::Rotation | SensorType::Compass, SensorValues::Quat(absolute)) => {
+                let dt = integrator
+                    .rotation
+                    .map(|previous| (timestamp - previous) as f32 * 1e-9)
+                    .filter(|&dt| !is_gap(dt))
+                    .unwrap_or(0.0);
+                states.correct_orientation(absolute, weight, dt);
+                integrator.rotation = Some(timestamp);
+            }
+            _ => (),
+        }
+    }
 }
 
+/// Estimate and calibration written to storage while suspended,
+/// so they survive the process being killed in the background
 #[cfg(target_os = "android")]
-fn update_state_vector(sensor_data: Res<SensorData>, mut states: ResMut<StateVector>) {
-    // Integrate (trapezoidal rule, backwards in time) acceleration -> velocity -> position
-    let accel_t = sensor_data.accelerometer.latest().unwrap();
+#[derive(Debug, Deserialize, Serialize)]
+struct Snapshot {
+    position: [f32; 3],
+    velocity: [f32; 3],
+    /// x, y, z, w
+    orientation: [f32; 4],
+    invalid: bool,
+    aligned: bool,
+    accelerometer: Option<CursorSnapshot>,
+    gyroscope: Option<CursorSnapshot>,
+    /// Timestamps of the last integrated pressure and rotation vector samples
+    pressure: Option<i64>,
+    rotation: Option<i64>,
+    barometer: Option<BarometerSnapshot>,
+}
+
+/// Last sample an [`Integrator`] stream stepped to
+#[cfg(target_os = "android")]
+#[derive(Debug, Deserialize, Serialize)]
+struct CursorSnapshot {
+    timestamp: i64,
+    value: [f32; 3],
+}
 
-    let accel_t_minus_1_event = sensor_data.accelerometer.t_minus(1);
+/// Reference of the [`Barometer`]
+#[cfg(target_os = "android")]
+#[derive(Debug, Deserialize, Serialize)]
+struct BarometerSnapshot {
+    pressure: f32,
+    altitude: f32,
+}
+
+#[cfg(target_os = "android")]
+impl Snapshot {
+    const FILE_NAME: &'static str = "estimate.toml";
 
-    let accel_t_minus_2_event = sensor_data.accelerometer.t_minus(2);
+    fn path() -> Option<PathBuf> {
+        bevy::window::ANDROID_APP
+            .get()?
+            .internal_data_path()
+            .map(|directory| directory.join(Self::FILE_NAME))
+    }
 
-    let vel_t_zero = states.velocity.clone();
+    fn capture(states: &StateVector, integrator: &Integrator, barometer: &Barometer) -> Self {
+        let cursor = |(timestamp, value): (i64, Vec3)| CursorSnapshot {
+            timestamp,
+            value: value.to_array(),
+        };
+        Self {
+            position: states.position.to_array(),
+            velocity: states.velocity.to_array(),
+            orientation: states.orientation.to_array(),
+            invalid: states.invalid,
+            aligned: states.aligned,
+            accelerometer: integrator.accelerometer.map(cursor),
+            gyroscope: integrator.gyroscope.map(cursor),
+            pressure: integrator.pressure,
+            rotation: integrator.rotation,
+            barometer: barometer
+                .reference
+                .map(|(pressure, altitude)| BarometerSnapshot { pressure, altitude }),
+        }
+    }
 
-    if let Some(accel_t_minus_1) = accel_t_minus_1_event {
-        states.velocity += (*accel_t.values.vec3().unwrap()
-            + *accel_t_minus_1.values.vec3().unwrap())
-            * ((accel_t.timestamp - accel_t_minus_1.timestamp) as f32)
-            * 1e-9
-            * 0.5;
+    fn restore(
+        &self,
+        states: &mut StateVector,
+        integrator: &mut Integrator,
+        barometer: &mut Barometer,
+    ) {
+        let cursor = |cursor: &CursorSnapshot| (cursor.timestamp, Vec3::from_array(cursor.value));
+        states.position = Vec3::from_array(self.position);
+        states.velocity = Vec3::from_array(self.velocity);
+        states.orientation = Quat::from_array(self.orientation).normalize();
+        states.invalid = self.invalid;
+        states.aligned = self.aligned;
+        integrator.accelerometer = self.accelerometer.as_ref().map(cursor);
+        integrator.gyroscope = self.gyroscope.as_ref().map(cursor);
+        integrator.pressure = self.pressure;
+        integrator.rotation = self.rotation;
+        barometer.reference = self
+            .barometer
+            .as_ref()
+            .map(|reference| (reference.pressure, reference.altitude));
     }
+}
 
-    if let Some(accel_t_minus_2) = accel_t_minus_2_event {
-        let vel_t_plus_1 = states.velocity.clone();
-        states.position += (vel_t_zero + vel_t_plus_1)
-            * ((accel_t.timestamp - accel_t_minus_2.timestamp) as f32)
-            * 1e-9
-            * 0.25;
+#[cfg(target_os = "android")]
+fn restore_estimate(
+    mut states: ResMut<StateVector>,
+    mut integrator: ResMut<Integrator>,
+    mut barometer: ResMut<Barometer>,
+) {
+    let Some(path) = Snapshot::path() else {
+        return;
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return,
+        Err(error) => {
+            warn!(
+                "Could not restore estimate from {}: {}",
+                path.display(),
+                error
+            );
+            return;
+        }
+    };
+    match toml::from_str::<Snapshot>(&contents) {
+        Ok(snapshot) => {
+            snapshot.restore(&mut states, &mut integrator, &mut barometer);
+            info!("Restored estimate from {}", path.display());
+        }
+        Err(error) => warn!("Skipping unreadable estimate {}: {}", path.display(), error),
     }
+}
 
-    // Complementary filter: rot vec + mag vec
+#[cfg(target_os = "android")]
+fn save_estimate_on_suspend(
+    mut session_changes: EventReader<SensorSessionChanged>,
+    states: Res<StateVector>,
+    integrator: Res<Integrator>,
+    barometer: Res<Barometer>,
+) {
+    if !session_changes
+        .read()
+        .any(|change| change.session == SensorSession::Suspended)
+    {
+        return;
+    }
+    let Some(path) = Snapshot::path() else {
+        warn!("No storage to save the estimate to");
+        return;
+    };
+    let contents = match toml::to_string(&Snapshot::capture(&states, &integrator, &barometer)) {
+        Ok(contents) => contents,
+        Err(error) => {
+            warn!("Could not serialize the estimate: {}", error);
+            return;
+        }
+    };
+    if let Err(error) = fs::write(&path, contents) {
+        warn!("Could not save estimate to {}: {}", path.display(), error);
+    }
 }
 
-fn print_state(states: Res<StateVector>) {
+fn print_state(states: Res<StateVector>, origin: Res<GeodeticOrigin>) {
+    if !states.is_valid() {
+        screen_print!(col: Color::srgb(1.0, 0.3, 0.3), "Estimate invalid");
+    }
+    if !states.is_aligned() {
+        screen_print!("Waiting for a rotation vector to align the orientation");
+    }
     screen_print!("Velocity: {:?}", states.velocity);
-    screen_print!("Position: {:?}", states.position);
+    screen_print!("Position (ENU): {:?}", states.position);
+    if let Some(coordinates) = origin.to_geodetic(states.position) {
+        screen_print!("Coordinates: {}", coordinates);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::event::{SensorAccuracy, SensorEvent};
+    use crate::plugins::sensor::SeriesRetention;
+    use bevy::ecs::system::RunSystemOnce;
+
+    const MS: i64 = 1_000_000; // nanoseconds
+
+    fn event(sensor_type: SensorType, timestamp: i64, values: SensorValues) -> SensorEvent {
+        SensorEvent {
+            accuracy: SensorAccuracy::High,
+            sensor_type,
+            timestamp,
+            values,
+        }
+    }
+
+    /// One second of gyroscope, accelerometer, gravity, pressure and rotation vector
+    /// samples at different rates and phases, in timestamp order
+    fn recording() -> Vec<SensorEvent> {
+        let mut events = Vec::new();
+        for step in 0..100 {
+            let timestamp = 1_000 * MS + step * 10 * MS;
+            let t = step as f32 * 0.01;
+            events.push(event(
+                SensorType::Gyroscope,
+                timestamp,
+                SensorValues::Vec3(Vec3::new(0.3 * t.sin(), 0.2, -0.1 * t.cos())),
+            ));
+            events.push(event(
+                SensorType::Accelerometer,
+                timestamp + 3 * MS,
+                SensorValues::Vec3(Vec3::new((3.0 * t).sin(), (2.0 * t).cos(), 0.1)),
+            ));
+            if step % 2 == 0 {
+                events.push(event(
+                    SensorType::Rotation,
+                    timestamp + MS,
+                    SensorValues::Quat(
+                        Quat::from_rotation_z(0.1 * t) * Quat::from_rotation_x(0.05),
+                    ),
+                ));
+                events.push(event(
+                    SensorType::Gravity,
+                    timestamp + 5 * MS,
+                    SensorValues::Vec3(Vec3::new(0.0, 2.0 * t, 9.8)),
+                ));
+            }
+            if step % 4 == 0 {
+                events.push(event(
+                    SensorType::Pressure,
+                    timestamp + 7 * MS,
+                    SensorValues::Scalar(1013.25 - 0.05 * t),
+                ));
+            }
+        }
+        events.sort_by_key(|event| event.timestamp);
+        events
+    }
+
+    /// Feeds `events` to the estimator, `batch` samples per frame, without low-pass filtering
+    fn estimate(events: &[SensorEvent], batch: usize) -> StateVector {
+        estimate_with(EstimatorConfig::default(), events, batch)
+    }
+
+    fn estimate_with(config: EstimatorConfig, events: &[SensorEvent], batch: usize) -> StateVector {
+        let mut sensor_data = SensorData::new(SeriesRetention::Samples(1_000));
+        sensor_data.set_low_pass(f32::INFINITY, |_| 50.0);
+        let mut world = World::new();
+        world.insert_resource(sensor_data);
+        world.insert_resource(config);
+        world.init_resource::<Integrator>();
+        world.init_resource::<Barometer>();
+        world.init_resource::<StateVector>();
+
+        for frame in events.chunks(batch) {
+            let mut sensor_data = world.resource_mut::<SensorData>();
+            for event in frame {
+                sensor_data
+                    .series_mut(event.sensor_type)
+                    .unwrap()
+                    .add(event.clone());
+            }
+            world.run_system_once(update_state_vector).unwrap();
+        }
+        world.remove_resource::<StateVector>().unwrap()
+    }
+
+    #[test]
+    fn batching_does_not_change_the_estimate() {
+        let events = recording();
+        let all_at_once = estimate(&events, events.len());
+        assert!(all_at_once.is_aligned());
+        assert_ne!(all_at_once.position(), Vec3::ZERO);
+
+        for batch in [1, 3, 7, 64] {
+            let batched = estimate(&events, batch);
+            assert_eq!(batched.position(), all_at_once.position(), "batch {batch}");
+            assert_eq!(batched.velocity(), all_at_once.velocity(), "batch {batch}");
+            assert_eq!(
+                batched.orientation(),
+                all_at_once.orientation(),
+                "batch {batch}"
+            );
+        }
+    }
+
+    #[test]
+    fn gap_policies_differ_across_a_gap() {
+        // aligned, accelerating north at 1 m/s² for half a second, then two seconds
+        // without samples
+        let mut events = vec![event(
+            SensorType::Rotation,
+            999 * MS,
+            SensorValues::Quat(Quat::IDENTITY),
+        )];
+        events.extend((0..51).map(|step| {
+            event(
+                SensorType::Accelerometer,
+                1_000 * MS + step * 10 * MS,
+                SensorValues::Vec3(Vec3::Y),
+            )
+        }));
+        events.push(event(
+            SensorType::Accelerometer,
+            3_500 * MS,
+            SensorValues::Vec3(Vec3::ZERO),
+        ));
+        let across_gap = |gap_policy| {
+            let config = EstimatorConfig {
+                gap_policy,
+                ..default()
+            };
+            estimate_with(config, &events, 1)
+        };
+
+        let rest = across_gap(GapPolicy::Rest);
+        assert!(rest.is_aligned() && rest.is_valid());
+        assert_eq!(rest.velocity(), Vec3::ZERO);
+        assert!(
+            (rest.position().y - 0.125).abs() < 1e-4,
+            "{}",
+            rest.position()
+        );
+
+        let reset = across_gap(GapPolicy::Reset);
+        assert!(!reset.is_aligned() && reset.is_valid());
+        assert_eq!(reset.velocity(), Vec3::ZERO);
+        assert_eq!(reset.position(), Vec3::ZERO);
+
+        let coast = across_gap(GapPolicy::Coast);
+        assert!(coast.is_aligned() && coast.is_valid());
+        assert!(
+            (coast.velocity().y - 0.5).abs() < 1e-4,
+            "{}",
+            coast.velocity()
+        );
+        assert!(
+            (coast.position().y - 1.125).abs() < 1e-3,
+            "{}",
+            coast.position()
+        );
+
+        let invalidate = across_gap(GapPolicy::Invalidate);
+        assert!(invalidate.is_aligned() && !invalidate.is_valid());
+        let velocity = invalidate.velocity();
+        assert!((velocity.y - 0.5).abs() < 1e-4, "{velocity}");
+        let position = invalidate.position();
+        assert!((position.y - 0.125).abs() < 1e-4, "{position}");
+    }
+
+    #[test]
+    fn acceleration_waits_for_alignment() {
+        // accelerating towards the top of the device at 1 m/s² for half a second
+        let mut events = (0..51)
+            .map(|step| {
+                event(
+                    SensorType::Accelerometer,
+                    1_000 * MS + step * 10 * MS,
+                    SensorValues::Vec3(Vec3::Y),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let unaligned = estimate(&events, 1);
+        assert!(!unaligned.is_aligned());
+        assert_eq!(unaligned.velocity(), Vec3::ZERO);
+        assert_eq!(unaligned.heading(), None);
+
+        // lying flat with its top pointing east
+        let east = Quat::from_rotation_z(-std::f32::consts::FRAC_PI_2);
+        events.insert(
+            0,
+            event(SensorType::Rotation, 999 * MS, SensorValues::Quat(east)),
+        );
+        let aligned = estimate(&events, 1);
+        assert!(aligned.is_aligned());
+        assert!(aligned.orientation().angle_between(east) < 1e-6);
+        let heading = aligned.heading().unwrap();
+        assert!((heading - 90.0).abs() < 1e-3, "{heading}");
+        let velocity = aligned.velocity();
+        assert!((velocity.x - 0.5).abs() < 1e-4, "{velocity}");
+        assert!(
+            velocity.y.abs() < 1e-4 && velocity.z.abs() < 1e-4,
+            "{velocity}"
+        );
+    }
+
+    #[test]
+    fn rotation_vector_corrects_gyroscope_drift() {
+        // a gyroscope with a 0.1 rad/s bias on a device that does not move
+        let mut events = Vec::new();
+        for step in 0..1_000 {
+            let timestamp = 1_000 * MS + step * 10 * MS;
+            events.push(event(
+                SensorType::Gyroscope,
+                timestamp,
+                SensorValues::Vec3(Vec3::new(0.0, 0.0, 0.1)),
+            ));
+            if step % 2 == 0 {
+                events.push(event(
+                    SensorType::Rotation,
+                    timestamp,
+                    SensorValues::Quat(Quat::IDENTITY),
+                ));
+            }
+        }
+
+        // ten seconds of drift would be a full radian, the correction holds it near
+        // bias / gain
+        let states = estimate(&events, 16);
+        let error = states.orientation().angle_between(Quat::IDENTITY);
+        assert!(error < 0.25, "{error} rad");
+    }
 }
@@ -0,0 +1,557 @@
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::window::AppLifecycle;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Write, fs, path::PathBuf};
+
+use super::sensor::SensorClock;
+use super::state::{GeodeticOrigin, StateVector};
+use crate::geodetic::LocalFrame;
+
+pub struct TrajectoryPlugin;
+
+impl Plugin for TrajectoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Trajectory>()
+            .init_resource::<SensorClock>()
+            .init_resource::<TrajectoryExport>()
+            .init_resource::<TrajectoryTrail>()
+            .add_event::<ExportTrajectory>()
+            .add_systems(PostUpdate, (record_trajectory, draw_trail).chain())
+            .add_systems(Last, (export_on_exit, export_trajectory).chain());
+    }
+}
+
+/// One recorded estimate
+#[derive(Clone, Copy, Debug)]
+pub struct TrajectoryPoint {
+    /// Wall clock time in seconds since the Unix epoch
+    pub timestamp: f64,
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub orientation: Quat,
+}
+
+/// History of the [`StateVector`], sampled at most every [`Trajectory::MIN_INTERVAL`].
+/// Only the latest [`Trajectory::MAX_POINTS`] are kept, so a long session cannot exhaust
+/// the memory.
+#[derive(Debug, Default, Resource)]
+pub struct Trajectory {
+    points: Vec<TrajectoryPoint>,
+}
+
+impl Trajectory {
+    const MIN_INTERVAL: f64 = 0.1; // seconds
+    /// Ten hours at [`Self::MIN_INTERVAL`]
+    const MAX_POINTS: usize = 360_000;
+
+    pub fn points(&self) -> &[TrajectoryPoint] {
+        &self.points
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    fn record(&mut self, point: TrajectoryPoint) {
+        if self
+            .points
+            .last()
+            .is_some_and(|last| point.timestamp - last.timestamp < Self::MIN_INTERVAL)
+        {
+            return;
+        }
+        if self.points.len() >= Self::MAX_POINTS {
+            // a tenth at a time, so recording stays amortized constant time
+            self.points.drain(..Self::MAX_POINTS / 10);
+        }
+        self.points.push(point);
+    }
+}
+
+/// How many of the latest trajectory points are drawn as a line in the scene
+#[derive(Clone, Debug, PartialEq, Resource)]
+pub struct TrajectoryTrail {
+    pub length: usize,
+}
+
+impl Default for TrajectoryTrail {
+    fn default() -> Self {
+        Self { length: 300 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrajectoryFormat {
+    Csv,
+    /// `timestamp tx ty tz qx qy qz qw`, as read by evaluation tools for the TUM RGB-D dataset
+    Tum,
+    Gpx,
+    Kml,
+    GeoJson,
+}
+
+impl TrajectoryFormat {
+    pub const ALL: [TrajectoryFormat; 5] = [
+        TrajectoryFormat::Csv,
+        TrajectoryFormat::Tum,
+        TrajectoryFormat::Gpx,
+        TrajectoryFormat::Kml,
+        TrajectoryFormat::GeoJson,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TrajectoryFormat::Csv => "csv",
+            TrajectoryFormat::Tum => "tum",
+            TrajectoryFormat::Gpx => "gpx",
+            TrajectoryFormat::Kml => "kml",
+            TrajectoryFormat::GeoJson => "geojson",
+        }
+    }
+
+    /// Whether the format holds coordinates and therefore needs a [`GeodeticOrigin`]
+    pub fn is_geodetic(&self) -> bool {
+        matches!(
+            self,
+            TrajectoryFormat::Gpx | TrajectoryFormat::Kml | TrajectoryFormat::GeoJson
+        )
+    }
+
+    /// Renders `points`, or `None` for a geodetic format without a local `frame`
+    pub fn write(&self, points: &[TrajectoryPoint], frame: Option<&LocalFrame>) -> Option<String> {
+        match (self, frame) {
+            (TrajectoryFormat::Csv, _) => Some(write_csv(points)),
+            (TrajectoryFormat::Tum, _) => Some(write_tum(points)),
+            (TrajectoryFormat::Gpx, Some(frame)) => Some(write_gpx(points, frame)),
+            (TrajectoryFormat::Kml, Some(frame)) => Some(write_kml(points, frame)),
+            (TrajectoryFormat::GeoJson, Some(frame)) => Some(write_geojson(points, frame)),
+            (_, None) => None,
+        }
+    }
+}
+
+/// Where and how the trajectory is written when the app is suspended or closed
+#[derive(Clone, Debug, PartialEq, Resource, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrajectoryExport {
+    pub formats: Vec<TrajectoryFormat>,
+    /// `None` disables the automatic export. Defaults to the app's external files
+    /// directory on Android, where it can be pulled with `adb`.
+    pub directory: Option<PathBuf>,
+}
+
+impl Default for TrajectoryExport {
+    fn default() -> Self {
+        #[cfg(target_os = "android")]
+        let directory = bevy::window::ANDROID_APP
+            .get()
+            .and_then(|app| app.external_data_path());
+        #[cfg(not(target_os = "android"))]
+        let directory = None;
+
+        Self {
+            formats: TrajectoryFormat::ALL.to_vec(),
+            directory,
+        }
+    }
+}
+
+/// Writes the recorded trajectory to `path`
+#[derive(Clone, Debug, Event)]
+pub struct ExportTrajectory {
+    pub format: TrajectoryFormat,
+    pub path: PathBuf,
+}
+
+fn write_csv(points: &[TrajectoryPoint]) -> String {
+    let mut out = String::from("timestamp,px,py,pz,vx,vy,vz,qx,qy,qz,qw\n");
+    for point in points {
+        let (p, v, q) = (point.position, point.velocity, point.orientation);
+        writeln!(
+            out,
+            "{:.3},{},{},{},{},{},{},{},{},{},{}",
+            point.timestamp, p.x, p.y, p.z, v.x, v.y, v.z, q.x, q.y, q.z, q.w
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn write_tum(points: &[TrajectoryPoint]) -> String {
+    let mut out = String::from("# timestamp tx ty tz qx qy qz qw\n");
+    for point in points {
+        let (p, q) = (point.position, point.orientation);
+        writeln!(
+            out,
+            "{:.6} {} {} {} {} {} {} {}",
+            point.timestamp, p.x, p.y, p.z, q.x, q.y, q.z, q.w
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// GPX elevations are meant to be above mean sea level. Without a geoid model the height
+/// above the WGS84 ellipsoid is written instead, which the track's description states.
+fn write_gpx(points: &[TrajectoryPoint], frame: &LocalFrame) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<gpx version=\"1.1\" creator=\"android-position-estimator\" ",
+        "xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+        "  <trk>\n    <name>Estimated trajectory</name>\n",
+        "    <desc>",
+        "Altitudes are heights above the WGS84 ellipsoid, not above mean sea level.",
+        "</desc>\n    <trkseg>\n",
+    ));
+    for point in points {
+        let coordinates = frame.enu_to_geodetic(point.position.as_dvec3());
+        writeln!(
+            out,
+            "      <trkpt lat=\"{:.8}\" lon=\"{:.8}\"><ele>{:.3}</ele><time>{}</time></trkpt>",
+            coordinates.latitude,
+            coordinates.longitude,
+            coordinates.altitude,
+            iso8601(point.timestamp)
+        )
+        .unwrap();
+    }
+    out.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    out
+}
+
+/// Like [`write_gpx`], `absolute` altitudes are meant to be above mean sea level but are
+/// above the ellipsoid, as the placemark's description states
+fn write_kml(points: &[TrajectoryPoint], frame: &LocalFrame) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n",
+        "  <Document>\n    <Placemark>\n      <name>Estimated trajectory</name>\n",
+        "      <description>",
+        "Altitudes are heights above the WGS84 ellipsoid, not above mean sea level.",
+        "</description>\n",
+        "      <LineString>\n        <altitudeMode>absolute</altitudeMode>\n",
+        "        <coordinates>\n",
+    ));
+    for point in points {
+        let coordinates = frame.enu_to_geodetic(point.position.as_dvec3());
+        writeln!(
+            out,
+            "          {:.8},{:.8},{:.3}",
+            coordinates.longitude, coordinates.latitude, coordinates.altitude
+        )
+        .unwrap();
+    }
+    out.push_str(concat!(
+        "        </coordinates>\n      </LineString>\n",
+        "    </Placemark>\n  </Document>\n</kml>\n",
+    ));
+    out
+}
+
+/// GeoJSON altitudes are defined as heights above the WGS84 ellipsoid, as written
+fn write_geojson(points: &[TrajectoryPoint], frame: &LocalFrame) -> String {
+    let coordinates = points
+        .iter()
+        .map(|point| {
+            let coordinates = frame.enu_to_geodetic(point.position.as_dvec3());
+            format!(
+                "[{:.8},{:.8},{:.3}]",
+                coordinates.longitude, coordinates.latitude, coordinates.altitude
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let times = points
+        .iter()
+        .map(|point| format!("\"{}\"", iso8601(point.timestamp)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        concat!(
+            "{{\"type\":\"FeatureCollection\",\"features\":[{{\"type\":\"Feature\",",
+            "\"geometry\":{{\"type\":\"LineString\",\"coordinates\":[{}]}},",
+            "\"properties\":{{\"name\":\"Estimated trajectory\",\"times\":[{}]}}}}]}}\n",
+        ),
+        coordinates, times
+    )
+}
+
+/// UTC date and time of a Unix timestamp, e.g. `2024-05-01T12:00:00.250Z`
+fn iso8601(timestamp: f64) -> String {
+    let (year, month, day, millis_of_day) = utc_date_time(timestamp);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        millis_of_day / 3_600_000,
+        millis_of_day / 60_000 % 60,
+        millis_of_day / 1_000 % 60,
+        millis_of_day % 1_000
+    )
+}
+
+/// Year, month, day and milliseconds since midnight of a Unix timestamp, in UTC
+pub(super) fn utc_date_time(timestamp: f64) -> (i64, i64, i64, i64) {
+    let millis = (timestamp * 1e3).round() as i64;
+    let (days, millis_of_day) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+
+    // days to civil date, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day, millis_of_day)
+}
+
+fn record_trajectory(
+    states: Res<StateVector>,
+    clock: Res<SensorClock>,
+    mut trajectory: ResMut<Trajectory>,
+) {
+    if !states.is_changed() || !states.is_valid() {
+        return;
+    }
+
+    trajectory.record(TrajectoryPoint {
+        timestamp: clock.to_unix_time(clock.app_time_now()) as f64 * 1e-9,
+        position: states.position(),
+        velocity: states.velocity(),
+        orientation: states.orientation(),
+    });
+}
+
+/// Queues an export of every configured format when the app goes to the background or exits
+fn export_on_exit(
+    mut lifecycle_events: EventReader<AppLifecycle>,
+    mut exit_events: EventReader<AppExit>,
+    export: Res<TrajectoryExport>,
+    origin: Res<GeodeticOrigin>,
+    mut exports: EventWriter<ExportTrajectory>,
+) {
+    let suspended = lifecycle_events
+        .read()
+        .any(|event| matches!(event, AppLifecycle::Suspended));
+    let exiting = exit_events.read().count() > 0;
+    let Some(directory) = &export.directory else {
+        return;
+    };
+    if !suspended && !exiting {
+        return;
+    }
+
+    for &format in &export.formats {
+        if format.is_geodetic() && origin.frame().is_none() {
+            continue;
+        }
+        exports.write(ExportTrajectory {
+            format,
+            path: directory.join(format!("trajectory.{}", format.extension())),
+        });
+    }
+}
+
+fn export_trajectory(
+    mut exports: EventReader<ExportTrajectory>,
+    trajectory: Res<Trajectory>,
+    origin: Res<GeodeticOrigin>,
+) {
+    for ExportTrajectory { format, path } in exports.read() {
+        let Some(contents) = format.write(trajectory.points(), origin.frame()) else {
+            warn!("{:?} export needs a geodetic origin", format);
+            continue;
+        };
+        match fs::write(path, contents) {
+            Ok(()) => info!(
+                "Exported {} trajectory points to {}",
+                trajectory.points().len(),
+                path.display()
+            ),
+            Err(error) => warn!(
+                "Could not export trajectory to {}: {}",
+                path.display(),
+                error
+            ),
+        }
+    }
+}
+
+fn draw_trail(trajectory: Res<Trajectory>, trail: Res<TrajectoryTrail>, mut gizmos: Gizmos) {
+    let points = trajectory.points();
+    let recent = &points[points.len().saturating_sub(trail.length)..];
+    // east, north, up to the scene's y-up axes
+    gizmos.linestrip(
+        recent
+            .iter()
+            .map(|point| Vec3::new(point.position.x, point.position.z, -point.position.y)),
+        Color::srgb(1.0, 0.8, 0.2),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geodetic::Geodetic;
+
+    #[test]
+    fn only_the_latest_points_are_kept() {
+        let mut trajectory = Trajectory::default();
+        for step in 0..Trajectory::MAX_POINTS + 1 {
+            trajectory.record(TrajectoryPoint {
+                timestamp: step as f64,
+                position: Vec3::ZERO,
+                velocity: Vec3::ZERO,
+                orientation: Quat::IDENTITY,
+            });
+        }
+
+        let points = trajectory.points();
+        assert!(points.len() <= Trajectory::MAX_POINTS);
+        assert_eq!(
+            points.last().unwrap().timestamp,
+            Trajectory::MAX_POINTS as f64
+        );
+        assert!(
+            points
+                .windows(2)
+                .all(|pair| pair[0].timestamp < pair[1].timestamp)
+        );
+    }
+
+    /// 2024-03-09 12:35:19.25 UTC at 48.1173° N 123.1° W and a second later 10 m higher
+    fn two_points() -> (Vec<TrajectoryPoint>, LocalFrame) {
+        let point = |timestamp, position| TrajectoryPoint {
+            timestamp,
+            position,
+            velocity: Vec3::new(3.0, 4.0, 0.0),
+            orientation: Quat::IDENTITY,
+        };
+        let points = vec![
+            point(1_709_987_719.25, Vec3::ZERO),
+            point(1_709_987_720.25, Vec3::new(0.0, 0.0, 10.0)),
+        ];
+        (
+            points,
+            LocalFrame::new(Geodetic::new(48.1173, -123.1, 545.4)),
+        )
+    }
+
+    #[test]
+    fn formats_match_the_reference() {
+        let (points, frame) = two_points();
+        let write = |format: TrajectoryFormat| format.write(&points, Some(&frame)).unwrap();
+
+        assert_eq!(
+            write(TrajectoryFormat::Csv),
+            concat!(
+                "timestamp,px,py,pz,vx,vy,vz,qx,qy,qz,qw\n",
+                "1709987719.250,0,0,0,3,4,0,0,0,0,1\n",
+                "1709987720.250,0,0,10,3,4,0,0,0,0,1\n",
+            )
+        );
+        assert_eq!(
+            write(TrajectoryFormat::Tum),
+            concat!(
+                "# timestamp tx ty tz qx qy qz qw\n",
+                "1709987719.250000 0 0 0 0 0 0 1\n",
+                "1709987720.250000 0 0 10 0 0 0 1\n",
+            )
+        );
+        assert_eq!(
+            write(TrajectoryFormat::Gpx),
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<gpx version=\"1.1\" creator=\"android-position-estimator\" ",
+                "xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+                "  <trk>\n    <name>Estimated trajectory</name>\n",
+                "    <desc>",
+                "Altitudes are heights above the WGS84 ellipsoid, not above mean sea level.",
+                "</desc>\n    <trkseg>\n",
+                "      <trkpt lat=\"48.11730000\" lon=\"-123.10000000\"><ele>545.400</ele>",
+                "<time>2024-03-09T12:35:19.250Z</time></trkpt>\n",
+                "      <trkpt lat=\"48.11730000\" lon=\"-123.10000000\"><ele>555.400</ele>",
+                "<time>2024-03-09T12:35:20.250Z</time></trkpt>\n",
+                "    </trkseg>\n  </trk>\n</gpx>\n",
+            )
+        );
+        assert_eq!(
+            write(TrajectoryFormat::Kml),
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n",
+                "  <Document>\n    <Placemark>\n      <name>Estimated trajectory</name>\n",
+                "      <description>",
+                "Altitudes are heights above the WGS84 ellipsoid, not above mean sea level.",
+                "</description>\n",
+                "      <LineString>\n        <altitudeMode>absolute</altitudeMode>\n",
+                "        <coordinates>\n",
+                "          -123.10000000,48.11730000,545.400\n",
+                "          -123.10000000,48.11730000,555.400\n",
+                "        </coordinates>\n      </LineString>\n",
+                "    </Placemark>\n  </Document>\n</kml>\n",
+            )
+        );
+        assert_eq!(
+            write(TrajectoryFormat::GeoJson),
+            concat!(
+                "{\"type\":\"FeatureCollection\",\"features\":[{\"type\":\"Feature\",",
+                "\"geometry\":{\"type\":\"LineString\",\"coordinates\":[",
+                "[-123.10000000,48.11730000,545.400],[-123.10000000,48.11730000,555.400]]},",
+                "\"properties\":{\"name\":\"Estimated trajectory\",\"times\":[",
+                "\"2024-03-09T12:35:19.250Z\",\"2024-03-09T12:35:20.250Z\"]}}]}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn geodetic_formats_need_a_frame() {
+        let (points, _) = two_points();
+        for format in TrajectoryFormat::ALL {
+            assert_eq!(
+                format.write(&points, None).is_some(),
+                !format.is_geodetic(),
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn dates_follow_the_gregorian_calendar() {
+        const DAY: f64 = 86_400.0;
+        // days since the Unix epoch
+        let date = |days: f64| iso8601(days * DAY);
+
+        assert_eq!(date(0.0), "1970-01-01T00:00:00.000Z");
+        // a year boundary, a millisecond apart
+        assert_eq!(iso8601(365.0 * DAY - 0.001), "1970-12-31T23:59:59.999Z");
+        assert_eq!(date(365.0), "1971-01-01T00:00:00.000Z");
+        // 2000 is a leap year despite being a century, 2100 is not
+        assert_eq!(date(11_016.0), "2000-02-29T00:00:00.000Z");
+        assert_eq!(date(11_017.0), "2000-03-01T00:00:00.000Z");
+        assert_eq!(date(47_540.0), "2100-02-28T00:00:00.000Z");
+        assert_eq!(date(47_541.0), "2100-03-01T00:00:00.000Z");
+        // an ordinary leap day
+        assert_eq!(
+            iso8601(1_709_164_800.0 + 12.0 * 3600.0),
+            "2024-02-29T12:00:00.000Z"
+        );
+        assert_eq!(utc_date_time(1_709_251_199.5), (2024, 2, 29, 86_399_500));
+        assert_eq!(utc_date_time(1_709_251_200.0), (2024, 3, 1, 0));
+        // before the epoch
+        assert_eq!(date(-1.0), "1969-12-31T00:00:00.000Z");
+    }
+}